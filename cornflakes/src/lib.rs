@@ -1,18 +1,164 @@
 //! Cap'n Proto code generation and conversion to/from Rust bytes.
 //! `structs` defined 
 
+#[derive(Default)]
 pub struct VmecRequestFields {
     pub timestamp_ms: u64,
     pub device_hash: String,
     pub request_hash: String,
     pub image_front: Vec<u8>,
     pub image_rear: Vec<u8>,
+    /// Made with the device's key by `auth::sign_frame`; empty when the client has no key
+    pub signature: Vec<u8>,
+    /// Increases with every frame a device sends; see `validation::SequenceCounter`
+    pub sequence: u64,
+    /// Latest `session_token` the device got in a reply; empty before the first
+    pub session_token: String,
+    /// Where the device was when it took the images, if it knows
+    pub location: Option<VmecLocation>,
+    /// Radius to ask for the hazards around `location` in; 0 to not ask
+    pub nearby_radius_m: f32,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmecLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl VmecRequestFields {
+    /// Each camera's JPEG, front first
+    pub fn images(&self) -> [(CameraDirection, &[u8]); 2] {
+        [(CameraDirection::Frontcam, &self.image_front), (CameraDirection::Rearcam, &self.image_rear)]
+    }
+}
+
 pub struct VmecResponseFields {
     pub timestamp_ms: u64,
     pub server_hash: String,
     pub response_hash: String,
     pub neural_output: String,
+    /// `Busy` means the frame was not processed; `ReduceRate` means it was, but the client should slow down.
+    /// `Superseded` means the frame was dropped because a newer frame from the same device arrived first.
+    /// `Error` means the frame could not be processed; see `error_code`.
+    /// `RateLimited` means the frame was not processed because the device, or all devices together, sent too many.
+    pub status: ResponseStatus,
+    /// How long the client should hold off before sending again, when `status` is not `Ok`
+    pub retry_after_ms: u32,
+    /// Why the frame could not be served, when `status` is `Error`
+    pub error_code: ErrorCode,
+    pub error_message: String,
+    /// Servers the frame passed through, starting with the one that processed it
+    pub hops: Vec<VmecHopFields>,
+    /// The device's session on the server that processed the frame, to send with later frames
+    pub session_token: String,
+    /// Engine and model version that served each camera, e.g. "front:road-hazards@2 rear:vehicles@1"
+    pub model_version: String,
+    /// Experiment arm the device is in; empty when it is in none
+    pub experiment_arm: String,
+    /// Hazards near the device, nearest first, when the frame asked for them
+    pub nearby_hazards: Vec<VmecHazardFields>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmecHazardFields {
+    pub location: VmecLocation,
+    pub kind: String,
+    /// Confidence of the detections, decayed with their age
+    pub score: f32,
+    /// Devices that detected it
+    pub reporters: u32,
+    pub last_detected_ms: u64,
+    pub distance_m: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmecHopFields {
+    pub server: String,
+    /// From receiving the frame to sending the reply, on this server
+    pub elapsed_us: u64,
+}
+
+impl Default for VmecResponseFields {
+    fn default() -> Self {
+        VmecResponseFields {
+            timestamp_ms: 0,
+            server_hash: String::new(),
+            response_hash: String::new(),
+            neural_output: String::new(),
+            status: ResponseStatus::Ok,
+            retry_after_ms: 0,
+            error_code: ErrorCode::None,
+            error_message: String::new(),
+            hops: Vec::new(),
+            session_token: String::new(),
+            model_version: String::new(),
+            experiment_arm: String::new(),
+            nearby_hazards: Vec::new(),
+        }
+    }
+}
+
+/// Lightweight liveness probe sent by the client while idle or between frames.
+#[derive(Default)]
+pub struct VmecHeartbeatFields {
+    pub timestamp_ms: u64,
+    pub device_hash: String,
+    pub sequence: u64,
+    /// Made with the device's key by `auth::sign_heartbeat`; empty when the client has no key
+    pub signature: Vec<u8>,
+}
+
+/// Server reply to a heartbeat, reporting its current load and health.
+pub struct VmecHealthFields {
+    pub timestamp_ms: u64,
+    pub server_hash: String,
+    pub uptime_ms: u64,
+    pub requests_served: u64,
+    pub requests_per_second: f32,
+    pub mean_processing_us: u64,
+    pub healthy: bool,
+    /// Frames accepted by the server but not yet answered
+    pub in_flight: u32,
+    /// Resent frames answered from the response cache instead of being processed again
+    pub cache_hits: u64,
+}
+
+/// Pushed by the server to one device, on a channel of its own; see `vmec_response_transport::encode_alert`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmecAlertFields {
+    pub timestamp_ms: u64,
+    pub device_hash: String,
+    pub kind: AlertKind,
+    pub message: String,
+    /// Details that depend on `kind`, as JSON; empty when there are none
+    pub detail: String,
+}
+
+/// Everything a client may send on the request socket.
+pub enum VmecRequestKind {
+    Frame(VmecRequestFields),
+    Heartbeat(VmecHeartbeatFields),
+    /// An encrypted frame or heartbeat; see `envelope`
+    Sealed(VmecSealedFields),
+    /// A server taking over a device's session from the server that issued its token
+    SessionPull(VmecSessionPullFields),
+}
+
+#[derive(Default)]
+pub struct VmecSessionPullFields {
+    pub device_hash: String,
+    pub session_token: String,
+    pub timestamp_ms: u64,
+    /// Keyed hash of the other fields, under the key the servers share
+    pub signature: Vec<u8>,
+}
+
+/// An encrypted request, opened with the key of the device it names.
+pub struct VmecSealedFields {
+    pub device_hash: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
 }
 
 pub mod vmec_request_capnp {
@@ -25,6 +171,9 @@ pub mod vmec_response_capnp {
     include!(concat!(env!("OUT_DIR"), "/schemas/vmec_response_capnp.rs"));
 }
 
+pub use vmec_request_capnp::req_frame::camera_image::CameraDirection;
+pub use vmec_response_capnp::{AlertKind, ErrorCode, ResponseStatus};
+
 pub mod capnp_bytes_io {
     pub struct CapnpEncoding {
         // Copies the bytes output of capn proto serialization to `bytesbuffer`
@@ -35,7 +184,6 @@ pub mod capnp_bytes_io {
 
     impl std::io::Write for CapnpEncoding {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            //dbg!(buf);
             self.encoded_bytes.extend_from_slice(buf);
             Ok(buf.len())
         }
@@ -79,7 +227,13 @@ pub mod vmec_response_transport {
     // TODO: Implement vmec_response, see vmec_request_capnp. Create a new .capnp schema file and the rest of it.
 
     use crate::vmec_response_capnp::vmec_response_struct;
-    use crate::VmecResponseFields;
+    use crate::{VmecAlertFields, VmecHazardFields, VmecHealthFields, VmecHopFields, VmecLocation, VmecResponseFields};
+
+    /// Topic of a device's alerts on the alert channel, so that it can subscribe to its own only.
+    /// Alerts are sent as the topic followed by the encoded (and maybe sealed) alert.
+    pub fn alert_topic(device_hash: &str) -> Vec<u8> {
+        format!("{}\0", device_hash.trim()).into_bytes()
+    }
 
     pub fn encode_response(fields: VmecResponseFields) -> Result<Vec<u8>, std::io::Error> {
         use crate::capnp_bytes_io::CapnpEncoding;
@@ -98,6 +252,30 @@ pub mod vmec_response_transport {
                 res_frame.set_server_hash(&fields.server_hash);
                 res_frame.set_response_hash(&fields.response_hash);
                 res_frame.set_neural_output(&fields.neural_output);
+                res_frame.set_status(fields.status);
+                res_frame.set_retry_after_ms(fields.retry_after_ms);
+                res_frame.set_error_code(fields.error_code);
+                res_frame.set_error_message(&fields.error_message);
+                res_frame.set_session_token(&fields.session_token);
+                res_frame.set_model_version(&fields.model_version);
+                res_frame.set_experiment_arm(&fields.experiment_arm);
+                let mut hazards = res_frame.reborrow().init_nearby_hazards(fields.nearby_hazards.len() as u32);
+                for (i, hazard) in fields.nearby_hazards.iter().enumerate() {
+                    let mut hazard_builder = hazards.reborrow().get(i as u32);
+                    hazard_builder.set_latitude(hazard.location.latitude);
+                    hazard_builder.set_longitude(hazard.location.longitude);
+                    hazard_builder.set_kind(&hazard.kind);
+                    hazard_builder.set_score(hazard.score);
+                    hazard_builder.set_reporters(hazard.reporters);
+                    hazard_builder.set_last_detected_ms(hazard.last_detected_ms);
+                    hazard_builder.set_distance_m(hazard.distance_m);
+                }
+                let mut hops = res_frame.init_hops(fields.hops.len() as u32);
+                for (i, hop) in fields.hops.iter().enumerate() {
+                    let mut hop_builder = hops.reborrow().get(i as u32);
+                    hop_builder.set_server(&hop.server);
+                    hop_builder.set_elapsed_us(hop.elapsed_us);
+                }
             }
         }
 
@@ -119,26 +297,195 @@ pub mod vmec_response_transport {
 
         let vmec_response_struct= message_reader.get_root::<vmec_response_struct::Reader>()?;
 
-        let mut res_fields = VmecResponseFields {
-            timestamp_ms: 0,
-            server_hash: String::new(),
-            response_hash: String::new(),
-            neural_output: String::new(),
-        };
+        let mut res_fields = VmecResponseFields::default();
 
         for frame in vmec_response_struct.get_frame()? {
             res_fields.timestamp_ms = frame.get_timestamp_ms();
             res_fields.server_hash = frame.get_server_hash()?.to_string();
             res_fields.response_hash = frame.get_response_hash()?.to_string();
             res_fields.neural_output = frame.get_neural_output()?.to_string();
+            res_fields.status = frame.get_status()?;
+            res_fields.retry_after_ms = frame.get_retry_after_ms();
+            res_fields.error_code = frame.get_error_code()?;
+            res_fields.error_message = frame.get_error_message()?.to_string();
+            res_fields.session_token = frame.get_session_token()?.to_string();
+            res_fields.model_version = frame.get_model_version()?.to_string();
+            res_fields.experiment_arm = frame.get_experiment_arm()?.to_string();
+            res_fields.nearby_hazards = Vec::new();
+            for hazard in frame.get_nearby_hazards()? {
+                res_fields.nearby_hazards.push(VmecHazardFields {
+                    location: VmecLocation { latitude: hazard.get_latitude(), longitude: hazard.get_longitude() },
+                    kind: hazard.get_kind()?.to_string(),
+                    score: hazard.get_score(),
+                    reporters: hazard.get_reporters(),
+                    last_detected_ms: hazard.get_last_detected_ms(),
+                    distance_m: hazard.get_distance_m(),
+                });
+            }
+            res_fields.hops = Vec::new();
+            for hop in frame.get_hops()? {
+                res_fields.hops.push(VmecHopFields {
+                    server: hop.get_server()?.to_string(),
+                    elapsed_us: hop.get_elapsed_us(),
+                });
+            }
         }
         Ok(res_fields)
     }
+
+    /// Reply to a session pull: the exported session, or None if the server has no such session
+    pub fn encode_session(session: Option<&[u8]>) -> Result<Vec<u8>, std::io::Error> {
+        use crate::capnp_bytes_io::CapnpEncoding;
+        let mut capnp_enc = CapnpEncoding {
+            encoded_bytes: Vec::new(),
+        };
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_response_struct = message.init_root::<vmec_response_struct::Builder>();
+
+            let mut transfer = vmec_response_struct.init_session();
+            transfer.set_found(session.is_some());
+            transfer.set_session(session.unwrap_or_default());
+        }
+
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+
+        Ok((capnp_enc.encoded_bytes).to_vec())
+    }
+
+    pub fn decode_session(bytes_to_decode: &[u8]) -> capnp::Result<Option<Vec<u8>>> {
+        use crate::capnp_bytes_io::CapnpDecoding;
+        let mut capnp_dec = CapnpDecoding {
+            bytes_to_decode: bytes_to_decode.to_vec(),
+        };
+
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut capnp_dec,
+            ::capnp::message::ReaderOptions::new(),
+        )?;
+
+        let vmec_response_struct = message_reader.get_root::<vmec_response_struct::Reader>()?;
+
+        if !vmec_response_struct.has_session() {
+            return Err(capnp::Error::failed(String::from("response does not contain a session transfer")));
+        }
+        let transfer = vmec_response_struct.get_session()?;
+        if !transfer.get_found() {
+            return Ok(None);
+        }
+        Ok(Some(transfer.get_session()?.to_vec()))
+    }
+
+    pub fn encode_alert(fields: VmecAlertFields) -> Result<Vec<u8>, std::io::Error> {
+        use crate::capnp_bytes_io::CapnpEncoding;
+        let mut capnp_enc = CapnpEncoding {
+            encoded_bytes: Vec::new(),
+        };
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_response_struct = message.init_root::<vmec_response_struct::Builder>();
+
+            let mut alert = vmec_response_struct.init_alert();
+            alert.set_timestamp_ms(fields.timestamp_ms);
+            alert.set_device_hash(&fields.device_hash);
+            alert.set_kind(fields.kind);
+            alert.set_message(&fields.message);
+            alert.set_detail(&fields.detail);
+        }
+
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+
+        Ok((capnp_enc.encoded_bytes).to_vec())
+    }
+
+    pub fn decode_alert(bytes_to_decode: &[u8]) -> capnp::Result<VmecAlertFields> {
+        use crate::capnp_bytes_io::CapnpDecoding;
+        let mut capnp_dec = CapnpDecoding {
+            bytes_to_decode: bytes_to_decode.to_vec(),
+        };
+
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut capnp_dec,
+            ::capnp::message::ReaderOptions::new(),
+        )?;
+
+        let vmec_response_struct = message_reader.get_root::<vmec_response_struct::Reader>()?;
+
+        if !vmec_response_struct.has_alert() {
+            return Err(capnp::Error::failed(String::from("response does not contain an alert")));
+        }
+        let alert = vmec_response_struct.get_alert()?;
+
+        Ok(VmecAlertFields {
+            timestamp_ms: alert.get_timestamp_ms(),
+            device_hash: alert.get_device_hash()?.to_string(),
+            kind: alert.get_kind()?,
+            message: alert.get_message()?.to_string(),
+            detail: alert.get_detail()?.to_string(),
+        })
+    }
+
+    pub fn encode_health(fields: VmecHealthFields) -> Result<Vec<u8>, std::io::Error> {
+        use crate::capnp_bytes_io::CapnpEncoding;
+        let mut capnp_enc = CapnpEncoding {
+            encoded_bytes: Vec::new(),
+        };
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_response_struct = message.init_root::<vmec_response_struct::Builder>();
+
+            let mut health = vmec_response_struct.init_health();
+            health.set_timestamp_ms(fields.timestamp_ms);
+            health.set_server_hash(&fields.server_hash);
+            health.set_uptime_ms(fields.uptime_ms);
+            health.set_requests_served(fields.requests_served);
+            health.set_requests_per_second(fields.requests_per_second);
+            health.set_mean_processing_us(fields.mean_processing_us);
+            health.set_healthy(fields.healthy);
+            health.set_in_flight(fields.in_flight);
+            health.set_cache_hits(fields.cache_hits);
+        }
+
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+
+        Ok((capnp_enc.encoded_bytes).to_vec())
+    }
+
+    pub fn decode_health(bytes_to_decode: &[u8]) -> capnp::Result<VmecHealthFields> {
+        use crate::capnp_bytes_io::CapnpDecoding;
+        let mut capnp_dec = CapnpDecoding {
+            bytes_to_decode: bytes_to_decode.to_vec(),
+        };
+
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut capnp_dec,
+            ::capnp::message::ReaderOptions::new(),
+        )?;
+
+        let vmec_response_struct = message_reader.get_root::<vmec_response_struct::Reader>()?;
+
+        if !vmec_response_struct.has_health() {
+            return Err(capnp::Error::failed(String::from("response does not contain a health report")));
+        }
+        let health = vmec_response_struct.get_health()?;
+
+        Ok(VmecHealthFields {
+            timestamp_ms: health.get_timestamp_ms(),
+            server_hash: health.get_server_hash()?.to_string(),
+            uptime_ms: health.get_uptime_ms(),
+            requests_served: health.get_requests_served(),
+            requests_per_second: health.get_requests_per_second(),
+            mean_processing_us: health.get_mean_processing_us(),
+            healthy: health.get_healthy(),
+            in_flight: health.get_in_flight(),
+            cache_hits: health.get_cache_hits(),
+        })
+    }
 }
 
 pub mod vmec_request_transport {
     use crate::vmec_request_capnp::{req_frame, vmec_request_struct};
-    use crate::VmecRequestFields;
+    use crate::{VmecHeartbeatFields, VmecLocation, VmecRequestFields, VmecRequestKind, VmecSealedFields, VmecSessionPullFields};
 
     pub fn encode_request(fields: VmecRequestFields) -> Result<Vec<u8>, std::io::Error> {
         use crate::capnp_bytes_io::CapnpEncoding;
@@ -157,6 +504,15 @@ pub mod vmec_request_transport {
                 req_frame.set_timestamp_ms(fields.timestamp_ms);
                 req_frame.set_device_hash(&String::from(fields.device_hash));
                 req_frame.set_request_hash(&String::from(fields.request_hash));
+                req_frame.set_signature(&fields.signature);
+                req_frame.set_sequence(fields.sequence);
+                req_frame.set_session_token(&fields.session_token);
+                if let Some(location) = fields.location {
+                    let mut location_builder = req_frame.reborrow().init_location();
+                    location_builder.set_latitude(location.latitude);
+                    location_builder.set_longitude(location.longitude);
+                }
+                req_frame.set_nearby_radius_m(fields.nearby_radius_m);
                 {
                     let mut req_frame_images = req_frame.reborrow().init_images(2);
                     req_frame_images
@@ -182,7 +538,58 @@ pub mod vmec_request_transport {
         Ok((capnp_enc.encoded_bytes).to_vec())
     }
 
+    pub fn encode_heartbeat(fields: VmecHeartbeatFields) -> Result<Vec<u8>, std::io::Error> {
+        use crate::capnp_bytes_io::CapnpEncoding;
+        let mut capnp_enc = CapnpEncoding {
+            encoded_bytes: Vec::new(),
+        };
+
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_request_struct = message.init_root::<vmec_request_struct::Builder>();
+
+            let mut heartbeat = vmec_request_struct.init_heartbeat();
+            heartbeat.set_timestamp_ms(fields.timestamp_ms);
+            heartbeat.set_device_hash(&fields.device_hash);
+            heartbeat.set_sequence(fields.sequence);
+            heartbeat.set_signature(&fields.signature);
+        }
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+        Ok((capnp_enc.encoded_bytes).to_vec())
+    }
+
+    pub fn encode_session_pull(fields: VmecSessionPullFields) -> Result<Vec<u8>, std::io::Error> {
+        use crate::capnp_bytes_io::CapnpEncoding;
+        let mut capnp_enc = CapnpEncoding {
+            encoded_bytes: Vec::new(),
+        };
+
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_request_struct = message.init_root::<vmec_request_struct::Builder>();
+
+            let mut pull = vmec_request_struct.init_session_pull();
+            pull.set_device_hash(&fields.device_hash);
+            pull.set_session_token(&fields.session_token);
+            pull.set_timestamp_ms(fields.timestamp_ms);
+            pull.set_signature(&fields.signature);
+        }
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+        Ok((capnp_enc.encoded_bytes).to_vec())
+    }
+
     pub fn decode_request(bytes_to_decode: &[u8]) -> capnp::Result<VmecRequestFields> {
+        match decode_request_kind(bytes_to_decode)? {
+            VmecRequestKind::Frame(req_fields) => Ok(req_fields),
+            VmecRequestKind::Heartbeat(_) => Err(capnp::Error::failed(String::from("expected a frame request, received a heartbeat"))),
+            VmecRequestKind::Sealed(_) => Err(capnp::Error::failed(String::from("expected a frame request, received a sealed request"))),
+            VmecRequestKind::SessionPull(_) => Err(capnp::Error::failed(String::from("expected a frame request, received a session pull"))),
+        }
+    }
+
+    /// Decodes anything a client or peer server may send, so the server can tell heartbeats, frames,
+    /// sealed requests and session pulls apart.
+    pub fn decode_request_kind(bytes_to_decode: &[u8]) -> capnp::Result<VmecRequestKind> {
         use crate::capnp_bytes_io::CapnpDecoding;
         let mut capnp_dec = CapnpDecoding {
             bytes_to_decode: bytes_to_decode.to_vec(),
@@ -195,18 +602,49 @@ pub mod vmec_request_transport {
 
         let vmec_request_struct= message_reader.get_root::<vmec_request_struct::Reader>()?;
 
-        let mut req_fields = VmecRequestFields {
-            timestamp_ms: 0,
-            device_hash: "".to_string(),
-            request_hash: "".to_string(),
-            image_front: Vec::new(),
-            image_rear: Vec::new(),
-        };
+        if vmec_request_struct.has_heartbeat() {
+            let heartbeat = vmec_request_struct.get_heartbeat()?;
+            return Ok(VmecRequestKind::Heartbeat(VmecHeartbeatFields {
+                timestamp_ms: heartbeat.get_timestamp_ms(),
+                device_hash: heartbeat.get_device_hash()?.to_string(),
+                sequence: heartbeat.get_sequence(),
+                signature: heartbeat.get_signature()?.to_vec(),
+            }));
+        }
+
+        if vmec_request_struct.has_sealed() {
+            let sealed = vmec_request_struct.get_sealed()?;
+            return Ok(VmecRequestKind::Sealed(VmecSealedFields {
+                device_hash: sealed.get_device_hash()?.to_string(),
+                nonce: sealed.get_nonce()?.to_vec(),
+                ciphertext: sealed.get_ciphertext()?.to_vec(),
+            }));
+        }
+
+        if vmec_request_struct.has_session_pull() {
+            let pull = vmec_request_struct.get_session_pull()?;
+            return Ok(VmecRequestKind::SessionPull(VmecSessionPullFields {
+                device_hash: pull.get_device_hash()?.to_string(),
+                session_token: pull.get_session_token()?.to_string(),
+                timestamp_ms: pull.get_timestamp_ms(),
+                signature: pull.get_signature()?.to_vec(),
+            }));
+        }
+
+        let mut req_fields = VmecRequestFields::default();
 
         for frame in vmec_request_struct.get_frame()? {
             req_fields.timestamp_ms = frame.get_timestamp_ms();
             req_fields.device_hash = frame.get_device_hash()?.to_string();
             req_fields.request_hash = frame.get_request_hash()?.to_string();
+            req_fields.signature = frame.get_signature()?.to_vec();
+            req_fields.sequence = frame.get_sequence();
+            req_fields.session_token = frame.get_session_token()?.to_string();
+            if frame.has_location() {
+                let location = frame.get_location()?;
+                req_fields.location = Some(VmecLocation { latitude: location.get_latitude(), longitude: location.get_longitude() });
+            }
+            req_fields.nearby_radius_m = frame.get_nearby_radius_m();
 
             for image in frame.get_images()? {
                 if image.get_type()? == req_frame::camera_image::CameraDirection::Frontcam {
@@ -216,7 +654,419 @@ pub mod vmec_request_transport {
                 }
             }
         }
-        Ok(req_fields)
+        Ok(VmecRequestKind::Frame(req_fields))
+    }
+}
+
+pub mod recording {
+    //! Files of recorded encoded messages, e.g. requests captured from live traffic for replay.
+    //! Each record is the message length as a little-endian u32 followed by the encoded bytes.
+
+    use std::io::{Read, Write};
+
+    /// Longest record a reader accepts. Frames hold two camera images, which are far smaller; a
+    /// longer length prefix means the file is corrupt, and is not trusted with an allocation.
+    pub const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+    pub fn write_record<W: Write>(writer: &mut W, encoded: &[u8]) -> std::io::Result<()> {
+        writer.write_all(&(encoded.len() as u32).to_le_bytes())?;
+        writer.write_all(encoded)
+    }
+
+    pub struct RecordReader<R: Read> {
+        inner: R,
+    }
+
+    impl<R: Read> RecordReader<R> {
+        pub fn new(inner: R) -> Self {
+            RecordReader { inner }
+        }
+    }
+
+    impl<R: Read> Iterator for RecordReader<R> {
+        type Item = std::io::Result<Vec<u8>>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let mut length = [0u8; 4];
+            let mut filled = 0;
+            while filled < length.len() {
+                match self.inner.read(&mut length[filled..]) {
+                    // a clean end of file can only fall between records
+                    Ok(0) if filled == 0 => return None,
+                    Ok(0) => return Some(Err(std::io::ErrorKind::UnexpectedEof.into())),
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let len = u32::from_le_bytes(length) as usize;
+            if len > MAX_RECORD_LEN {
+                let message = format!("record of {} bytes is longer than the {} byte limit", len, MAX_RECORD_LEN);
+                return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message)));
+            }
+            let mut encoded = vec![0u8; len];
+            Some(self.inner.read_exact(&mut encoded).map(|_| encoded))
+        }
+    }
+}
+
+pub mod validation {
+    //! Protection against replayed requests. A signature proves a request came from a device, but
+    //! not that it was sent just now: a captured request could be sent again later. So a request
+    //! must carry a timestamp close to the server's clock, and a sequence number higher than any
+    //! seen before from the same device. Frames and heartbeats are sent from different threads
+    //! on the client, and are numbered separately.
+
+    use std::collections::HashMap;
+
+    use crate::{ErrorCode, VmecHeartbeatFields, VmecRequestFields};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ReplayViolation {
+        /// The request's timestamp is further from the server's clock than the allowed skew
+        ClockSkew { skew_ms: i64 },
+        /// The device has already sent a request with this sequence number or a higher one
+        Replayed { last_sequence: u64 },
+    }
+
+    impl ReplayViolation {
+        pub fn error_code(self) -> ErrorCode {
+            match self {
+                ReplayViolation::ClockSkew { .. } => ErrorCode::ClockSkew,
+                ReplayViolation::Replayed { .. } => ErrorCode::Replayed,
+            }
+        }
+
+        pub fn message(self) -> String {
+            match self {
+                ReplayViolation::ClockSkew { skew_ms } => format!("timestamp is {} ms off the server clock", skew_ms),
+                ReplayViolation::Replayed { last_sequence } => format!("sequence number is not above {}", last_sequence),
+            }
+        }
+    }
+
+    pub struct ReplayGuard {
+        max_skew_ms: u64,
+        // highest sequence number accepted from each device
+        frames: HashMap<String, u64>,
+        heartbeats: HashMap<String, u64>,
+    }
+
+    impl ReplayGuard {
+        pub fn new(max_skew_ms: u64) -> Self {
+            ReplayGuard {
+                max_skew_ms,
+                frames: HashMap::new(),
+                heartbeats: HashMap::new(),
+            }
+        }
+
+        pub fn check_frame(&mut self, fields: &VmecRequestFields, now_ms: u64) -> Result<(), ReplayViolation> {
+            check(self.max_skew_ms, &mut self.frames, &fields.device_hash, fields.timestamp_ms, fields.sequence, now_ms)
+        }
+
+        pub fn check_heartbeat(&mut self, fields: &VmecHeartbeatFields, now_ms: u64) -> Result<(), ReplayViolation> {
+            check(self.max_skew_ms, &mut self.heartbeats, &fields.device_hash, fields.timestamp_ms, fields.sequence, now_ms)
+        }
+
+        /// Carries over sequence numbers a device used with another server, so that requests it
+        /// sent there cannot be replayed here
+        pub fn restore(&mut self, device_hash: &str, frame_sequence: u64, heartbeat_sequence: u64) {
+            for (last_sequences, sequence) in [(&mut self.frames, frame_sequence), (&mut self.heartbeats, heartbeat_sequence)] {
+                let last_sequence = last_sequences.entry(String::from(device_hash)).or_insert(0);
+                *last_sequence = (*last_sequence).max(sequence);
+            }
+        }
+    }
+
+    fn check(max_skew_ms: u64, last_sequences: &mut HashMap<String, u64>, device_hash: &str, timestamp_ms: u64, sequence: u64, now_ms: u64) -> Result<(), ReplayViolation> {
+        let skew_ms = timestamp_ms as i64 - now_ms as i64;
+        if skew_ms.unsigned_abs() > max_skew_ms {
+            return Err(ReplayViolation::ClockSkew { skew_ms });
+        }
+        match last_sequences.get_mut(device_hash) {
+            Some(last_sequence) if sequence <= *last_sequence => Err(ReplayViolation::Replayed { last_sequence: *last_sequence }),
+            Some(last_sequence) => {
+                *last_sequence = sequence;
+                Ok(())
+            }
+            None => {
+                last_sequences.insert(String::from(device_hash), sequence);
+                Ok(())
+            }
+        }
+    }
+
+    /// Client side sequence numbers. They start from the current time in microseconds, so that they
+    /// keep increasing across restarts of the client without storing anything.
+    pub struct SequenceCounter {
+        last: u64,
+    }
+
+    impl SequenceCounter {
+        pub fn new() -> Self {
+            SequenceCounter { last: 0 }
+        }
+
+        pub fn next_sequence(&mut self) -> u64 {
+            let now_us = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_micros() as u64)
+                .unwrap_or(0);
+            self.last = now_us.max(self.last + 1);
+            self.last
+        }
+    }
+
+    impl Default for SequenceCounter {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+pub mod auth {
+    //! Per-device pre-shared keys. A client signs each message with a keyed BLAKE3 hash of its
+    //! contents, and the server looks up the key for the claimed `device_hash` to check it.
+    //! Images are signed through their hashes so that signing does not copy them. Servers sign
+    //! session pulls to each other the same way, with a key they share.
+
+    use crate::{VmecHeartbeatFields, VmecRequestFields, VmecSessionPullFields};
+
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct DeviceKey([u8; blake3::KEY_LEN]);
+
+    impl DeviceKey {
+        pub fn from_bytes(bytes: [u8; blake3::KEY_LEN]) -> Self {
+            DeviceKey(bytes)
+        }
+
+        /// Parses a key written as 64 hex digits, as stored in key and config files
+        pub fn from_hex(hex: &str) -> Result<Self, String> {
+            let hex = hex.trim();
+            if hex.len() != blake3::KEY_LEN * 2 || !hex.is_ascii() {
+                return Err(format!("device key must be {} hex digits", blake3::KEY_LEN * 2));
+            }
+            let mut bytes = [0u8; blake3::KEY_LEN];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                    .map_err(|_| String::from("device key is not valid hex"))?;
+            }
+            Ok(DeviceKey(bytes))
+        }
+
+        pub fn to_hex(&self) -> String {
+            self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+
+        pub(crate) fn as_bytes(&self) -> &[u8; blake3::KEY_LEN] {
+            &self.0
+        }
+
+        fn mac(&self, message: &[u8]) -> blake3::Hash {
+            blake3::keyed_hash(&self.0, message)
+        }
+
+        fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+            match <[u8; blake3::OUT_LEN]>::try_from(signature) {
+                // comparing `Hash`es is constant time
+                Ok(signature) => self.mac(message) == blake3::Hash::from(signature),
+                Err(_) => false,
+            }
+        }
+    }
+
+    fn push_field(message: &mut Vec<u8>, field: &[u8]) {
+        // length prefixes keep adjacent fields from running into each other
+        message.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        message.extend_from_slice(field);
+    }
+
+    fn frame_message(fields: &VmecRequestFields) -> Vec<u8> {
+        let mut message = Vec::from(&b"vmec-frame"[..]);
+        push_field(&mut message, &fields.timestamp_ms.to_le_bytes());
+        push_field(&mut message, fields.device_hash.as_bytes());
+        push_field(&mut message, fields.request_hash.as_bytes());
+        push_field(&mut message, &fields.sequence.to_le_bytes());
+        push_field(&mut message, blake3::hash(&fields.image_front).as_bytes());
+        push_field(&mut message, blake3::hash(&fields.image_rear).as_bytes());
+        // only when present, so that frames without one are signed as before
+        if let Some(location) = fields.location {
+            push_field(&mut message, &location.latitude.to_le_bytes());
+            push_field(&mut message, &location.longitude.to_le_bytes());
+        }
+        // likewise, and named, so that it cannot be taken for a location
+        if !fields.session_token.is_empty() {
+            push_field(&mut message, b"session_token");
+            push_field(&mut message, fields.session_token.as_bytes());
+        }
+        if fields.nearby_radius_m != 0.0 {
+            push_field(&mut message, b"nearby_radius_m");
+            push_field(&mut message, &fields.nearby_radius_m.to_le_bytes());
+        }
+        message
+    }
+
+    fn heartbeat_message(fields: &VmecHeartbeatFields) -> Vec<u8> {
+        let mut message = Vec::from(&b"vmec-heartbeat"[..]);
+        push_field(&mut message, &fields.timestamp_ms.to_le_bytes());
+        push_field(&mut message, fields.device_hash.as_bytes());
+        push_field(&mut message, &fields.sequence.to_le_bytes());
+        message
+    }
+
+    fn session_pull_message(fields: &VmecSessionPullFields) -> Vec<u8> {
+        let mut message = Vec::from(&b"vmec-session-pull"[..]);
+        push_field(&mut message, &fields.timestamp_ms.to_le_bytes());
+        push_field(&mut message, fields.device_hash.as_bytes());
+        push_field(&mut message, fields.session_token.as_bytes());
+        message
+    }
+
+    pub fn sign_frame(fields: &mut VmecRequestFields, key: &DeviceKey) {
+        fields.signature = key.mac(&frame_message(fields)).as_bytes().to_vec();
+    }
+
+    pub fn verify_frame(fields: &VmecRequestFields, key: &DeviceKey) -> bool {
+        key.verify(&frame_message(fields), &fields.signature)
+    }
+
+    pub fn sign_heartbeat(fields: &mut VmecHeartbeatFields, key: &DeviceKey) {
+        fields.signature = key.mac(&heartbeat_message(fields)).as_bytes().to_vec();
+    }
+
+    pub fn verify_heartbeat(fields: &VmecHeartbeatFields, key: &DeviceKey) -> bool {
+        key.verify(&heartbeat_message(fields), &fields.signature)
+    }
+
+    pub fn sign_session_pull(fields: &mut VmecSessionPullFields, key: &DeviceKey) {
+        fields.signature = key.mac(&session_pull_message(fields)).as_bytes().to_vec();
+    }
+
+    pub fn verify_session_pull(fields: &VmecSessionPullFields, key: &DeviceKey) -> bool {
+        key.verify(&session_pull_message(fields), &fields.signature)
+    }
+}
+
+pub mod envelope {
+    //! Optional encryption of whole messages, for links where camera images should not travel in
+    //! plaintext. An encoded request or response is encrypted with XChaCha20-Poly1305 under a key
+    //! derived from the device's pre-shared key, and sent wrapped in a `sealed` message. The device
+    //! hash travels in the clear so the server knows which key to open a request with.
+
+    use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+    use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+    use crate::auth::DeviceKey;
+    use crate::capnp_bytes_io::{CapnpDecoding, CapnpEncoding};
+    use crate::vmec_request_capnp::vmec_request_struct;
+    use crate::vmec_response_capnp::vmec_response_struct;
+    use crate::VmecSealedFields;
+
+    /// Separates the encryption key from the signing key, though both come from the same device key
+    const KEY_CONTEXT: &str = "velovision-mec 2022-11 transport encryption";
+
+    #[derive(Debug)]
+    pub enum EnvelopeError {
+        /// The message was sent in plaintext
+        NotSealed,
+        Decode(capnp::Error),
+        /// Wrong key, or the message was tampered with
+        Decrypt,
+    }
+
+    impl From<capnp::Error> for EnvelopeError {
+        fn from(e: capnp::Error) -> Self {
+            EnvelopeError::Decode(e)
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TransportKey([u8; 32]);
+
+    impl TransportKey {
+        pub fn derive(device_key: &DeviceKey) -> Self {
+            TransportKey(blake3::derive_key(KEY_CONTEXT, device_key.as_bytes()))
+        }
+
+        fn seal(&self, plaintext: &[u8], aad: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            let cipher = XChaCha20Poly1305::new((&self.0).into());
+            // 24 byte nonces are long enough to pick at random
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).expect("encryption failed");
+            (nonce.to_vec(), ciphertext)
+        }
+
+        fn open(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+            if nonce.len() != 24 {
+                return Err(EnvelopeError::Decrypt);
+            }
+            let cipher = XChaCha20Poly1305::new((&self.0).into());
+            cipher
+                .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+                .map_err(|_| EnvelopeError::Decrypt)
+        }
+    }
+
+    // binds each ciphertext to its direction and device, so it cannot be passed off as another message
+    fn associated_data(direction: &str, device_hash: &str) -> Vec<u8> {
+        [direction.as_bytes(), &[0], device_hash.as_bytes()].concat()
+    }
+
+    /// Wraps an encoded request (frame or heartbeat) for sending over an untrusted link
+    pub fn seal_request(encoded: &[u8], device_hash: &str, key: &TransportKey) -> Vec<u8> {
+        let (nonce, ciphertext) = key.seal(encoded, &associated_data("request", device_hash));
+
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_request_struct = message.init_root::<vmec_request_struct::Builder>();
+            let mut sealed = vmec_request_struct.init_sealed();
+            sealed.set_device_hash(device_hash);
+            sealed.set_nonce(&nonce);
+            sealed.set_ciphertext(&ciphertext);
+        }
+        let mut capnp_enc = CapnpEncoding { encoded_bytes: Vec::new() };
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+        capnp_enc.encoded_bytes
+    }
+
+    /// The encoded request inside a sealed one
+    pub fn open_request(sealed: &VmecSealedFields, key: &TransportKey) -> Result<Vec<u8>, EnvelopeError> {
+        key.open(&sealed.nonce, &sealed.ciphertext, &associated_data("request", &sealed.device_hash))
+    }
+
+    /// Wraps an encoded response for the device that sent a sealed request
+    pub fn seal_response(encoded: &[u8], device_hash: &str, key: &TransportKey) -> Vec<u8> {
+        let (nonce, ciphertext) = key.seal(encoded, &associated_data("response", device_hash));
+
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let vmec_response_struct = message.init_root::<vmec_response_struct::Builder>();
+            let mut sealed = vmec_response_struct.init_sealed();
+            sealed.set_nonce(&nonce);
+            sealed.set_ciphertext(&ciphertext);
+        }
+        let mut capnp_enc = CapnpEncoding { encoded_bytes: Vec::new() };
+        capnp::serialize_packed::write_message(&mut capnp_enc, &message).unwrap();
+        capnp_enc.encoded_bytes
+    }
+
+    /// The encoded response inside a sealed one. Plaintext responses are refused, as anyone on the
+    /// link could have sent them.
+    pub fn open_response(bytes_to_decode: &[u8], device_hash: &str, key: &TransportKey) -> Result<Vec<u8>, EnvelopeError> {
+        let mut capnp_dec = CapnpDecoding {
+            bytes_to_decode: bytes_to_decode.to_vec(),
+        };
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut capnp_dec,
+            ::capnp::message::ReaderOptions::new(),
+        )?;
+        let vmec_response_struct = message_reader.get_root::<vmec_response_struct::Reader>()?;
+        if !vmec_response_struct.has_sealed() {
+            return Err(EnvelopeError::NotSealed);
+        }
+        let sealed = vmec_response_struct.get_sealed()?;
+        key.open(sealed.get_nonce()?, sealed.get_ciphertext()?, &associated_data("response", device_hash))
     }
 }
 
@@ -228,4 +1078,349 @@ mod tests {
     fn it_works() {
         assert!(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn heartbeat_roundtrip() {
+        let encoded = vmec_request_transport::encode_heartbeat(VmecHeartbeatFields {
+            timestamp_ms: 1234,
+            device_hash: String::from("device"),
+            sequence: 7,
+            ..Default::default()
+        }).unwrap();
+
+        match vmec_request_transport::decode_request_kind(&encoded).unwrap() {
+            VmecRequestKind::Heartbeat(heartbeat) => {
+                assert_eq!(heartbeat.timestamp_ms, 1234);
+                assert_eq!(heartbeat.device_hash, "device");
+                assert_eq!(heartbeat.sequence, 7);
+            }
+            _ => panic!("heartbeat decoded as another kind of request"),
+        }
+        assert!(vmec_request_transport::decode_request(&encoded).is_err());
+    }
+
+    #[test]
+    fn frame_is_not_a_heartbeat() {
+        let encoded = vmec_request_transport::encode_request(VmecRequestFields {
+            timestamp_ms: 1,
+            device_hash: String::from("device"),
+            request_hash: String::from("request"),
+            image_front: vec![1, 2, 3],
+            image_rear: vec![4, 5],
+            ..Default::default()
+        }).unwrap();
+
+        match vmec_request_transport::decode_request_kind(&encoded).unwrap() {
+            VmecRequestKind::Frame(frame) => {
+                assert_eq!(frame.request_hash, "request");
+                assert_eq!(frame.image_front, vec![1, 2, 3]);
+                assert_eq!(frame.image_rear, vec![4, 5]);
+            }
+            _ => panic!("frame decoded as another kind of request"),
+        }
+    }
+
+    #[test]
+    fn health_roundtrip() {
+        let encoded = vmec_response_transport::encode_health(VmecHealthFields {
+            timestamp_ms: 99,
+            server_hash: String::from("server"),
+            uptime_ms: 5000,
+            requests_served: 42,
+            requests_per_second: 12.5,
+            mean_processing_us: 1500,
+            healthy: true,
+            in_flight: 3,
+            cache_hits: 7,
+        }).unwrap();
+
+        let health = vmec_response_transport::decode_health(&encoded).unwrap();
+        assert_eq!(health.server_hash, "server");
+        assert_eq!(health.requests_served, 42);
+        assert_eq!(health.requests_per_second, 12.5);
+        assert!(health.healthy);
+        assert_eq!(health.in_flight, 3);
+        assert_eq!(health.cache_hits, 7);
+    }
+
+    #[test]
+    fn recording_roundtrip() {
+        let mut file = Vec::new();
+        recording::write_record(&mut file, &[1, 2, 3]).unwrap();
+        recording::write_record(&mut file, &[]).unwrap();
+        recording::write_record(&mut file, &[4]).unwrap();
+
+        let records: Vec<Vec<u8>> = recording::RecordReader::new(&file[..]).map(|r| r.unwrap()).collect();
+        assert_eq!(records, vec![vec![1, 2, 3], vec![], vec![4]]);
+
+        let truncated = &file[..file.len() - 1];
+        assert!(recording::RecordReader::new(truncated).last().unwrap().is_err());
+
+        let corrupt = (recording::MAX_RECORD_LEN as u32 + 1).to_le_bytes();
+        let error = recording::RecordReader::new(&corrupt[..]).next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn busy_response_roundtrip() {
+        let encoded = vmec_response_transport::encode_response(VmecResponseFields {
+            timestamp_ms: 5,
+            server_hash: String::from("server"),
+            response_hash: String::new(),
+            neural_output: String::new(),
+            status: ResponseStatus::Busy,
+            retry_after_ms: 250,
+            ..Default::default()
+        }).unwrap();
+
+        let response = vmec_response_transport::decode_response(&encoded).unwrap();
+        assert_eq!(response.status, ResponseStatus::Busy);
+        assert_eq!(response.retry_after_ms, 250);
+    }
+
+    #[test]
+    fn error_response_roundtrip() {
+        let encoded = vmec_response_transport::encode_response(VmecResponseFields {
+            status: ResponseStatus::Error,
+            error_code: ErrorCode::Internal,
+            error_message: String::from("model crashed"),
+            ..Default::default()
+        }).unwrap();
+
+        let response = vmec_response_transport::decode_response(&encoded).unwrap();
+        assert_eq!(response.status, ResponseStatus::Error);
+        assert_eq!(response.error_code, ErrorCode::Internal);
+        assert_eq!(response.error_message, "model crashed");
+    }
+
+    #[test]
+    fn hops_roundtrip() {
+        let hops = vec![
+            VmecHopFields { server: String::from("regional"), elapsed_us: 900 },
+            VmecHopFields { server: String::from("edge"), elapsed_us: 1500 },
+        ];
+        let encoded = vmec_response_transport::encode_response(VmecResponseFields { hops: hops.clone(), ..Default::default() }).unwrap();
+        assert_eq!(vmec_response_transport::decode_response(&encoded).unwrap().hops, hops);
+    }
+
+    #[test]
+    fn model_version_roundtrip() {
+        let encoded = vmec_response_transport::encode_response(VmecResponseFields {
+            model_version: String::from("front:hazards@2 rear:vehicles@1"),
+            experiment_arm: String::from("candidate"),
+            ..Default::default()
+        }).unwrap();
+        let response = vmec_response_transport::decode_response(&encoded).unwrap();
+        assert_eq!(response.model_version, "front:hazards@2 rear:vehicles@1");
+        assert_eq!(response.experiment_arm, "candidate");
+    }
+
+    #[test]
+    fn location_and_hazards_roundtrip() {
+        let location = VmecLocation { latitude: 37.5665, longitude: 126.978 };
+        let frame = vmec_request_transport::encode_request(VmecRequestFields {
+            location: Some(location),
+            nearby_radius_m: 200.0,
+            ..Default::default()
+        }).unwrap();
+        let frame = vmec_request_transport::decode_request(&frame).unwrap();
+        assert_eq!((frame.location, frame.nearby_radius_m), (Some(location), 200.0));
+        let unlocated = vmec_request_transport::encode_request(VmecRequestFields::default()).unwrap();
+        assert_eq!(vmec_request_transport::decode_request(&unlocated).unwrap().location, None);
+
+        let hazard = VmecHazardFields {
+            location,
+            kind: String::from("pothole"),
+            score: 1.5,
+            reporters: 2,
+            last_detected_ms: 42,
+            distance_m: 12.5,
+        };
+        let encoded = vmec_response_transport::encode_response(VmecResponseFields {
+            nearby_hazards: vec![hazard.clone()],
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(vmec_response_transport::decode_response(&encoded).unwrap().nearby_hazards, vec![hazard]);
+    }
+
+    #[test]
+    fn session_pull_roundtrip() {
+        let frame = vmec_request_transport::encode_request(VmecRequestFields {
+            session_token: String::from("edge-a/1f"),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(vmec_request_transport::decode_request(&frame).unwrap().session_token, "edge-a/1f");
+
+        let pull = vmec_request_transport::encode_session_pull(VmecSessionPullFields {
+            device_hash: String::from("device"),
+            session_token: String::from("edge-a/1f"),
+            ..Default::default()
+        }).unwrap();
+        match vmec_request_transport::decode_request_kind(&pull).unwrap() {
+            VmecRequestKind::SessionPull(pull) => assert_eq!((pull.device_hash.as_str(), pull.session_token.as_str()), ("device", "edge-a/1f")),
+            _ => panic!("session pull decoded as another kind of request"),
+        }
+
+        let transfer = vmec_response_transport::encode_session(Some(b"{}")).unwrap();
+        assert_eq!(vmec_response_transport::decode_session(&transfer).unwrap().as_deref(), Some(&b"{}"[..]));
+        let not_found = vmec_response_transport::encode_session(None).unwrap();
+        assert_eq!(vmec_response_transport::decode_session(&not_found).unwrap(), None);
+        assert!(vmec_response_transport::decode_session(&vmec_response_transport::encode_response(VmecResponseFields::default()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn alert_roundtrip() {
+        let alert = VmecAlertFields {
+            timestamp_ms: 42,
+            device_hash: String::from("device\n"),
+            kind: AlertKind::HazardAhead,
+            message: String::from("pothole in 80 m"),
+            detail: String::from("{}"),
+        };
+        let encoded = vmec_response_transport::encode_alert(alert.clone()).unwrap();
+        assert_eq!(vmec_response_transport::decode_alert(&encoded).unwrap(), alert);
+        assert!(vmec_response_transport::decode_alert(&vmec_response_transport::encode_response(VmecResponseFields::default()).unwrap()).is_err());
+
+        // subscribing to one device's topic does not match a device whose hash starts with it
+        let topic = vmec_response_transport::alert_topic(&alert.device_hash);
+        assert!(topic.starts_with(&vmec_response_transport::alert_topic("device")));
+        assert!(!topic.starts_with(&vmec_response_transport::alert_topic("dev")));
+    }
+
+    #[test]
+    fn signed_frame_roundtrip() {
+        let key = auth::DeviceKey::from_hex(&"ab".repeat(32)).unwrap();
+        let mut frame = VmecRequestFields {
+            timestamp_ms: 1,
+            device_hash: String::from("device"),
+            request_hash: String::from("request"),
+            image_front: vec![1, 2, 3],
+            ..Default::default()
+        };
+        auth::sign_frame(&mut frame, &key);
+
+        let mut decoded = vmec_request_transport::decode_request(&vmec_request_transport::encode_request(frame).unwrap()).unwrap();
+        assert!(auth::verify_frame(&decoded, &key));
+
+        let other_key = auth::DeviceKey::from_bytes([1; 32]);
+        assert!(!auth::verify_frame(&decoded, &other_key));
+        decoded.image_front[0] = 9;
+        assert!(!auth::verify_frame(&decoded, &key));
+
+        let mut located = VmecRequestFields { location: Some(VmecLocation { latitude: 1.0, longitude: 2.0 }), ..Default::default() };
+        auth::sign_frame(&mut located, &key);
+        assert!(auth::verify_frame(&located, &key));
+        located.location = Some(VmecLocation { latitude: 1.0, longitude: 3.0 });
+        assert!(!auth::verify_frame(&located, &key));
+
+        // a stolen token cannot be put on another frame
+        let mut resumed = VmecRequestFields { session_token: String::from("edge-a/1f"), ..Default::default() };
+        auth::sign_frame(&mut resumed, &key);
+        assert!(auth::verify_frame(&resumed, &key));
+        resumed.session_token = String::from("edge-a/2e");
+        assert!(!auth::verify_frame(&resumed, &key));
+
+        let mut asking = VmecRequestFields { nearby_radius_m: 300.0, ..Default::default() };
+        auth::sign_frame(&mut asking, &key);
+        assert!(auth::verify_frame(&asking, &key));
+        asking.nearby_radius_m = 5000.0;
+        assert!(!auth::verify_frame(&asking, &key));
+    }
+
+    #[test]
+    fn signed_session_pull_roundtrip() {
+        let key = auth::DeviceKey::from_bytes([7; 32]);
+        let mut pull = VmecSessionPullFields {
+            device_hash: String::from("device"),
+            session_token: String::from("edge-a/1f"),
+            timestamp_ms: 100,
+            ..Default::default()
+        };
+        auth::sign_session_pull(&mut pull, &key);
+        let decoded = match vmec_request_transport::decode_request_kind(&vmec_request_transport::encode_session_pull(pull).unwrap()).unwrap() {
+            VmecRequestKind::SessionPull(pull) => pull,
+            _ => panic!("session pull decoded as another kind of request"),
+        };
+        assert!(auth::verify_session_pull(&decoded, &key));
+        assert!(!auth::verify_session_pull(&decoded, &auth::DeviceKey::from_bytes([8; 32])));
+        assert!(!auth::verify_session_pull(&VmecSessionPullFields { timestamp_ms: 101, ..decoded }, &key));
+    }
+
+    #[test]
+    fn device_key_hex() {
+        let key = auth::DeviceKey::from_bytes([0x0f; 32]);
+        assert!(auth::DeviceKey::from_hex(&key.to_hex()).unwrap() == key);
+        assert!(auth::DeviceKey::from_hex("0f0f").is_err());
+        assert!(auth::DeviceKey::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn sealed_roundtrip() {
+        let key = envelope::TransportKey::derive(&auth::DeviceKey::from_bytes([5; 32]));
+        let frame = VmecRequestFields {
+            device_hash: String::from("device"),
+            request_hash: String::from("request"),
+            image_front: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let plaintext = vmec_request_transport::encode_request(frame).unwrap();
+        let sealed = envelope::seal_request(&plaintext, "device", &key);
+        assert!(!sealed.windows(7).any(|window| window == b"request"));
+
+        let sealed = match vmec_request_transport::decode_request_kind(&sealed).unwrap() {
+            VmecRequestKind::Sealed(sealed) => sealed,
+            _ => panic!("sealed request decoded as plaintext"),
+        };
+        assert_eq!(sealed.device_hash, "device");
+        let opened = envelope::open_request(&sealed, &key).unwrap();
+        assert_eq!(vmec_request_transport::decode_request(&opened).unwrap().image_front, vec![1, 2, 3]);
+
+        let other_key = envelope::TransportKey::derive(&auth::DeviceKey::from_bytes([6; 32]));
+        assert!(matches!(envelope::open_request(&sealed, &other_key), Err(envelope::EnvelopeError::Decrypt)));
+    }
+
+    #[test]
+    fn sealed_response_roundtrip() {
+        let key = envelope::TransportKey::derive(&auth::DeviceKey::from_bytes([5; 32]));
+        let plaintext = vmec_response_transport::encode_response(VmecResponseFields {
+            neural_output: String::from("output"),
+            ..Default::default()
+        }).unwrap();
+
+        let sealed = envelope::seal_response(&plaintext, "device", &key);
+        let opened = envelope::open_response(&sealed, "device", &key).unwrap();
+        assert_eq!(vmec_response_transport::decode_response(&opened).unwrap().neural_output, "output");
+
+        // a response sealed for one device does not open as another's
+        assert!(envelope::open_response(&sealed, "other", &key).is_err());
+        assert!(matches!(envelope::open_response(&plaintext, "device", &key), Err(envelope::EnvelopeError::NotSealed)));
+    }
+
+    #[test]
+    fn replay_guard() {
+        let mut guard = validation::ReplayGuard::new(1000);
+        let frame = |timestamp_ms: u64, sequence: u64| VmecRequestFields {
+            timestamp_ms,
+            device_hash: String::from("device"),
+            sequence,
+            ..Default::default()
+        };
+        assert_eq!(guard.check_frame(&frame(10_000, 5), 10_500), Ok(()));
+        assert_eq!(guard.check_frame(&frame(10_000, 5), 10_500), Err(validation::ReplayViolation::Replayed { last_sequence: 5 }));
+        assert_eq!(guard.check_frame(&frame(10_000, 6), 12_000), Err(validation::ReplayViolation::ClockSkew { skew_ms: -2000 }));
+        assert_eq!(guard.check_frame(&frame(10_000, 6), 9_500), Ok(()));
+
+        // heartbeats are numbered separately from frames
+        let heartbeat = VmecHeartbeatFields { timestamp_ms: 10_000, device_hash: String::from("device"), sequence: 1, ..Default::default() };
+        assert_eq!(guard.check_heartbeat(&heartbeat, 10_000), Ok(()));
+
+        // sequence numbers used with another server carry over, but never go back
+        guard.restore("device", 20, 0);
+        assert_eq!(guard.check_frame(&frame(10_000, 20), 10_000), Err(validation::ReplayViolation::Replayed { last_sequence: 20 }));
+        assert_eq!(guard.check_heartbeat(&heartbeat, 10_000), Err(validation::ReplayViolation::Replayed { last_sequence: 1 }));
+
+        let mut counter = validation::SequenceCounter::new();
+        let first = counter.next_sequence();
+        assert!(counter.next_sequence() > first);
+    }
+}
@@ -0,0 +1,128 @@
+//! Alerts the server pushes to this device on its alert channel, handled on a thread of their own
+//! so that they are not held up by frame processing.
+//!
+//! Engines raise the same alert on frame after frame while it holds, so repeats are only reported
+//! once they have not been seen for a while. Alerts that arrive too late to still be of use, e.g.
+//! after the connection stalled, are dropped.
+
+use std::collections::HashMap;
+use std::thread;
+
+use log::{debug, info, warn};
+
+use cornflakes::envelope;
+use cornflakes::{vmec_response_transport, AlertKind, VmecAlertFields};
+
+use crate::DeviceCredentials;
+
+pub struct AlertFilter {
+    device_hash: String,
+    max_age_ms: u64,
+    // when each (kind, message) was last seen
+    last_seen: HashMap<(u16, String), u64>,
+}
+
+impl AlertFilter {
+    pub fn new(device_hash: &str, max_age_ms: u64) -> Self {
+        AlertFilter {
+            device_hash: String::from(device_hash.trim()),
+            max_age_ms,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Whether the alert should be reported to the rider
+    pub fn accept(&mut self, alert: &VmecAlertFields, now_ms: u64) -> bool {
+        if alert.device_hash.trim() != self.device_hash {
+            return false;
+        }
+        if now_ms.saturating_sub(alert.timestamp_ms) > self.max_age_ms {
+            debug!("Dropping stale alert: {}", alert.message);
+            return false;
+        }
+        let max_age_ms = self.max_age_ms;
+        self.last_seen.retain(|_, last_seen| now_ms.saturating_sub(*last_seen) <= max_age_ms);
+        self.last_seen.insert((alert.kind as u16, alert.message.clone()), alert.timestamp_ms).is_none()
+    }
+}
+
+fn report(alert: &VmecAlertFields) {
+    match alert.kind {
+        AlertKind::ApproachingVehicle => warn!("ALERT vehicle approaching: {} {}", alert.message, alert.detail),
+        AlertKind::HazardAhead => warn!("ALERT hazard ahead: {} {}", alert.message, alert.detail),
+        AlertKind::Operator => info!("Message from the server: {}", alert.message),
+    }
+}
+
+pub fn spawn_alert_listener(context: zmq::Context, address: String, device: DeviceCredentials, max_age_ms: u64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let topic = vmec_response_transport::alert_topic(&device.device_hash);
+        let subscriber = context.socket(zmq::SUB).unwrap();
+        subscriber.connect(&address).unwrap();
+        subscriber.set_subscribe(&topic).unwrap();
+        info!("Listening for alerts on {}", address);
+
+        let mut filter = AlertFilter::new(&device.device_hash, max_age_ms);
+        loop {
+            let Ok(message) = subscriber.recv_bytes(0) else {
+                continue;
+            };
+            let payload = &message[topic.len()..];
+            let payload = match &device.transport_key {
+                Some(key) => match envelope::open_response(payload, &device.device_hash, key) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Could not open alert from server: {:?}", e);
+                        continue;
+                    }
+                },
+                None => payload.to_vec(),
+            };
+            let alert = match vmec_response_transport::decode_alert(&payload) {
+                Ok(alert) => alert,
+                Err(e) => {
+                    warn!("Could not decode alert from server: {:?}", e);
+                    continue;
+                }
+            };
+            let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+            if filter.accept(&alert, now_ms) {
+                report(&alert);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(message: &str, timestamp_ms: u64) -> VmecAlertFields {
+        VmecAlertFields {
+            timestamp_ms,
+            device_hash: String::from("bike\n"),
+            kind: AlertKind::HazardAhead,
+            message: String::from(message),
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn common_alerts_report_repeats_once() {
+        let mut filter = AlertFilter::new("bike", 1000);
+        assert!(filter.accept(&alert("pothole", 100), 150));
+        assert!(!filter.accept(&alert("pothole", 600), 650));
+        assert!(filter.accept(&alert("glass", 600), 650));
+        // not seen for a while
+        assert!(filter.accept(&alert("pothole", 1700), 1750));
+    }
+
+    #[test]
+    fn common_alerts_drop_stale_and_misaddressed() {
+        let mut filter = AlertFilter::new("bike", 1000);
+        assert!(!filter.accept(&alert("pothole", 100), 1200));
+        let mut other = alert("pothole", 100);
+        other.device_hash = String::from("bike-2");
+        assert!(!filter.accept(&other, 150));
+    }
+}
@@ -0,0 +1,137 @@
+//! Reacts to the server's backpressure signals by sending fewer and smaller frames.
+//!
+//! `Busy` and `RateLimited` mean the frame was dropped: hold off sending entirely until the
+//! suggested retry time.
+//! `ReduceRate` means the frame was served but the server is filling up: lower JPEG quality and
+//! skip frames. `Ok` replies slowly restore full rate and quality. `Superseded` says nothing about
+//! load, as the newer frame that replaced it will get its own reply, and neither does `Error`.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use cornflakes::ResponseStatus;
+
+const MAX_QUALITY: u16 = 100;
+const QUALITY_STEP_DOWN: u16 = 20;
+const QUALITY_STEP_UP: u16 = 5;
+const MAX_FRAME_STRIDE: u32 = 8;
+/// Consecutive `Ok` replies needed before sending one more frame out of every stride
+const OK_REPLIES_PER_STRIDE_STEP: u32 = 10;
+
+pub struct SendGovernor {
+    min_quality: u16,
+    quality: u16,
+    // send one out of every `frame_stride` frames
+    frame_stride: u32,
+    hold_until: Option<Instant>,
+    consecutive_ok: u32,
+}
+
+impl SendGovernor {
+    pub fn new(min_quality: u16) -> Self {
+        assert!(min_quality > 0 && min_quality <= MAX_QUALITY);
+        SendGovernor {
+            min_quality,
+            quality: MAX_QUALITY,
+            frame_stride: 1,
+            hold_until: None,
+            consecutive_ok: 0,
+        }
+    }
+
+    /// Whether frame number `frame_index` should be sent to the server at all
+    pub fn should_send(&self, frame_index: u64) -> bool {
+        if let Some(hold_until) = self.hold_until {
+            if Instant::now() < hold_until {
+                return false;
+            }
+        }
+        frame_index.is_multiple_of(self.frame_stride as u64)
+    }
+
+    /// JPEG quality to re-encode frames at, or None to send camera frames untouched
+    pub fn jpeg_quality(&self) -> Option<u16> {
+        if self.quality < MAX_QUALITY {
+            Some(self.quality)
+        } else {
+            None
+        }
+    }
+
+    pub fn on_reply(&mut self, status: ResponseStatus, retry_after_ms: u32) {
+        match status {
+            ResponseStatus::Ok => {
+                self.consecutive_ok += 1;
+                self.quality = (self.quality + QUALITY_STEP_UP).min(MAX_QUALITY);
+                if self.consecutive_ok.is_multiple_of(OK_REPLIES_PER_STRIDE_STEP) && self.frame_stride > 1 {
+                    self.frame_stride -= 1;
+                    info!("Server keeping up; sending 1 of every {} frames", self.frame_stride);
+                }
+            }
+            ResponseStatus::ReduceRate => {
+                self.consecutive_ok = 0;
+                self.quality = self.quality.saturating_sub(QUALITY_STEP_DOWN).max(self.min_quality);
+                self.frame_stride = (self.frame_stride + 1).min(MAX_FRAME_STRIDE);
+                warn!("Server asked to reduce rate; JPEG quality {}, sending 1 of every {} frames", self.quality, self.frame_stride);
+            }
+            ResponseStatus::Busy | ResponseStatus::RateLimited => {
+                self.consecutive_ok = 0;
+                self.frame_stride = (self.frame_stride + 1).min(MAX_FRAME_STRIDE);
+                self.hold_until = Some(Instant::now() + Duration::from_millis(retry_after_ms as u64));
+                warn!("Server replied {:?}; holding off for {} ms", status, retry_after_ms);
+            }
+            ResponseStatus::Superseded | ResponseStatus::Error => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendGovernor;
+    use cornflakes::ResponseStatus;
+
+    #[test]
+    fn common_backpressure_starts_at_full_rate() {
+        let governor = SendGovernor::new(30);
+        assert!(governor.should_send(0));
+        assert!(governor.should_send(1));
+        assert_eq!(governor.jpeg_quality(), None);
+    }
+
+    #[test]
+    fn common_backpressure_reduce_rate_lowers_quality_and_rate() {
+        let mut governor = SendGovernor::new(30);
+        governor.on_reply(ResponseStatus::ReduceRate, 10);
+        assert_eq!(governor.jpeg_quality(), Some(80));
+        assert!(governor.should_send(2));
+        assert!(!governor.should_send(3));
+
+        for _ in 0..10 {
+            governor.on_reply(ResponseStatus::ReduceRate, 10);
+        }
+        assert_eq!(governor.jpeg_quality(), Some(30));
+    }
+
+    #[test]
+    fn common_backpressure_busy_holds_off() {
+        let mut governor = SendGovernor::new(30);
+        governor.on_reply(ResponseStatus::Busy, 60_000);
+        assert!(!governor.should_send(0));
+
+        let mut governor = SendGovernor::new(30);
+        governor.on_reply(ResponseStatus::RateLimited, 60_000);
+        assert!(!governor.should_send(0));
+    }
+
+    #[test]
+    fn common_backpressure_recovers_on_ok() {
+        let mut governor = SendGovernor::new(30);
+        governor.on_reply(ResponseStatus::ReduceRate, 10);
+        for _ in 0..10 {
+            governor.on_reply(ResponseStatus::Ok, 0);
+        }
+        assert_eq!(governor.jpeg_quality(), None);
+        assert!(governor.should_send(1));
+    }
+}
@@ -0,0 +1,139 @@
+//! Connection state machine, fed by frame replies and heartbeats.
+//!
+//! Connected: the last exchange with the server succeeded and it reports itself healthy.
+//! Degraded:  a few replies were missed, or the server reports itself unhealthy.
+//! Lost:      too many consecutive replies were missed.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use cornflakes::VmecHealthFields;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Degraded,
+    Lost,
+}
+
+pub struct ConnectionMonitor {
+    state: ConnectionState,
+    consecutive_misses: u32,
+    degraded_after_misses: u32,
+    lost_after_misses: u32,
+    last_reply: Option<Instant>,
+    last_health: Option<VmecHealthFields>,
+}
+
+impl ConnectionMonitor {
+    pub fn new(degraded_after_misses: u32, lost_after_misses: u32) -> Self {
+        assert!(degraded_after_misses <= lost_after_misses);
+        ConnectionMonitor {
+            // nothing has been heard from the server yet
+            state: ConnectionState::Lost,
+            consecutive_misses: 0,
+            degraded_after_misses,
+            lost_after_misses,
+            last_reply: None,
+            last_health: None,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Time since any reply (frame or heartbeat) arrived from the server
+    pub fn since_last_reply(&self) -> Option<Duration> {
+        self.last_reply.map(|t| t.elapsed())
+    }
+
+    /// A frame reply arrived in time
+    pub fn record_reply(&mut self) {
+        self.consecutive_misses = 0;
+        self.last_reply = Some(Instant::now());
+        let healthy = self.last_health.as_ref().is_none_or(|health| health.healthy);
+        self.set_state(if healthy { ConnectionState::Connected } else { ConnectionState::Degraded });
+    }
+
+    /// A heartbeat reply arrived in time
+    pub fn record_health(&mut self, health: VmecHealthFields) {
+        self.last_health = Some(health);
+        self.record_reply();
+    }
+
+    /// A frame or heartbeat reply did not arrive in time
+    pub fn record_miss(&mut self) {
+        self.consecutive_misses += 1;
+        if self.consecutive_misses >= self.lost_after_misses {
+            self.set_state(ConnectionState::Lost);
+        } else if self.consecutive_misses >= self.degraded_after_misses {
+            self.set_state(ConnectionState::Degraded);
+        }
+    }
+
+    fn set_state(&mut self, new_state: ConnectionState) {
+        if new_state == self.state {
+            return;
+        }
+        match new_state {
+            ConnectionState::Connected => info!("Connection state: {:?} -> {:?}", self.state, new_state),
+            _ => warn!("Connection state: {:?} -> {:?} ({} consecutive misses)", self.state, new_state, self.consecutive_misses),
+        }
+        self.state = new_state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionMonitor, ConnectionState};
+    use cornflakes::VmecHealthFields;
+
+    fn health(healthy: bool) -> VmecHealthFields {
+        VmecHealthFields {
+            timestamp_ms: 0,
+            server_hash: String::from("server"),
+            uptime_ms: 0,
+            requests_served: 0,
+            requests_per_second: 0.0,
+            mean_processing_us: 0,
+            healthy,
+            in_flight: 0,
+            cache_hits: 0,
+        }
+    }
+
+    #[test]
+    fn common_connection_starts_lost() {
+        let monitor = ConnectionMonitor::new(1, 3);
+        assert_eq!(monitor.state(), ConnectionState::Lost);
+        assert!(monitor.since_last_reply().is_none());
+    }
+
+    #[test]
+    fn common_connection_misses_degrade_then_lose() {
+        let mut monitor = ConnectionMonitor::new(1, 3);
+        monitor.record_reply();
+        assert_eq!(monitor.state(), ConnectionState::Connected);
+
+        monitor.record_miss();
+        assert_eq!(monitor.state(), ConnectionState::Degraded);
+        monitor.record_miss();
+        assert_eq!(monitor.state(), ConnectionState::Degraded);
+        monitor.record_miss();
+        assert_eq!(monitor.state(), ConnectionState::Lost);
+
+        monitor.record_reply();
+        assert_eq!(monitor.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn common_connection_unhealthy_server_is_degraded() {
+        let mut monitor = ConnectionMonitor::new(1, 3);
+        monitor.record_health(health(false));
+        assert_eq!(monitor.state(), ConnectionState::Degraded);
+        monitor.record_health(health(true));
+        assert_eq!(monitor.state(), ConnectionState::Connected);
+    }
+}
@@ -2,26 +2,41 @@ use std::time::Duration;
 use std::{io, env, thread};
 use std::io::Write;
 use std::fs;
+use std::sync::{Arc, Mutex};
 
 use log::{debug, info, warn, error};
 use log::LevelFilter;
 
 use clap::Parser;
 use rscam::{Camera, Config};
-use turbojpeg;
 use show_image::{ImageView, ImageInfo, create_window, WindowProxy, WindowOptions};
 use ctrlc;
-use image;
 use uuid;
 use blake3;
 
 use cornflakes::{
+    auth::{self, DeviceKey},
+    envelope::{self, EnvelopeError, TransportKey},
+    validation::SequenceCounter,
+    ResponseStatus,
+    VmecHeartbeatFields,
+    VmecLocation,
     VmecRequestFields, 
     VmecResponseFields,
     vmec_request_transport,
     vmec_response_transport,
 };
 
+use vmec_preproc::tensor::raw_pixels_to_tensor;
+
+mod alerts;
+mod backpressure;
+mod connection;
+
+use alerts::spawn_alert_listener;
+use backpressure::SendGovernor;
+use connection::ConnectionMonitor;
+
 fn print_type<T>(_: &T) {
     println!("{}", std::any::type_name::<T>())
 }
@@ -46,47 +61,10 @@ fn launch_camera(path: &str, fps: u32, width: u32, height: u32, format: &[u8]) -
     }
 }
 
-fn decode_jpeg_to_imagebuffer(frame: &rscam::Frame) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    let image: image::RgbImage = turbojpeg::decompress_image(&frame[..]).unwrap();
-    image
-}
-
-fn encode_imagebuffer_to_jpeg(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> turbojpeg::OwnedBuf {
-    turbojpeg::compress_image(image, 100, turbojpeg::Subsamp::Sub2x2).unwrap()
-}
-
-fn decode_jpeg_to_raw(frame: &rscam::Frame) -> Vec<u8> {
-    // convert JPEG from camera to raw image with turbojpeg
-    // 1.4ms for 640x480
-    let image: image::RgbImage = turbojpeg::decompress_image(&frame[..]).unwrap();
-    image.into_raw()
-}
-
-fn raw_pixels_to_tensor(pixels: &[u8], width: u32, height: u32) -> tch::Tensor {
-    let pixel_tensor = tch::Tensor::of_slice(pixels);
-
-    // pixel_tensor is a 1D tensor organized as [R, G, B, R, G, B, ...]
-    // each ['R', 'G', 'B'] is a pixel, and grouped along width, then height
-    // Torch tensor requires [C, H, W] ordering, so we need to reshape and permute
-    pixel_tensor.reshape(&[height as i64, width as i64, 3]).permute(&[2, 0, 1])
-}
-
 fn save_tensor_as_image(tensor: tch::Tensor, path: &str) {
     tch::vision::image::save(&tensor, path).unwrap();
 }
 
-fn crush(frame: &rscam::Frame, width: u32, height: u32, quality: u16) -> Vec<u8> {
-    // Too slow; 8ms for 320x240
-    assert!(quality <= 100 && quality > 0);
-    let imgbuf = decode_jpeg_to_imagebuffer(frame);
-
-    let resized_imgbuf = image::imageops::resize(&imgbuf, width, height, image::imageops::FilterType::Triangle); // choose CatmullRom (cubic) for better quality and Lanczos3 for best quality
-    
-    let encoded_jpeg = turbojpeg::compress_image(&resized_imgbuf, quality as i32, turbojpeg::Subsamp::Sub2x2).unwrap();
-
-    encoded_jpeg[..].to_vec()
-}
-
 fn write_jpeg_to_file(path: &str, frame: &rscam::Frame) {
     let mut file = std::fs::OpenOptions::new()
     .write(true)
@@ -110,6 +88,140 @@ fn hash_strings(strings: Vec<String>) -> String {
     hasher.finalize().to_hex().to_string()
 }
 
+fn spawn_request(context: &zmq::Context, address: &str, receive_timeout: i32, max_retries: u32, request_to_send: Vec<u8>) -> thread::JoinHandle<io::Result<(Duration, Vec<u8>)>> {
+    let context = context.clone();
+    let address = String::from(address);
+
+    thread::spawn(move || {
+        // time round trip, including any resends
+        let sent_time = std::time::Instant::now();
+
+        for attempt in 0..=max_retries {
+            // a REQ socket that timed out cannot send again, so every attempt gets a fresh one
+            let requester = context.socket(zmq::REQ).unwrap();
+            requester.set_rcvtimeo(receive_timeout).unwrap(); // Set timeout because otherwise infinite connections will be made since ZMQ socket is run in a thread
+            requester.set_linger(0).unwrap();
+            assert!(requester.connect(&address).is_ok());
+
+            if attempt > 0 {
+                // same bytes and request_hash, so the server can answer from its response cache
+                info!("Resending request (retry {} of {})", attempt, max_retries);
+            }
+            debug!("Inside ZMQ Thread: Sending request");
+            debug!("Length of request: {}", request_to_send.len());
+            requester.send(&request_to_send[..], 0).unwrap();
+            debug!("Inside ZMQ Thread: Sent request");
+
+            match requester.recv_bytes(0) {
+                Ok(received_bytes) => {
+                    let duration = sent_time.elapsed();
+                    debug!("Length of received bytes: {}", received_bytes.len());
+                    return Ok((duration, received_bytes));
+                },
+                Err(_) => {
+                    debug!("Inside ZMQ Thread: Socket timed out waiting for reply");
+                }
+            }
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "ZMQ Timeout"))
+    })
+}
+
+/// Who this device is, and the keys it signs and encrypts with, if any
+#[derive(Clone)]
+struct DeviceCredentials {
+    device_hash: String,
+    device_key: Option<DeviceKey>,
+    transport_key: Option<TransportKey>,
+}
+
+/// Opens a sealed reply when the link is encrypted, and passes plaintext through otherwise
+fn open_reply(reply_bytes: Vec<u8>, device_hash: &str, transport_key: Option<&TransportKey>) -> Result<Vec<u8>, EnvelopeError> {
+    match transport_key {
+        Some(key) => envelope::open_response(&reply_bytes, device_hash, key),
+        None => Ok(reply_bytes),
+    }
+}
+
+fn spawn_heartbeat(
+    context: zmq::Context,
+    address: String,
+    device: DeviceCredentials,
+    interval: Duration,
+    receive_timeout: i32,
+    monitor: Arc<Mutex<ConnectionMonitor>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut sequences = SequenceCounter::new();
+        loop {
+            thread::sleep(interval);
+
+            // frame replies already prove the server is alive; only probe when the link has gone quiet
+            let recently_heard = monitor.lock().unwrap().since_last_reply().is_some_and(|elapsed| elapsed < interval);
+            if recently_heard {
+                continue;
+            }
+
+            let sequence = sequences.next_sequence();
+            let mut heartbeat = VmecHeartbeatFields {
+                timestamp_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64,
+                device_hash: device.device_hash.clone(),
+                sequence,
+                signature: Vec::new(),
+            };
+            if let Some(key) = &device.device_key {
+                auth::sign_heartbeat(&mut heartbeat, key);
+            }
+            let mut heartbeat = vmec_request_transport::encode_heartbeat(heartbeat).unwrap();
+            if let Some(key) = &device.transport_key {
+                heartbeat = envelope::seal_request(&heartbeat, &device.device_hash, key);
+            }
+
+            // a REQ socket that timed out cannot send again, so every heartbeat gets a fresh one
+            let requester = context.socket(zmq::REQ).unwrap();
+            requester.set_rcvtimeo(receive_timeout).unwrap();
+            requester.set_linger(0).unwrap();
+            assert!(requester.connect(&address).is_ok());
+            requester.send(heartbeat, 0).unwrap();
+
+            match requester.recv_bytes(0) {
+                Ok(reply_bytes) => match open_reply(reply_bytes, &device.device_hash, device.transport_key.as_ref()) {
+                    Ok(reply_bytes) => match vmec_response_transport::decode_health(&reply_bytes) {
+                        Ok(health) => {
+                            debug!("Heartbeat {}: server healthy={} load={:.1} req/s in flight={} mean processing={} μs",
+                                sequence, health.healthy, health.requests_per_second, health.in_flight, health.mean_processing_us);
+                            monitor.lock().unwrap().record_health(health);
+                        },
+                        Err(e) => {
+                            warn!("Heartbeat {}: could not decode reply: {:?}", sequence, e);
+                            monitor.lock().unwrap().record_miss();
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Heartbeat {}: could not open reply: {:?}", sequence, e);
+                        monitor.lock().unwrap().record_miss();
+                    }
+                },
+                Err(_) => {
+                    debug!("Heartbeat {}: timed out waiting for reply", sequence);
+                    monitor.lock().unwrap().record_miss();
+                }
+            }
+        }
+    })
+}
+
+/// Parses "<latitude>,<longitude>"
+fn parse_location(text: &str) -> Result<VmecLocation, String> {
+    let (latitude, longitude) = text.split_once(',').ok_or("expected <latitude>,<longitude>")?;
+    let latitude: f64 = latitude.trim().parse().map_err(|_| format!("invalid latitude {}", latitude))?;
+    let longitude: f64 = longitude.trim().parse().map_err(|_| format!("invalid longitude {}", longitude))?;
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(String::from("latitude must be within ±90 and longitude within ±180"));
+    }
+    Ok(VmecLocation { latitude, longitude })
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
 struct Args {
@@ -138,6 +250,51 @@ struct Args {
     #[arg(long, default_value="300")]
     // milliseconds to wait for a response from the server
     receive_timeout: i32,
+
+    #[arg(long, default_value="200")]
+    /// Milliseconds of silence from the server before a heartbeat is sent
+    heartbeat_interval: u64,
+
+    #[arg(long, default_value="1")]
+    /// Consecutive missed replies before the connection is considered degraded
+    degraded_after_misses: u32,
+
+    #[arg(long, default_value="3")]
+    /// Consecutive missed replies before the connection is considered lost
+    lost_after_misses: u32,
+
+    #[arg(long, default_value="30")]
+    /// Lowest JPEG quality to fall back to when the server asks to reduce rate
+    min_jpeg_quality: u16,
+
+    #[arg(long, default_value="0")]
+    /// Times to resend a frame whose reply timed out; the server answers resends without processing them again
+    max_retries: u32,
+
+    #[arg(long)]
+    /// File holding this device's key from `vmec-server keygen`; requests are sent unsigned without it
+    device_key: Option<String>,
+
+    #[arg(long)]
+    /// Encrypt requests and replies with the device key; the server must list this device in its config
+    encrypt: bool,
+
+    #[arg(long)]
+    /// Port the server publishes alerts on (its --alert-port); no alerts are received without it
+    alert_port: Option<u16>,
+
+    #[arg(long, default_value="2000")]
+    /// Alerts older than this many milliseconds are dropped, and repeats within it reported once
+    alert_max_age_ms: u64,
+
+    #[arg(long, value_parser=parse_location)]
+    /// Where the device is, as "<latitude>,<longitude>", for a device without GPS that stays put;
+    /// frames carry no location without it
+    location: Option<VmecLocation>,
+
+    #[arg(long, default_value="0")]
+    /// Ask the server for the hazards on its map within this many metres with every frame
+    nearby_radius_m: f32,
 }
 
 #[show_image::main]
@@ -180,11 +337,57 @@ fn main() {
 
     // basic ZMQ request client
     let context = zmq::Context::new();
+    let address = format!("tcp://{}:{}",
+        args.server_ip,
+        args.server_port,
+    );
+
+    let device_key = args.device_key.as_ref().map(|path| {
+        let hex = fs::read_to_string(path).expect("Could not read device key file");
+        DeviceKey::from_hex(&hex).expect("Invalid device key")
+    });
+    let transport_key = if args.encrypt {
+        let device_key = device_key.as_ref().expect("--encrypt needs a --device-key");
+        Some(TransportKey::derive(device_key))
+    } else {
+        None
+    };
+    let device = DeviceCredentials {
+        device_hash: get_machine_hash(),
+        device_key,
+        transport_key,
+    };
+
+    let monitor = Arc::new(Mutex::new(ConnectionMonitor::new(
+        args.degraded_after_misses,
+        args.lost_after_misses,
+    )));
+    spawn_heartbeat(
+        context.clone(),
+        address.clone(),
+        device.clone(),
+        Duration::from_millis(args.heartbeat_interval),
+        args.receive_timeout,
+        monitor.clone(),
+    );
+
+    if let Some(alert_port) = args.alert_port {
+        let alert_address = format!("tcp://{}:{}", args.server_ip, alert_port);
+        spawn_alert_listener(context.clone(), alert_address, device.clone(), args.alert_max_age_ms);
+    }
+
+    let mut governor = SendGovernor::new(args.min_jpeg_quality);
+    let mut frame_sequences = SequenceCounter::new();
+    // lets the server we move to take over our session from the one we were using
+    let mut session_token = String::new();
+    // models the server last answered with, logged when they change
+    let mut model_version = String::new();
 
     let mut i = 0;
     loop {
         i += 1;
         debug!("\n=== Next Frame ===");
+        debug!("Connection state: {:?}", monitor.lock().unwrap().state());
 
         let start = std::time::Instant::now();
 
@@ -195,7 +398,7 @@ fn main() {
         let (width, height) = frame.resolution;
 
         // decode, resize, and re-encode with lower quality
-        // let jpeg_bytes = crush(&frame, 320, 240, 50);
+        // let jpeg_bytes = vmec_preproc::crush(&frame, 320, 240, 50).unwrap();
 
         if args.save_cam_image {
             write_jpeg_to_file(&format!("{}/{}_{}_{}", "images", "image", &i.to_string(), "rawcam"), &frame);
@@ -203,57 +406,46 @@ fn main() {
 
         debug!("Size of frame in bytes: {}", frame.len());
 
-        let vmec_request_vals = VmecRequestFields {
-            timestamp_ms: timestamp,
-            device_hash: get_machine_hash(),
-            request_hash: request_hash,
-            image_front: Vec::from(&(*frame)),
-            // image_front: Vec::from([1,2,3]),
-            image_rear: Vec::from(&(*frame)),
-            // image_rear: Vec::from([1,2,3]),
-        };
-
-        let request_to_send = vmec_request_transport::encode_request(vmec_request_vals).unwrap();
-
-        let requester = context.socket(zmq::REQ).unwrap();
-        requester.set_rcvtimeo(args.receive_timeout).unwrap(); // Set timeout because otherwise infinite connections will be made since ZMQ socket is run in a thread
-        //let address = "tcp://localhost:5555";
-        let address = format!("tcp://{}:{}",
-            args.server_ip,
-            args.server_port,
-        );
-        assert!(requester.connect(&address).is_ok());
-
-        let request_handle = thread::spawn(move || {
-            //requester.send("Hello", 0).unwrap();
-            // time round trip
-            let sent_time = std::time::Instant::now();
-            debug!("Inside ZMQ Thread: Sending request");
-            debug!("Length of request: {}", request_to_send.len());
-            requester.send(request_to_send, 0).unwrap();
-            debug!("Inside ZMQ Thread: Sent request");
-
-            //let received_bytes = requester.recv_bytes(0).unwrap();
+        let request_handle = if governor.should_send(i as u64) {
+            // re-encode at lower quality only when the server has asked us to back off
+            let jpeg_bytes = match governor.jpeg_quality() {
+                Some(quality) => vmec_preproc::crush(&frame, width, height, quality as u8).unwrap_or_else(|e| {
+                    warn!("Sending frame at full quality: {}", e);
+                    Vec::from(&(*frame))
+                }),
+                None => Vec::from(&(*frame)),
+            };
 
-            match requester.recv_bytes(0) {
-                Ok(received_bytes) => {
-                    let duration = sent_time.elapsed();
-                    debug!("Length of received bytes: {}", received_bytes.len());
-                    Ok((duration, received_bytes))
-                },
-                Err(_) => {
-                    debug!("Inside ZMQ Thread: Socket timed out waiting for reply");
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, "ZMQ Timeout"))
-                }
+            let mut vmec_request_vals = VmecRequestFields {
+                timestamp_ms: timestamp,
+                device_hash: get_machine_hash(),
+                request_hash,
+                image_front: jpeg_bytes.clone(),
+                // image_front: Vec::from([1,2,3]),
+                image_rear: jpeg_bytes,
+                // image_rear: Vec::from([1,2,3]),
+                signature: Vec::new(),
+                sequence: frame_sequences.next_sequence(),
+                session_token: session_token.clone(),
+                location: args.location,
+                nearby_radius_m: args.nearby_radius_m,
+            };
+            if let Some(key) = &device.device_key {
+                auth::sign_frame(&mut vmec_request_vals, key);
             }
 
-            // calculate duration
-            //let duration = sent_time.elapsed();
-            //(duration, received_bytes)
-        });
+            let mut request_to_send = vmec_request_transport::encode_request(vmec_request_vals).unwrap();
+            if let Some(key) = &device.transport_key {
+                request_to_send = envelope::seal_request(&request_to_send, &device.device_hash, key);
+            }
+            Some(spawn_request(&context, &address, args.receive_timeout, args.max_retries, request_to_send))
+        } else {
+            debug!("Backpressure: not sending frame {}", i);
+            None
+        };
 
         let t1 = std::time::Instant::now();
-        let raw_pixels = decode_jpeg_to_raw(&frame);
+        let raw_pixels = vmec_preproc::decode_jpeg(&frame).unwrap().into_raw();
         debug!("Time to decode JPEG: {} microseconds", t1.elapsed().as_micros());
         debug!("Size of raw_pixels in bytes: {}", raw_pixels.len());
         let image_tensor = raw_pixels_to_tensor(&raw_pixels, width, height);
@@ -281,22 +473,64 @@ fn main() {
 
         thread::sleep(Duration::from_millis(500)); // mock work on client
 
-        if request_handle.is_finished() {
-            debug!("Request thread finished");
-            let (roundtrip_time, reply_bytes) = match request_handle.join().unwrap() {
-                Ok(thread_output) => thread_output,
-                Err(e) => {
-                    debug!("Error in request thread: {:?}", e);
-                    continue;
+        match request_handle {
+            Some(request_handle) if request_handle.is_finished() => {
+                debug!("Request thread finished");
+                let (roundtrip_time, reply_bytes) = match request_handle.join().unwrap() {
+                    Ok(thread_output) => thread_output,
+                    Err(e) => {
+                        debug!("Error in request thread: {:?}", e);
+                        monitor.lock().unwrap().record_miss();
+                        continue;
+                    }
+                };
+                let reply_bytes = match open_reply(reply_bytes, &device.device_hash, device.transport_key.as_ref()) {
+                    Ok(reply_bytes) => reply_bytes,
+                    Err(e) => {
+                        warn!("Could not open reply from server: {:?}", e);
+                        monitor.lock().unwrap().record_miss();
+                        continue;
+                    }
+                };
+                let reply = match vmec_response_transport::decode_response(&reply_bytes) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!("Could not decode reply from server: {:?}", e);
+                        monitor.lock().unwrap().record_miss();
+                        continue;
+                    }
+                };
+                monitor.lock().unwrap().record_reply();
+                info!("Roundtrip time: {} μs", roundtrip_time.as_micros());
+                info!("Roundtrip time: {} ms",
+            roundtrip_time.as_millis());
+                info!("Reply data timestamp: {} ms", reply.timestamp_ms);
+                match reply.status {
+                    ResponseStatus::Superseded => debug!("Frame was superseded by a newer one before the server got to it"),
+                    ResponseStatus::Error => warn!("Server could not process frame: {:?} {}", reply.error_code, reply.error_message),
+                    _ => {}
                 }
-            };
-            let reply = vmec_response_transport::decode_response(&reply_bytes).unwrap();
-            info!("Roundtrip time: {} μs", roundtrip_time.as_micros());
-            info!("Roundtrip time: {} ms",
-        roundtrip_time.as_millis());
-            info!("Reply data timestamp: {} ms", reply.timestamp_ms);
-        } else {
-            warn!("Timeout: Reply from server did not arrive by the time local work was done. Continuing...");
+                if !reply.session_token.is_empty() && reply.session_token != session_token {
+                    info!("Session token: {}", reply.session_token);
+                    session_token = reply.session_token;
+                }
+                for hazard in &reply.nearby_hazards {
+                    debug!("Nearby hazard: {} in {:.0} m, reported by {} devices", hazard.kind, hazard.distance_m, hazard.reporters);
+                }
+                if !reply.model_version.is_empty() && reply.model_version != model_version {
+                    match reply.experiment_arm.as_str() {
+                        "" => info!("Models: {}", reply.model_version),
+                        arm => info!("Models: {} (experiment arm {})", reply.model_version, arm),
+                    }
+                    model_version = reply.model_version;
+                }
+                governor.on_reply(reply.status, reply.retry_after_ms);
+            },
+            Some(_) => {
+                warn!("Timeout: Reply from server did not arrive by the time local work was done. Continuing...");
+                monitor.lock().unwrap().record_miss();
+            },
+            None => {},
         }
     }
 }
@@ -308,10 +542,8 @@ mod tests {
     // + "bare" tests are for systems with cameras & hardware connected. They are expected to fail on CI
     use tch::Kind;
     use std::env;
-    use super::{
-        raw_pixels_to_tensor,
-        launch_camera,
-    };
+    use vmec_preproc::tensor::raw_pixels_to_tensor;
+    use super::launch_camera;
 
     fn running_in_ci_server() -> bool {
         // check CI environment variable
@@ -324,6 +556,13 @@ mod tests {
         assert_eq!(2 + 2, 4);
     }
     #[test]
+    fn common_parse_location() {
+        let location = super::parse_location("37.5665, 126.978").unwrap();
+        assert_eq!((location.latitude, location.longitude), (37.5665, 126.978));
+        assert!(super::parse_location("37.5665").is_err());
+        assert!(super::parse_location("137.5,0").is_err());
+    }
+    #[test]
     fn bare_preproc_launch_camera() {
         if running_in_ci_server() {
             assert!(false, "This is a bare test, and should not be run on CI");
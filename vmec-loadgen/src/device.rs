@@ -0,0 +1,163 @@
+//! A virtual device: sends frames to the server at a fixed rate from its own REQ socket, and
+//! records how each request went.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+
+use cornflakes::auth::{self, DeviceKey};
+use cornflakes::envelope::{self, TransportKey};
+use cornflakes::validation::SequenceCounter;
+use cornflakes::{vmec_request_transport, vmec_response_transport, VmecRequestFields};
+use vmec_preproc::RgbImage;
+
+use crate::report::Sample;
+
+/// Camera images to put in the frames
+pub enum FrameSource {
+    /// A JPEG of random pixels of about this size for each camera, which does not compress away
+    /// on the wire
+    Synthetic { image_bytes: usize },
+    /// (front, rear) images taken from a recording, sent in turn
+    Recorded(Arc<Vec<(Vec<u8>, Vec<u8>)>>),
+}
+
+pub struct VirtualDevice {
+    pub index: usize,
+    pub device_hash: String,
+    /// Starts every request hash, so that runs do not reuse each other's hashes
+    pub request_prefix: String,
+    pub address: String,
+    /// Frames per second
+    pub rate: f64,
+    pub receive_timeout_ms: i32,
+    pub source: FrameSource,
+    /// Signs requests when set
+    pub device_key: Option<DeviceKey>,
+    /// Seals requests and opens replies when set
+    pub transport_key: Option<TransportKey>,
+}
+
+/// A JPEG of random pixels, sized to be about `image_bytes` long
+pub fn synthetic_jpeg(image_bytes: usize) -> Vec<u8> {
+    let noise = |side: u32| {
+        let mut pixels = vec![0u8; (side * side * 3) as usize];
+        rand::thread_rng().fill_bytes(&mut pixels);
+        vmec_preproc::encode_jpeg(&RgbImage::from_raw(side, side, pixels).unwrap(), 90).unwrap()
+    };
+    // noise compresses about as well at any size, so a small image tells how many bytes a pixel takes
+    const PROBE_SIDE: u32 = 64;
+    let bytes_per_pixel = noise(PROBE_SIDE).len() as f64 / (PROBE_SIDE * PROBE_SIDE) as f64;
+    let side = (image_bytes as f64 / bytes_per_pixel).sqrt().round().max(8.0) as u32;
+    noise(side)
+}
+
+fn connect(context: &zmq::Context, address: &str, receive_timeout_ms: i32) -> zmq::Socket {
+    let socket = context.socket(zmq::REQ).unwrap();
+    socket.set_rcvtimeo(receive_timeout_ms).unwrap();
+    socket.set_linger(0).unwrap();
+    assert!(socket.connect(address).is_ok());
+    socket
+}
+
+fn ms_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+impl VirtualDevice {
+    /// Sends frames from `start` until `start + duration`, one at a time, and returns a sample for each
+    pub fn run(self, context: zmq::Context, start: Instant, duration: Duration) -> Vec<Sample> {
+        let interval = Duration::from_secs_f64(1.0 / self.rate);
+        let frames = match self.source {
+            FrameSource::Synthetic { image_bytes } => {
+                let image = synthetic_jpeg(image_bytes);
+                Arc::new(vec![(image.clone(), image)])
+            }
+            FrameSource::Recorded(frames) => frames,
+        };
+
+        let mut socket = connect(&context, &self.address, self.receive_timeout_ms);
+        let mut sequences = SequenceCounter::new();
+        let mut samples = Vec::new();
+        // spread devices over the first interval, so they do not all send in lockstep
+        let mut next_send = start + interval.mul_f64(self.index as f64 % 16.0 / 16.0);
+
+        for frame_index in 0.. {
+            if next_send >= start + duration {
+                break;
+            }
+            let now = Instant::now();
+            if next_send > now {
+                thread::sleep(next_send - now);
+            }
+
+            let (image_front, image_rear) = frames[(self.index + frame_index) % frames.len()].clone();
+            let mut fields = VmecRequestFields {
+                timestamp_ms: ms_now(),
+                device_hash: self.device_hash.clone(),
+                request_hash: format!("{}-{}", self.request_prefix, frame_index),
+                image_front,
+                image_rear,
+                sequence: sequences.next_sequence(),
+                ..Default::default()
+            };
+            if let Some(key) = &self.device_key {
+                auth::sign_frame(&mut fields, key);
+            }
+            let mut request = vmec_request_transport::encode_request(fields).unwrap();
+            if let Some(key) = &self.transport_key {
+                request = envelope::seal_request(&request, &self.device_hash, key);
+            }
+            let request_bytes = request.len();
+
+            let sent = Instant::now();
+            socket.send(request, 0).unwrap();
+            let (rtt, outcome) = match socket.recv_bytes(0) {
+                Ok(reply) => {
+                    let rtt = sent.elapsed();
+                    let reply = match &self.transport_key {
+                        Some(key) => envelope::open_response(&reply, &self.device_hash, key).ok(),
+                        None => Some(reply),
+                    };
+                    match reply.map(|reply| vmec_response_transport::decode_response(&reply)) {
+                        Some(Ok(response)) => (Some(rtt), format!("{:?}", response.status)),
+                        _ => (Some(rtt), String::from("malformed")),
+                    }
+                }
+                Err(_) => {
+                    // a REQ socket that timed out cannot send again
+                    socket = connect(&context, &self.address, self.receive_timeout_ms);
+                    (None, String::from("timeout"))
+                }
+            };
+            samples.push(Sample {
+                device: self.index,
+                sent_after: sent - start,
+                rtt,
+                outcome,
+                request_bytes,
+            });
+
+            // a device that fell behind sends its next frame right away, rather than a burst to catch up
+            next_send = (next_send + interval).max(Instant::now());
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_images_are_jpegs_of_about_the_requested_size() {
+        let jpeg = synthetic_jpeg(20_000);
+        assert!(vmec_preproc::decode_jpeg(&jpeg).is_ok());
+        assert!((15_000..25_000).contains(&jpeg.len()), "{} bytes", jpeg.len());
+    }
+}
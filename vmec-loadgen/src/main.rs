@@ -0,0 +1,140 @@
+//! Load generator for `vmec-server`: runs many virtual devices at once, each sending frames at a
+//! fixed rate, and reports the server's throughput and round trip times.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use cornflakes::auth::DeviceKey;
+use cornflakes::envelope::TransportKey;
+use cornflakes::recording::RecordReader;
+use cornflakes::{vmec_request_transport, VmecRequestKind};
+
+mod device;
+mod report;
+
+use device::{FrameSource, VirtualDevice};
+use report::ExportFormat;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[arg(long, default_value="localhost")]
+    server_ip: String,
+
+    #[arg(long, default_value="5555")]
+    server_port: u16,
+
+    #[arg(long, default_value="10")]
+    /// Number of virtual devices sending at once
+    devices: usize,
+
+    #[arg(long, default_value="5.0")]
+    /// Frames per second sent by each device
+    rate: f64,
+
+    #[arg(long, default_value="30")]
+    /// How long to send for, in seconds
+    duration: u64,
+
+    #[arg(long, default_value="20000")]
+    /// Size of each synthetic camera image, in bytes; images are JPEGs of random pixels, of about this size
+    image_bytes: usize,
+
+    #[arg(long)]
+    /// Send the images from a recording made with `vmec-server --record-requests` instead of synthetic ones
+    recording: Option<PathBuf>,
+
+    #[arg(long, default_value="1000")]
+    /// Milliseconds to wait for each reply before counting it as timed out
+    receive_timeout: i32,
+
+    #[arg(long)]
+    /// File to export every request's result to
+    output: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value="csv")]
+    format: ExportFormat,
+
+    #[arg(long)]
+    /// File holding a device key from `vmec-server keygen` to sign every device's requests with; the
+    /// server must list each device, `loadgen-<index>`, with this key. Requests are sent unsigned without it
+    device_key: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Encrypt requests and replies with the device key, as `vmec-client --encrypt` does
+    encrypt: bool,
+}
+
+fn load_recording(path: &PathBuf) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let records = RecordReader::new(BufReader::new(File::open(path).expect("Could not open recording")));
+    let mut frames = Vec::new();
+    for encoded in records {
+        let encoded = encoded.expect("Could not read recording");
+        if let Ok(VmecRequestKind::Frame(request)) = vmec_request_transport::decode_request_kind(&encoded) {
+            frames.push((request.image_front, request.image_rear));
+        }
+    }
+    assert!(!frames.is_empty(), "Recording holds no frames");
+    frames
+}
+
+fn main() {
+    let args = Args::parse();
+    assert!(args.rate > 0.0, "--rate must be positive");
+
+    let recorded = args.recording.as_ref().map(|path| {
+        let frames = load_recording(path);
+        println!("Sending {} recorded frames", frames.len());
+        Arc::new(frames)
+    });
+    let device_key = args.device_key.as_ref().map(|path| {
+        let hex = fs::read_to_string(path).expect("Could not read device key file");
+        DeviceKey::from_hex(&hex).expect("Invalid device key")
+    });
+    let transport_key = args.encrypt.then(|| TransportKey::derive(device_key.as_ref().expect("--encrypt needs a --device-key")));
+    let address = format!("tcp://{}:{}", args.server_ip, args.server_port);
+    let run_id = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+
+    println!("{} devices sending {} frames/s each to {} for {} s", args.devices, args.rate, address, args.duration);
+    let context = zmq::Context::new();
+    let duration = Duration::from_secs(args.duration);
+    let start = Instant::now();
+    let handles: Vec<thread::JoinHandle<Vec<report::Sample>>> = (0..args.devices).map(|index| {
+        let device_hash = format!("loadgen-{}", index);
+        let device = VirtualDevice {
+            index,
+            request_prefix: format!("{}-{}", device_hash, run_id),
+            device_hash,
+            address: address.clone(),
+            rate: args.rate,
+            receive_timeout_ms: args.receive_timeout,
+            source: match &recorded {
+                Some(frames) => FrameSource::Recorded(frames.clone()),
+                None => FrameSource::Synthetic { image_bytes: args.image_bytes },
+            },
+            device_key: device_key.clone(),
+            transport_key: transport_key.clone(),
+        };
+        let context = context.clone();
+        thread::spawn(move || device.run(context, start, duration))
+    }).collect();
+
+    let mut samples: Vec<report::Sample> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+    let elapsed = start.elapsed();
+    samples.sort_by_key(|sample| sample.sent_after);
+
+    let summary = report::summarize(&samples, args.devices, elapsed);
+    summary.print();
+
+    if let Some(output) = &args.output {
+        let mut writer = BufWriter::new(File::create(output).expect("Could not create output file"));
+        report::export(&mut writer, args.format, &summary, &samples).expect("Could not write results");
+        println!("Wrote {} results to {}", samples.len(), output.display());
+    }
+}
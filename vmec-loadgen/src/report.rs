@@ -0,0 +1,157 @@
+//! Results of a load test: one sample per request sent, summarized as throughput and RTT
+//! percentiles, and exported as CSV or JSON so runs against different server versions can be compared.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One row per request
+    Csv,
+    /// The summary, followed by every request
+    Json,
+}
+
+/// What became of one request
+pub struct Sample {
+    pub device: usize,
+    /// When the request was sent, since the start of the run
+    pub sent_after: Duration,
+    /// Round trip time, or None if no reply arrived in time
+    pub rtt: Option<Duration>,
+    /// Response status as reported by the server, "timeout", or "malformed"
+    pub outcome: String,
+    pub request_bytes: usize,
+}
+
+pub struct Summary {
+    pub devices: usize,
+    pub elapsed: Duration,
+    pub sent: usize,
+    pub replies: usize,
+    /// Requests answered with status Ok, per second of the run
+    pub throughput: f64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    pub outcomes: BTreeMap<String, usize>,
+}
+
+pub fn summarize(samples: &[Sample], devices: usize, elapsed: Duration) -> Summary {
+    let mut rtts: Vec<u64> = samples.iter().filter_map(|sample| sample.rtt).map(|rtt| rtt.as_micros() as u64).collect();
+    rtts.sort_unstable();
+    let percentile = |p: usize| if rtts.is_empty() { 0 } else { rtts[(rtts.len() - 1) * p / 100] };
+
+    let mut outcomes = BTreeMap::new();
+    for sample in samples {
+        *outcomes.entry(sample.outcome.clone()).or_insert(0) += 1;
+    }
+    let served = outcomes.get("Ok").copied().unwrap_or(0);
+
+    Summary {
+        devices,
+        elapsed,
+        sent: samples.len(),
+        replies: rtts.len(),
+        throughput: if elapsed.is_zero() { 0.0 } else { served as f64 / elapsed.as_secs_f64() },
+        p50_us: percentile(50),
+        p95_us: percentile(95),
+        p99_us: percentile(99),
+        max_us: percentile(100),
+        outcomes,
+    }
+}
+
+impl Summary {
+    pub fn print(&self) {
+        println!("{} devices, {:.1} s: {} requests sent, {} replies", self.devices, self.elapsed.as_secs_f64(), self.sent, self.replies);
+        println!("Throughput {:.1} frames/s served", self.throughput);
+        println!("RTT p50 {} μs, p95 {} μs, p99 {} μs, max {} μs", self.p50_us, self.p95_us, self.p99_us, self.max_us);
+        for (outcome, count) in &self.outcomes {
+            println!("  {}: {}", outcome, count);
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "devices": self.devices,
+            "elapsed_s": self.elapsed.as_secs_f64(),
+            "sent": self.sent,
+            "replies": self.replies,
+            "throughput": self.throughput,
+            "p50_us": self.p50_us,
+            "p95_us": self.p95_us,
+            "p99_us": self.p99_us,
+            "max_us": self.max_us,
+            "outcomes": self.outcomes,
+        })
+    }
+}
+
+pub fn export<W: Write>(writer: &mut W, format: ExportFormat, summary: &Summary, samples: &[Sample]) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(writer, "device,sent_ms,rtt_us,outcome,request_bytes")?;
+            for sample in samples {
+                let rtt_us = sample.rtt.map(|rtt| rtt.as_micros().to_string()).unwrap_or_default();
+                writeln!(writer, "{},{},{},{},{}", sample.device, sample.sent_after.as_millis(), rtt_us, sample.outcome, sample.request_bytes)?;
+            }
+        }
+        ExportFormat::Json => {
+            let requests: Vec<serde_json::Value> = samples.iter().map(|sample| serde_json::json!({
+                "device": sample.device,
+                "sent_ms": sample.sent_after.as_millis() as u64,
+                "rtt_us": sample.rtt.map(|rtt| rtt.as_micros() as u64),
+                "outcome": sample.outcome,
+                "request_bytes": sample.request_bytes,
+            })).collect();
+            let report = serde_json::json!({ "summary": summary.to_json(), "requests": requests });
+            writeln!(writer, "{}", report)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rtt_ms: Option<u64>, outcome: &str) -> Sample {
+        Sample {
+            device: 0,
+            sent_after: Duration::ZERO,
+            rtt: rtt_ms.map(Duration::from_millis),
+            outcome: String::from(outcome),
+            request_bytes: 10,
+        }
+    }
+
+    #[test]
+    fn summary_percentiles_and_throughput() {
+        let mut samples: Vec<Sample> = (1..=100).map(|ms| sample(Some(ms), "Ok")).collect();
+        samples.push(sample(None, "timeout"));
+        let summary = summarize(&samples, 2, Duration::from_secs(10));
+
+        assert_eq!((summary.sent, summary.replies), (101, 100));
+        assert_eq!(summary.throughput, 10.0);
+        assert_eq!((summary.p50_us, summary.p95_us, summary.p99_us, summary.max_us), (50_000, 95_000, 99_000, 100_000));
+        assert_eq!(summary.outcomes["timeout"], 1);
+    }
+
+    #[test]
+    fn csv_export() {
+        let samples = vec![sample(Some(2), "Ok"), sample(None, "timeout")];
+        let summary = summarize(&samples, 1, Duration::from_secs(1));
+        let mut csv = Vec::new();
+        export(&mut csv, ExportFormat::Csv, &summary, &samples).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "device,sent_ms,rtt_us,outcome,request_bytes\n0,0,2000,Ok,10\n0,0,,timeout,10\n");
+
+        let mut json = Vec::new();
+        export(&mut json, ExportFormat::Json, &summary, &samples).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed["summary"]["replies"], 1);
+        assert_eq!(parsed["requests"][1]["rtt_us"], serde_json::Value::Null);
+    }
+}
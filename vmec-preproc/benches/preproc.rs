@@ -0,0 +1,30 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use vmec_preproc::{
+    crush, decode_jpeg, encode_jpeg, letterbox, normalize_to_chw, Normalization, Preprocessor, RgbImage,
+    LETTERBOX_FILL,
+};
+
+// a camera frame at the client's capture resolution, with some detail so it does not compress away
+fn camera_frame() -> RgbImage {
+    RgbImage::from_fn(640, 480, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8]))
+}
+
+fn bench_preproc(c: &mut Criterion) {
+    let frame = camera_frame();
+    let jpeg = encode_jpeg(&frame, 90).unwrap();
+    let (boxed, _) = letterbox(&frame, 640, 640, LETTERBOX_FILL);
+    let preprocessor = Preprocessor::new(640, 640);
+
+    c.bench_function("decode_jpeg 640x480", |b| b.iter(|| decode_jpeg(black_box(&jpeg)).unwrap()));
+    c.bench_function("encode_jpeg 640x480", |b| b.iter(|| encode_jpeg(black_box(&frame), 90).unwrap()));
+    c.bench_function("crush 640x480 to 320x240", |b| b.iter(|| crush(black_box(&jpeg), 320, 240, 50).unwrap()));
+    c.bench_function("letterbox 640x480 to 640x640", |b| b.iter(|| letterbox(black_box(&frame), 640, 640, LETTERBOX_FILL)));
+    c.bench_function("normalize_to_chw 640x640", |b| {
+        b.iter(|| normalize_to_chw(black_box(boxed.as_raw()), 640, 640, &Normalization::IMAGENET))
+    });
+    c.bench_function("preprocessor 640x480 to 640x640", |b| b.iter(|| preprocessor.run(black_box(&jpeg)).unwrap()));
+}
+
+criterion_group!(benches, bench_preproc);
+criterion_main!(benches);
@@ -0,0 +1,278 @@
+//! Image pre-processing shared by the client and server: JPEG decoding and encoding, resizing,
+//! letterboxing, and conversion of RGB pixels to normalized CHW model inputs, as plain buffers
+//! or (with the `tch` feature) tensors.
+//!
+//! JPEG coding uses the pure Rust decoder of the `image` crate, or libturbojpeg with the
+//! `turbojpeg` feature, which is several times faster.
+
+use std::fmt;
+
+use image::imageops::{self, FilterType};
+pub use image::RgbImage;
+
+/// Grey used by YOLO-style models to pad letterboxed images
+pub const LETTERBOX_FILL: [u8; 3] = [114, 114, 114];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreprocError {
+    Decode(String),
+    Encode(String),
+}
+
+impl fmt::Display for PreprocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreprocError::Decode(e) => write!(f, "could not decode JPEG: {}", e),
+            PreprocError::Encode(e) => write!(f, "could not encode JPEG: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "turbojpeg")]
+pub fn decode_jpeg(jpeg: &[u8]) -> Result<RgbImage, PreprocError> {
+    // 1.4ms for 640x480
+    turbojpeg::decompress_image(jpeg).map_err(|e| PreprocError::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "turbojpeg"))]
+pub fn decode_jpeg(jpeg: &[u8]) -> Result<RgbImage, PreprocError> {
+    let image = image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)
+        .map_err(|e| PreprocError::Decode(e.to_string()))?;
+    Ok(image.into_rgb8())
+}
+
+/// Encodes with 4:2:0 chroma subsampling; `quality` is 1 to 100
+#[cfg(feature = "turbojpeg")]
+pub fn encode_jpeg(image: &RgbImage, quality: u8) -> Result<Vec<u8>, PreprocError> {
+    assert!(quality <= 100 && quality > 0);
+    let encoded = turbojpeg::compress_image(image, quality as i32, turbojpeg::Subsamp::Sub2x2)
+        .map_err(|e| PreprocError::Encode(e.to_string()))?;
+    Ok(encoded[..].to_vec())
+}
+
+/// Encodes with 4:2:0 chroma subsampling; `quality` is 1 to 100
+#[cfg(not(feature = "turbojpeg"))]
+pub fn encode_jpeg(image: &RgbImage, quality: u8) -> Result<Vec<u8>, PreprocError> {
+    assert!(quality <= 100 && quality > 0);
+    let mut encoded = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, quality)
+        .encode_image(image)
+        .map_err(|e| PreprocError::Encode(e.to_string()))?;
+    Ok(encoded)
+}
+
+/// Stretches to exactly `width` x `height`, ignoring the aspect ratio
+pub fn resize(image: &RgbImage, width: u32, height: u32) -> RgbImage {
+    // choose CatmullRom (cubic) for better quality and Lanczos3 for best quality
+    imageops::resize(image, width, height, FilterType::Triangle)
+}
+
+/// Decodes, resizes and re-encodes a JPEG at lower quality, to send less over the network
+pub fn crush(jpeg: &[u8], width: u32, height: u32, quality: u8) -> Result<Vec<u8>, PreprocError> {
+    // Too slow; 8ms for 320x240
+    let image = decode_jpeg(jpeg)?;
+    encode_jpeg(&resize(&image, width, height), quality)
+}
+
+/// Where a letterboxed image sits in the model input, to map outputs back to the original image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Letterbox {
+    /// Model input pixels per original pixel
+    pub scale: f32,
+    pub pad_x: u32,
+    pub pad_y: u32,
+}
+
+impl Letterbox {
+    /// Position in the original image of a point in the model input
+    pub fn to_source(&self, x: f32, y: f32) -> (f32, f32) {
+        ((x - self.pad_x as f32) / self.scale, (y - self.pad_y as f32) / self.scale)
+    }
+}
+
+/// Scales to fit in `width` x `height` keeping the aspect ratio, and pads the rest with `fill`
+pub fn letterbox(image: &RgbImage, width: u32, height: u32, fill: [u8; 3]) -> (RgbImage, Letterbox) {
+    let scale = f32::min(width as f32 / image.width() as f32, height as f32 / image.height() as f32);
+    let scaled_width = ((image.width() as f32 * scale).round() as u32).clamp(1, width);
+    let scaled_height = ((image.height() as f32 * scale).round() as u32).clamp(1, height);
+    let pad_x = (width - scaled_width) / 2;
+    let pad_y = (height - scaled_height) / 2;
+
+    let mut canvas = RgbImage::from_pixel(width, height, image::Rgb(fill));
+    let scaled = if (scaled_width, scaled_height) == image.dimensions() {
+        image.clone()
+    } else {
+        resize(image, scaled_width, scaled_height)
+    };
+    imageops::replace(&mut canvas, &scaled, pad_x as i64, pad_y as i64);
+    (canvas, Letterbox { scale, pad_x, pad_y })
+}
+
+/// Per-channel normalization applied after scaling pixel values to 0.0..=1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl Normalization {
+    /// Only scale to 0.0..=1.0
+    pub const UNIT: Normalization = Normalization { mean: [0.0; 3], std: [1.0; 3] };
+    /// Statistics of the ImageNet training set, expected by most torchvision models
+    pub const IMAGENET: Normalization = Normalization { mean: [0.485, 0.456, 0.406], std: [0.229, 0.224, 0.225] };
+}
+
+/// Reorders interleaved RGB pixels, [R, G, B, R, G, B, ...] along width then height, into
+/// channel planes [R..., G..., B...] as torch expects
+pub fn hwc_to_chw(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let plane = (width * height) as usize;
+    assert_eq!(pixels.len(), plane * 3);
+    let mut planes = vec![0; pixels.len()];
+    for (i, pixel) in pixels.chunks_exact(3).enumerate() {
+        for (channel, &value) in pixel.iter().enumerate() {
+            planes[channel * plane + i] = value;
+        }
+    }
+    planes
+}
+
+/// Like `hwc_to_chw`, with values scaled to 0.0..=1.0 and normalized
+pub fn normalize_to_chw(pixels: &[u8], width: u32, height: u32, normalization: &Normalization) -> Vec<f32> {
+    let plane = (width * height) as usize;
+    assert_eq!(pixels.len(), plane * 3);
+    let mut planes = vec![0.0; pixels.len()];
+    for (i, pixel) in pixels.chunks_exact(3).enumerate() {
+        for (channel, &value) in pixel.iter().enumerate() {
+            planes[channel * plane + i] = (value as f32 / 255.0 - normalization.mean[channel]) / normalization.std[channel];
+        }
+    }
+    planes
+}
+
+/// A model input made from a JPEG
+pub struct ModelInput {
+    /// Normalized CHW values, 3 x height x width
+    pub chw: Vec<f32>,
+    pub letterbox: Letterbox,
+}
+
+/// Turns JPEGs into model inputs of one size: decode, letterbox, normalize, and reorder to CHW
+#[derive(Debug, Clone)]
+pub struct Preprocessor {
+    pub width: u32,
+    pub height: u32,
+    pub normalization: Normalization,
+    pub fill: [u8; 3],
+}
+
+impl Preprocessor {
+    pub fn new(width: u32, height: u32) -> Self {
+        Preprocessor {
+            width,
+            height,
+            normalization: Normalization::UNIT,
+            fill: LETTERBOX_FILL,
+        }
+    }
+
+    pub fn run(&self, jpeg: &[u8]) -> Result<ModelInput, PreprocError> {
+        let image = decode_jpeg(jpeg)?;
+        let (boxed, letterbox) = letterbox(&image, self.width, self.height, self.fill);
+        Ok(ModelInput {
+            chw: normalize_to_chw(boxed.as_raw(), self.width, self.height, &self.normalization),
+            letterbox,
+        })
+    }
+}
+
+#[cfg(feature = "tch")]
+pub mod tensor {
+    use super::{normalize_to_chw, Normalization, RgbImage};
+
+    /// Interleaved RGB pixels as a [C, H, W] tensor of u8
+    pub fn raw_pixels_to_tensor(pixels: &[u8], width: u32, height: u32) -> tch::Tensor {
+        let pixel_tensor = tch::Tensor::of_slice(pixels);
+
+        // pixel_tensor is a 1D tensor organized as [R, G, B, R, G, B, ...]
+        // each ['R', 'G', 'B'] is a pixel, and grouped along width, then height
+        // Torch tensor requires [C, H, W] ordering, so we need to reshape and permute
+        pixel_tensor.reshape(&[height as i64, width as i64, 3]).permute(&[2, 0, 1])
+    }
+
+    /// A [C, H, W] tensor of normalized f32 values
+    pub fn normalized_tensor(image: &RgbImage, normalization: &Normalization) -> tch::Tensor {
+        let (width, height) = image.dimensions();
+        let chw = normalize_to_chw(image.as_raw(), width, height, normalization);
+        tch::Tensor::of_slice(&chw).reshape(&[3, height as i64, width as i64])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128]))
+    }
+
+    #[test]
+    fn jpeg_roundtrip() {
+        let image = gradient(64, 48);
+        let jpeg = encode_jpeg(&image, 90).unwrap();
+        let decoded = decode_jpeg(&jpeg).unwrap();
+        assert_eq!(decoded.dimensions(), (64, 48));
+        // lossy, but close
+        let error = image.as_raw().iter().zip(decoded.as_raw()).map(|(&a, &b)| (a as i32 - b as i32).abs()).max().unwrap();
+        assert!(error < 16, "max error {}", error);
+
+        assert!(matches!(decode_jpeg(b"not a jpeg"), Err(PreprocError::Decode(_))));
+    }
+
+    #[test]
+    fn crush_resizes() {
+        let jpeg = encode_jpeg(&gradient(64, 48), 100).unwrap();
+        let crushed = crush(&jpeg, 32, 24, 50).unwrap();
+        assert_eq!(decode_jpeg(&crushed).unwrap().dimensions(), (32, 24));
+        assert!(crushed.len() < jpeg.len());
+    }
+
+    #[test]
+    fn letterbox_pads_the_short_side() {
+        let image = RgbImage::from_pixel(64, 32, image::Rgb([255, 0, 0]));
+        let (boxed, letterbox) = letterbox(&image, 32, 32, LETTERBOX_FILL);
+        assert_eq!(boxed.dimensions(), (32, 32));
+        assert_eq!(letterbox, Letterbox { scale: 0.5, pad_x: 0, pad_y: 8 });
+        assert_eq!(boxed.get_pixel(16, 0).0, LETTERBOX_FILL);
+        assert_eq!(boxed.get_pixel(16, 16).0, [255, 0, 0]);
+        assert_eq!(boxed.get_pixel(16, 31).0, LETTERBOX_FILL);
+        assert_eq!(letterbox.to_source(16.0, 24.0), (32.0, 32.0));
+    }
+
+    #[test]
+    fn chw_ordering_and_normalization() {
+        // 2x1 image: a red pixel, then a white one
+        let pixels = [255, 0, 0, 255, 255, 255];
+        assert_eq!(hwc_to_chw(&pixels, 2, 1), vec![255, 255, 0, 255, 0, 255]);
+        assert_eq!(normalize_to_chw(&pixels, 2, 1, &Normalization::UNIT), vec![1.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        let imagenet = normalize_to_chw(&pixels, 2, 1, &Normalization::IMAGENET);
+        assert!((imagenet[0] - (1.0 - 0.485) / 0.229).abs() < 1e-6);
+        assert!((imagenet[2] - (0.0 - 0.456) / 0.224).abs() < 1e-6);
+    }
+
+    #[test]
+    fn preprocessor_output_size() {
+        let jpeg = encode_jpeg(&gradient(64, 48), 90).unwrap();
+        let input = Preprocessor::new(32, 32).run(&jpeg).unwrap();
+        assert_eq!(input.chw.len(), 3 * 32 * 32);
+        assert_eq!(input.letterbox, Letterbox { scale: 0.5, pad_x: 0, pad_y: 4 });
+        assert!(input.chw.iter().all(|&value| (0.0..=1.0).contains(&value)));
+    }
+
+    #[cfg(feature = "tch")]
+    #[test]
+    fn normalized_tensor_shape() {
+        let tensor = tensor::normalized_tensor(&gradient(64, 48), &Normalization::UNIT);
+        assert_eq!(tensor.size(), &[3, 48, 64]);
+    }
+}
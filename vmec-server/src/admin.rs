@@ -0,0 +1,344 @@
+//! Admin interface for a running server: a ZMQ REP socket that takes one JSON command per request
+//! and answers with JSON, e.g.
+//!
+//! ```text
+//! {"command": "devices"}
+//! {"command": "disconnect", "device_hash": "...", "duration_ms": 60000}
+//! {"command": "throttle", "device_hash": "...", "per_second": 2.0}
+//! {"command": "restore", "device_hash": "..."}
+//! {"command": "log_level", "level": "debug"}
+//! {"command": "models"}
+//! {"command": "reload_model", "engine": "..."}
+//! {"command": "shadow"}
+//! {"command": "experiment"}
+//! {"command": "alert", "device_hash": "...", "message": "..."}
+//! {"command": "hazards", "latitude": 37.5665, "longitude": 126.978, "radius_m": 500}
+//! {"command": "events", "device_hash": "...", "from_ms": 1700000000000, "class": "pothole"}
+//! ```
+//!
+//! It has no authentication of its own, so bind it to a local address only. `vmec-server admin`
+//! sends a command and prints the answer.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::LevelFilter;
+use serde::Deserialize;
+use serde_json::json;
+
+use cornflakes::{AlertKind, VmecAlertFields, VmecLocation};
+
+use crate::alerts::AlertPublisher;
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::engine::EngineRouter;
+use crate::events::{EventQuery, EventStore};
+use crate::experiment::ExperimentMetrics;
+use crate::hazards::{HazardMap, MAX_NEARBY_RADIUS_M};
+use crate::ratelimit::RateLimiter;
+use crate::reload::ModelReloader;
+use crate::shadow::Shadow;
+use crate::stats::DeviceStats;
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// Figures for every device seen so far
+    Devices,
+    /// Refuse the device's requests, for `duration_ms` or until it is restored
+    Disconnect {
+        device_hash: String,
+        duration_ms: Option<u64>,
+    },
+    /// Rate limit the device's frames in place of its configured limit
+    Throttle {
+        device_hash: String,
+        per_second: f64,
+        /// One second's worth of frames when not given
+        burst: Option<f64>,
+    },
+    /// Lift a disconnect or throttle
+    Restore {
+        device_hash: String,
+    },
+    LogLevel {
+        level: String,
+    },
+    /// The engines, with the version of the model each has loaded
+    Models,
+    /// Load the engine's model file again, and swap it in once it is warmed up
+    ReloadModel {
+        engine: String,
+    },
+    /// How often the candidate engines agree with the primary ones on mirrored images
+    Shadow,
+    /// Figures for each arm of the experiment
+    Experiment,
+    /// Push a message to the device on the alert channel
+    Alert {
+        device_hash: String,
+        message: String,
+    },
+    /// Hazards on the map around a place
+    Hazards {
+        latitude: f64,
+        longitude: f64,
+        radius_m: f64,
+    },
+    /// Stored requests, detections and alerts matching the filters; see `events::EventQuery`
+    Events(EventQuery),
+}
+
+/// The parts of the server that admin commands act on
+pub struct Controls<'a> {
+    pub devices: &'a mut DeviceStats,
+    pub rate_limiter: &'a mut Option<RateLimiter>,
+    pub router: &'a EngineRouter,
+    pub reloader: &'a ModelReloader,
+    pub shadow: Option<&'a Shadow>,
+    pub experiment: &'a ExperimentMetrics,
+    pub alerts: Option<&'a AlertPublisher>,
+    pub hazard_map: Option<&'a HazardMap>,
+    pub events: Option<&'a EventStore>,
+}
+
+pub struct Admin {
+    socket: zmq::Socket,
+    // disconnected devices, keyed by trimmed device hash, until when if not for good
+    disconnected: HashMap<String, Option<Instant>>,
+}
+
+impl Admin {
+    pub fn bind(context: &zmq::Context, address: &str) -> Self {
+        let socket = context.socket(zmq::REP).unwrap();
+        socket.bind(address).unwrap();
+        Admin {
+            socket,
+            disconnected: HashMap::new(),
+        }
+    }
+
+    /// Readable when a command has arrived
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    pub fn is_disconnected(&mut self, device_hash: &str, now: Instant) -> bool {
+        let device_hash = device_hash.trim();
+        match self.disconnected.get(device_hash) {
+            Some(Some(until)) if *until <= now => {
+                self.disconnected.remove(device_hash);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Answers the commands that have arrived since the last call
+    pub fn serve(&mut self, mut controls: Controls) {
+        while let Ok(request) = self.socket.recv_bytes(zmq::DONTWAIT) {
+            let answer = match serde_json::from_slice::<AdminCommand>(&request) {
+                Ok(command) => self.run(command, &mut controls, Instant::now()),
+                Err(e) => Err(format!("invalid command: {}", e)),
+            };
+            let answer = match answer {
+                Ok(serde_json::Value::Null) => json!({ "ok": true }),
+                Ok(mut answer) => {
+                    answer["ok"] = json!(true);
+                    answer
+                }
+                Err(error) => json!({ "ok": false, "error": error }),
+            };
+            self.socket.send(answer.to_string().as_bytes(), 0).unwrap();
+        }
+    }
+
+    fn run(&mut self, command: AdminCommand, controls: &mut Controls, now: Instant) -> Result<serde_json::Value, String> {
+        log::info!("Admin command: {:?}", command);
+        let Controls { devices, rate_limiter, router, reloader, shadow, experiment, alerts, hazard_map, events } = controls;
+        match command {
+            AdminCommand::Devices => {
+                let reports = devices.report().into_iter().map(|report| {
+                    let mut report = serde_json::to_value(&report).unwrap();
+                    let device_hash = String::from(report["device_hash"].as_str().unwrap());
+                    report["disconnected"] = json!(self.is_disconnected(&device_hash, now));
+                    let throttled = rate_limiter.as_ref().and_then(|limiter| limiter.throttled(&device_hash));
+                    report["throttled_per_second"] = json!(throttled.map(|limit| limit.per_second));
+                    report
+                }).collect::<Vec<_>>();
+                Ok(json!({ "devices": reports }))
+            }
+            AdminCommand::Disconnect { device_hash, duration_ms } => {
+                let until = duration_ms.map(|duration_ms| now + Duration::from_millis(duration_ms));
+                self.disconnected.insert(String::from(device_hash.trim()), until);
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::Throttle { device_hash, per_second, burst } => {
+                if per_second <= 0.0 || burst.is_some_and(|burst| burst < 1.0) {
+                    return Err(String::from("per_second must be above 0, and burst at least 1"));
+                }
+                let limit = RateLimit { per_second, burst: burst.unwrap_or(per_second.max(1.0)) };
+                rate_limiter
+                    .get_or_insert_with(|| RateLimiter::new(&RateLimitConfig::default(), &HashMap::new()))
+                    .throttle(&device_hash, Some(limit));
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::Restore { device_hash } => {
+                self.disconnected.remove(device_hash.trim());
+                if let Some(limiter) = rate_limiter {
+                    limiter.throttle(&device_hash, None);
+                }
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::LogLevel { level } => {
+                let level: LevelFilter = level.parse().map_err(|_| format!("unknown log level {}", level))?;
+                log::set_max_level(level);
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::Models => {
+                let engines = router.engines().iter().map(|engine| json!({
+                    "engine": engine.name,
+                    "model": engine.model.display().to_string(),
+                    "version": engine.version,
+                })).collect::<Vec<_>>();
+                Ok(json!({ "engines": engines }))
+            }
+            AdminCommand::ReloadModel { engine } => {
+                if !router.engine_names().any(|name| name == engine) {
+                    return Err(format!("no engine named {}", engine));
+                }
+                // answered before the model is loaded; `models` shows when the new version is in
+                reloader.request(&engine);
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::Shadow => {
+                let shadow = shadow.ok_or("shadow mirroring is not configured")?;
+                Ok(json!({ "shadow": shadow.metrics() }))
+            }
+            AdminCommand::Experiment => {
+                let running = router.experiment().ok_or("no experiment is configured")?;
+                Ok(json!({ "experiment": running.name, "arms": experiment.report(running) }))
+            }
+            AdminCommand::Alert { device_hash, message } => {
+                let alerts = alerts.ok_or("alerts are not enabled; see --alert-port")?;
+                let timestamp_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+                let alert = VmecAlertFields { timestamp_ms, device_hash, kind: AlertKind::Operator, message, detail: String::new() };
+                if let Some(events) = events {
+                    events.record_alert(&alert);
+                }
+                alerts.publish(alert);
+                Ok(serde_json::Value::Null)
+            }
+            AdminCommand::Hazards { latitude, longitude, radius_m } => {
+                let hazard_map = hazard_map.ok_or("no hazard map is configured")?;
+                let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+                let hazards = hazard_map.near(VmecLocation { latitude, longitude }, radius_m, now_ms).into_iter().map(|hazard| json!({
+                    "kind": hazard.kind,
+                    "latitude": hazard.location.latitude,
+                    "longitude": hazard.location.longitude,
+                    "score": hazard.score,
+                    "reporters": hazard.reporters,
+                    "last_detected_ms": hazard.last_detected_ms,
+                    "distance_m": hazard.distance_m,
+                })).collect::<Vec<_>>();
+                Ok(json!({ "mapped": hazard_map.len(), "max_radius_m": MAX_NEARBY_RADIUS_M, "hazards": hazards }))
+            }
+            AdminCommand::Events(query) => {
+                let events = events.ok_or("no event store is configured; see --event-db")?;
+                Ok(json!({ "events": events.query(&query)? }))
+            }
+        }
+    }
+}
+
+/// Sends one command to a server's admin socket, and returns its answer
+pub fn send_command(address: &str, command: &str, timeout_ms: i32) -> Result<String, String> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REQ).map_err(|e| e.to_string())?;
+    socket.set_rcvtimeo(timeout_ms).map_err(|e| e.to_string())?;
+    socket.set_linger(0).map_err(|e| e.to_string())?;
+    socket.connect(address).map_err(|e| e.to_string())?;
+    socket.send(command.as_bytes(), 0).map_err(|e| e.to_string())?;
+    let answer = socket.recv_bytes(0).map_err(|_| format!("no answer from {}", address))?;
+    Ok(String::from_utf8_lossy(&answer).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cornflakes::ResponseStatus;
+
+    use crate::config::ServerConfig;
+
+    fn router() -> EngineRouter {
+        EngineRouter::new(&ServerConfig::default(), None).unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let command: AdminCommand = serde_json::from_str(r#"{"command": "throttle", "device_hash": "bike", "per_second": 2.0}"#).unwrap();
+        assert_eq!(command, AdminCommand::Throttle { device_hash: String::from("bike"), per_second: 2.0, burst: None });
+        assert_eq!(serde_json::from_str::<AdminCommand>(r#"{"command": "devices"}"#).unwrap(), AdminCommand::Devices);
+        assert!(serde_json::from_str::<AdminCommand>(r#"{"command": "reboot"}"#).is_err());
+        let events: AdminCommand = serde_json::from_str(r#"{"command": "events", "device_hash": "bike", "limit": 10}"#).unwrap();
+        assert_eq!(events, AdminCommand::Events(EventQuery { device_hash: Some(String::from("bike")), limit: Some(10), ..EventQuery::default() }));
+        assert!(serde_json::from_str::<AdminCommand>(r#"{"command": "events", "device": "bike"}"#).is_err());
+    }
+
+    #[test]
+    fn disconnects_and_throttles_devices() {
+        let context = zmq::Context::new();
+        let mut admin = Admin::bind(&context, "inproc://admin-test");
+        let mut devices = DeviceStats::new();
+        devices.record_frame("bike", ResponseStatus::Ok, Duration::from_millis(5), 100);
+        let mut rate_limiter = None;
+        let router = router();
+        let reloader = ModelReloader::spawn(&context, router.engines(), None);
+        let mut controls = Controls { devices: &mut devices, rate_limiter: &mut rate_limiter, router: &router, reloader: &reloader, shadow: None, experiment: &ExperimentMetrics::new(), alerts: None, hazard_map: None, events: None };
+        let now = Instant::now();
+
+        let disconnect = AdminCommand::Disconnect { device_hash: String::from("bike\n"), duration_ms: Some(1000) };
+        admin.run(disconnect, &mut controls, now).unwrap();
+        assert!(admin.is_disconnected("bike", now));
+        assert!(!admin.is_disconnected("bike", now + Duration::from_millis(1000)));
+
+        let throttle = AdminCommand::Throttle { device_hash: String::from("bike"), per_second: 1.0, burst: None };
+        admin.run(throttle, &mut controls, now).unwrap();
+        assert_eq!(controls.rate_limiter.as_mut().unwrap().check("bike", now), Ok(()));
+        assert_eq!(controls.rate_limiter.as_mut().unwrap().check("bike", now), Err(1000));
+
+        let listed = admin.run(AdminCommand::Devices, &mut controls, now).unwrap();
+        assert_eq!(listed["devices"][0]["device_hash"], "bike");
+        assert_eq!(listed["devices"][0]["throttled_per_second"], 1.0);
+
+        admin.run(AdminCommand::Restore { device_hash: String::from("bike") }, &mut controls, now).unwrap();
+        assert_eq!(controls.rate_limiter.as_mut().unwrap().check("bike", now), Ok(()));
+        assert!(admin.run(AdminCommand::LogLevel { level: String::from("loud") }, &mut controls, now).is_err());
+
+        let models = admin.run(AdminCommand::Models, &mut controls, now).unwrap();
+        assert_eq!(models["engines"][0]["version"], 1);
+        assert!(admin.run(AdminCommand::ReloadModel { engine: String::from("missing") }, &mut controls, now).is_err());
+        assert!(admin.run(AdminCommand::Shadow, &mut controls, now).is_err());
+        assert!(admin.run(AdminCommand::Experiment, &mut controls, now).is_err());
+        let alert = AdminCommand::Alert { device_hash: String::from("bike"), message: String::from("hello") };
+        assert!(admin.run(alert, &mut controls, now).is_err());
+        let hazards = AdminCommand::Hazards { latitude: 37.5, longitude: 127.0, radius_m: 100.0 };
+        assert!(admin.run(hazards, &mut controls, now).is_err());
+        assert!(admin.run(AdminCommand::Events(EventQuery::default()), &mut controls, now).is_err());
+    }
+
+    #[test]
+    fn answers_over_the_socket() {
+        let context = zmq::Context::new();
+        let mut admin = Admin::bind(&context, "tcp://127.0.0.1:*");
+        let address = admin.socket().get_last_endpoint().unwrap().unwrap();
+        let client = std::thread::spawn(move || send_command(&address, r#"{"command": "devices"}"#, 1000));
+
+        admin.socket().poll(zmq::POLLIN, 1000).unwrap();
+        let router = router();
+        let reloader = ModelReloader::spawn(&context, router.engines(), None);
+        admin.serve(Controls { devices: &mut DeviceStats::new(), rate_limiter: &mut None, router: &router, reloader: &reloader, shadow: None, experiment: &ExperimentMetrics::new(), alerts: None, hazard_map: None, events: None });
+        let answer: serde_json::Value = serde_json::from_str(&client.join().unwrap().unwrap()).unwrap();
+        assert_eq!(answer, json!({ "ok": true, "devices": [] }));
+    }
+}
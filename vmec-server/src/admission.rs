@@ -0,0 +1,78 @@
+//! Admission control: decides whether a newly arrived frame is queued or bounced straight back.
+//!
+//! Below `soft_limit` frames in flight everything is accepted as normal. Between the soft and hard
+//! limit frames are still accepted, but the reply asks the client to reduce its rate. At
+//! `hard_limit` new frames are rejected with a "busy, retry after N ms" reply.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    AcceptReduceRate { retry_after_ms: u32 },
+    Reject { retry_after_ms: u32 },
+}
+
+pub struct AdmissionController {
+    soft_limit: usize,
+    hard_limit: usize,
+}
+
+impl AdmissionController {
+    pub fn new(soft_limit: usize, hard_limit: usize) -> Result<Self, String> {
+        if soft_limit > hard_limit {
+            return Err(format!("soft in-flight limit {} exceeds the hard limit {}", soft_limit, hard_limit));
+        }
+        Ok(AdmissionController { soft_limit, hard_limit })
+    }
+
+    /// `in_flight` is the number of frames already accepted but not yet answered
+    pub fn decide(&self, in_flight: usize, mean_processing_time: Duration) -> Admission {
+        if in_flight >= self.hard_limit {
+            Admission::Reject { retry_after_ms: Self::drain_time_ms(in_flight, mean_processing_time) }
+        } else if in_flight >= self.soft_limit {
+            Admission::AcceptReduceRate { retry_after_ms: Self::drain_time_ms(in_flight - self.soft_limit + 1, mean_processing_time) }
+        } else {
+            Admission::Accept
+        }
+    }
+
+    /// Rough time for the server to work through `queued` frames
+    fn drain_time_ms(queued: usize, mean_processing_time: Duration) -> u32 {
+        // never suggest less than 1 ms, or clients would retry immediately
+        let per_frame_ms = mean_processing_time.as_millis().max(1);
+        (per_frame_ms * queued as u128).min(u32::MAX as u128) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_below_soft_limit() {
+        let admission = AdmissionController::new(2, 4).unwrap();
+        assert_eq!(admission.decide(0, Duration::from_millis(10)), Admission::Accept);
+        assert_eq!(admission.decide(1, Duration::from_millis(10)), Admission::Accept);
+    }
+
+    #[test]
+    fn asks_to_reduce_rate_between_limits() {
+        let admission = AdmissionController::new(2, 4).unwrap();
+        assert_eq!(admission.decide(2, Duration::from_millis(10)), Admission::AcceptReduceRate { retry_after_ms: 10 });
+        assert_eq!(admission.decide(3, Duration::from_millis(10)), Admission::AcceptReduceRate { retry_after_ms: 20 });
+    }
+
+    #[test]
+    fn rejects_at_hard_limit() {
+        let admission = AdmissionController::new(2, 4).unwrap();
+        assert_eq!(admission.decide(4, Duration::from_millis(10)), Admission::Reject { retry_after_ms: 40 });
+        assert_eq!(admission.decide(4, Duration::ZERO), Admission::Reject { retry_after_ms: 4 });
+    }
+
+    #[test]
+    fn rejects_soft_limit_above_hard_limit() {
+        assert!(AdmissionController::new(4, 4).is_ok());
+        assert!(AdmissionController::new(5, 4).is_err());
+    }
+}
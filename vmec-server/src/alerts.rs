@@ -0,0 +1,164 @@
+//! Alerts pushed to devices on a PUB socket, outside the request/reply cycle, so that a rider hears
+//! of e.g. a fast-approaching vehicle as soon as the server does. Each device subscribes to its own
+//! topic; see `vmec_response_transport::alert_topic`.
+//!
+//! Engines raise alerts by listing them under "alerts" in a camera's output, each with a "kind"
+//! ("approaching_vehicle" or "hazard_ahead") and a "message"; the rest of the entry is passed on as
+//! the alert's detail. Alerts to devices that seal their requests are sealed too.
+
+use std::collections::HashMap;
+
+use log::{debug, warn};
+
+use cornflakes::{vmec_response_transport, AlertKind, VmecAlertFields};
+
+use crate::transport::Seal;
+
+pub struct AlertPublisher {
+    socket: zmq::Socket,
+    require_encryption: bool,
+    // how to seal alerts to each device that sealed its last request, keyed by trimmed device hash
+    seals: HashMap<String, Seal>,
+}
+
+impl AlertPublisher {
+    pub fn bind(context: &zmq::Context, address: &str, require_encryption: bool) -> Self {
+        let socket = context.socket(zmq::PUB).unwrap();
+        socket.bind(address).unwrap();
+        AlertPublisher {
+            socket,
+            require_encryption,
+            seals: HashMap::new(),
+        }
+    }
+
+    /// Notes whether the device sealed its latest request, and so whether to seal alerts to it
+    pub fn record_seal(&mut self, device_hash: &str, seal: Option<&Seal>) {
+        match seal {
+            Some(seal) => {
+                self.seals.insert(String::from(device_hash.trim()), seal.clone());
+            }
+            None => {
+                self.seals.remove(device_hash.trim());
+            }
+        }
+    }
+
+    /// Sends the alert to its device, if it is subscribed; alerts are not kept for devices that
+    /// subscribe later
+    pub fn publish(&self, alert: VmecAlertFields) {
+        let device_hash = String::from(alert.device_hash.trim());
+        let encoded = vmec_response_transport::encode_alert(alert).unwrap();
+        let payload = match self.seals.get(&device_hash) {
+            Some(seal) => seal.seal(&encoded),
+            None if self.require_encryption => {
+                warn!("Not alerting {}: it has not sent a sealed request yet", device_hash);
+                return;
+            }
+            None => encoded,
+        };
+        debug!("Alerting {}", device_hash);
+        let message = [vmec_response_transport::alert_topic(&device_hash), payload].concat();
+        self.socket.send(message, 0).unwrap();
+    }
+}
+
+fn alert_kind(kind: &str) -> Option<AlertKind> {
+    match kind {
+        "approaching_vehicle" => Some(AlertKind::ApproachingVehicle),
+        "hazard_ahead" => Some(AlertKind::HazardAhead),
+        _ => None,
+    }
+}
+
+/// The name engines give the kind by, or "operator" for messages from the admin interface
+pub fn kind_name(kind: AlertKind) -> &'static str {
+    match kind {
+        AlertKind::ApproachingVehicle => "approaching_vehicle",
+        AlertKind::HazardAhead => "hazard_ahead",
+        AlertKind::Operator => "operator",
+    }
+}
+
+/// Alerts the engines raised in a reply's neural output
+pub fn alerts_in(device_hash: &str, neural_output: &str, now_ms: u64) -> Vec<VmecAlertFields> {
+    let Ok(serde_json::Value::Object(cameras)) = serde_json::from_str(neural_output) else {
+        return Vec::new();
+    };
+    let mut alerts = Vec::new();
+    for (camera, output) in cameras {
+        for raised in output["alerts"].as_array().into_iter().flatten() {
+            let Some(kind) = raised["kind"].as_str().and_then(alert_kind) else {
+                warn!("Ignoring {} camera alert of unknown kind: {}", camera, raised);
+                continue;
+            };
+            let mut detail = raised.clone();
+            if let Some(detail) = detail.as_object_mut() {
+                detail.remove("kind");
+                detail.remove("message");
+                detail.insert(String::from("camera"), serde_json::json!(camera));
+            }
+            alerts.push(VmecAlertFields {
+                timestamp_ms: now_ms,
+                device_hash: String::from(device_hash),
+                kind,
+                message: String::from(raised["message"].as_str().unwrap_or_default()),
+                detail: detail.to_string(),
+            });
+        }
+    }
+    alerts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn finds_alerts_in_outputs() {
+        let output = json!({
+            "front": { "engine": "hazards", "alerts": [{ "kind": "hazard_ahead", "message": "pothole", "distance_m": 12 }] },
+            "rear": { "engine": "vehicles", "alerts": [{ "kind": "alien_invasion" }] },
+        });
+        let alerts = alerts_in("bike", &output.to_string(), 100);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].kind, alerts[0].message.as_str()), (AlertKind::HazardAhead, "pothole"));
+        let detail: serde_json::Value = serde_json::from_str(&alerts[0].detail).unwrap();
+        assert_eq!(detail, json!({ "distance_m": 12, "camera": "front" }));
+
+        assert!(alerts_in("bike", &json!({ "front": { "engine": "hazards" } }).to_string(), 100).is_empty());
+        assert!(alerts_in("bike", "not json", 100).is_empty());
+    }
+
+    #[test]
+    fn publishes_on_device_topics() {
+        let context = zmq::Context::new();
+        let publisher = AlertPublisher::bind(&context, "inproc://alerts-test", false);
+        let subscriber = context.socket(zmq::SUB).unwrap();
+        subscriber.connect("inproc://alerts-test").unwrap();
+        let topic = vmec_response_transport::alert_topic("bike");
+        subscriber.set_subscribe(&topic).unwrap();
+
+        let alert = |device_hash: &str| VmecAlertFields {
+            timestamp_ms: 100,
+            device_hash: String::from(device_hash),
+            kind: AlertKind::Operator,
+            message: String::from(device_hash),
+            detail: String::new(),
+        };
+        // subscriptions reach the publisher asynchronously
+        let mut received = None;
+        for _ in 0..100 {
+            publisher.publish(alert("bike-2"));
+            publisher.publish(alert("bike\n"));
+            if subscriber.poll(zmq::POLLIN, 10).unwrap() > 0 {
+                received = Some(subscriber.recv_bytes(0).unwrap());
+                break;
+            }
+        }
+        let received = received.unwrap();
+        let decoded = vmec_response_transport::decode_alert(&received[topic.len()..]).unwrap();
+        assert_eq!(decoded.message, "bike\n");
+    }
+}
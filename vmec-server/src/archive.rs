@@ -0,0 +1,343 @@
+//! On-disk archive of received frames, for collecting training data from real edge traffic.
+//!
+//! Layout: `<root>/<device>/<YYYY-MM-DD>/<received_ms>_<request_hash>_{front.jpg,rear.jpg,meta.json}`,
+//! dated by the server clock; the client's own timestamp is only kept in meta.json.
+//!
+//! Only a sampled fraction of frames is stored. Day directories older than the retention period
+//! are removed, and when the archive grows past its size cap the oldest files are deleted first.
+//! Writing happens on a background thread so archival never delays a reply.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+
+use log::{error, warn};
+
+use cornflakes::{ResponseStatus, VmecRequestFields};
+
+/// Records waiting to be written; beyond this, new records are dropped rather than queued
+const ARCHIVE_BACKLOG: usize = 64;
+
+/// Once over the size cap, delete down to this fraction of it, so that not every frame triggers a cleanup
+const CLEANUP_TARGET_FRACTION: f64 = 0.9;
+
+pub struct ArchiveConfig {
+    pub root: PathBuf,
+    pub retention_days: u64,
+    pub max_bytes: u64,
+}
+
+pub struct ArchiveRecord {
+    pub request: VmecRequestFields,
+    /// When the server received the frame, by its own clock; the request's timestamp is the
+    /// client's, which it could set to anything
+    pub received_ms: u64,
+    pub status: ResponseStatus,
+    pub response_hash: String,
+    pub neural_output: String,
+    pub processing_us: u64,
+}
+
+/// Deterministically picks `rate` of all frames, spread evenly
+pub struct ArchiveSampler {
+    rate: f64,
+    seen: u64,
+}
+
+impl ArchiveSampler {
+    pub fn new(rate: f64) -> Self {
+        ArchiveSampler { rate: rate.clamp(0.0, 1.0), seen: 0 }
+    }
+
+    pub fn sample(&mut self) -> bool {
+        let before = (self.seen as f64 * self.rate).floor();
+        self.seen += 1;
+        let after = (self.seen as f64 * self.rate).floor();
+        after > before
+    }
+}
+
+pub struct Archive {
+    config: ArchiveConfig,
+    usage_bytes: u64,
+    // day of the most recent retention sweep, as YYYY-MM-DD
+    last_sweep_day: Option<String>,
+}
+
+impl Archive {
+    pub fn open(config: ArchiveConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.root)?;
+        let usage_bytes = archived_files(&config.root)?.iter().map(|(_, size)| size).sum();
+        Ok(Archive {
+            config,
+            usage_bytes,
+            last_sweep_day: None,
+        })
+    }
+
+    pub fn store(&mut self, record: &ArchiveRecord) -> io::Result<()> {
+        let day = date_from_ms(record.received_ms);
+        if self.last_sweep_day.as_deref() != Some(day.as_str()) {
+            self.remove_expired(record.received_ms)?;
+            self.last_sweep_day = Some(day.clone());
+        }
+
+        let dir = self.config.root.join(sanitize(&record.request.device_hash)).join(&day);
+        fs::create_dir_all(&dir)?;
+        let stem = format!("{}_{}", record.received_ms, sanitize(&record.request.request_hash));
+
+        let metadata = serde_json::json!({
+            "timestamp_ms": record.request.timestamp_ms,
+            "received_ms": record.received_ms,
+            "device_hash": record.request.device_hash.trim(),
+            "request_hash": record.request.request_hash,
+            "front_bytes": record.request.image_front.len(),
+            "rear_bytes": record.request.image_rear.len(),
+            "response": {
+                "status": format!("{:?}", record.status),
+                "response_hash": record.response_hash,
+                "neural_output": record.neural_output,
+                "processing_us": record.processing_us,
+            },
+        });
+
+        self.write(&dir.join(format!("{}_front.jpg", stem)), &record.request.image_front)?;
+        self.write(&dir.join(format!("{}_rear.jpg", stem)), &record.request.image_rear)?;
+        self.write(&dir.join(format!("{}_meta.json", stem)), metadata.to_string().as_bytes())?;
+
+        if self.usage_bytes > self.config.max_bytes {
+            self.shrink_to((self.config.max_bytes as f64 * CLEANUP_TARGET_FRACTION) as u64)?;
+        }
+        Ok(())
+    }
+
+    pub fn usage_bytes(&self) -> u64 {
+        self.usage_bytes
+    }
+
+    fn write(&mut self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        fs::write(path, bytes)?;
+        self.usage_bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Removes day directories that fall outside the retention period
+    fn remove_expired(&mut self, now_ms: u64) -> io::Result<()> {
+        let retention_ms = self.config.retention_days * 24 * 60 * 60 * 1000;
+        let cutoff = date_from_ms(now_ms.saturating_sub(retention_ms));
+        for device_dir in fs::read_dir(&self.config.root)? {
+            let device_dir = device_dir?.path();
+            if !device_dir.is_dir() {
+                continue;
+            }
+            for day_dir in fs::read_dir(&device_dir)? {
+                let day_dir = day_dir?.path();
+                let expired = day_dir.file_name().and_then(|name| name.to_str()).is_some_and(|name| name < cutoff.as_str());
+                if day_dir.is_dir() && expired {
+                    let freed: u64 = archived_files(&day_dir)?.iter().map(|(_, size)| size).sum();
+                    fs::remove_dir_all(&day_dir)?;
+                    self.usage_bytes = self.usage_bytes.saturating_sub(freed);
+                }
+            }
+            // only succeeds once the device has nothing left
+            let _ = fs::remove_dir(&device_dir);
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest archived files until usage is at most `target_bytes`
+    fn shrink_to(&mut self, target_bytes: u64) -> io::Result<()> {
+        let mut files = archived_files(&self.config.root)?;
+        // day directory first, then the timestamp the file name starts with
+        files.sort_by(|(a, _), (b, _)| (a.parent().and_then(|p| p.file_name()), a.file_name()).cmp(&(b.parent().and_then(|p| p.file_name()), b.file_name())));
+        for (path, size) in files {
+            if self.usage_bytes <= target_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            self.usage_bytes = self.usage_bytes.saturating_sub(size);
+            if let Some(day_dir) = path.parent() {
+                let _ = fs::remove_dir(day_dir);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts the background writer. Records sent while it is backlogged are dropped.
+pub fn spawn_archiver(mut archive: Archive) -> SyncSender<ArchiveRecord> {
+    let (sender, receiver) = sync_channel::<ArchiveRecord>(ARCHIVE_BACKLOG);
+    thread::spawn(move || {
+        for record in receiver {
+            if let Err(e) = archive.store(&record) {
+                error!("Failed to archive frame {}: {}", record.request.request_hash, e);
+            }
+        }
+    });
+    sender
+}
+
+pub fn submit(archiver: &SyncSender<ArchiveRecord>, record: ArchiveRecord) {
+    match archiver.try_send(record) {
+        Ok(()) => {}
+        Err(TrySendError::Full(record)) => warn!("Archive backlogged; dropping frame {}", record.request.request_hash),
+        Err(TrySendError::Disconnected(_)) => error!("Archive writer has stopped"),
+    }
+}
+
+/// All files below `dir`, with their sizes
+fn archived_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(archived_files(&path)?);
+        } else {
+            files.push((path, entry.metadata()?.len()));
+        }
+    }
+    Ok(files)
+}
+
+/// Device and request hashes are client-supplied, so keep them to characters that are safe in a path
+fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if cleaned.is_empty() {
+        String::from("unknown")
+    } else {
+        cleaned
+    }
+}
+
+/// UTC calendar date of a unix timestamp, as YYYY-MM-DD
+fn date_from_ms(timestamp_ms: u64) -> String {
+    // days-to-civil conversion from http://howardhinnant.github.io/date_algorithms.html
+    let days = (timestamp_ms / 1000 / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vmec-archive-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(device_hash: &str, timestamp_ms: u64, image_bytes: usize) -> ArchiveRecord {
+        ArchiveRecord {
+            received_ms: timestamp_ms,
+            request: VmecRequestFields {
+                timestamp_ms,
+                device_hash: String::from(device_hash),
+                request_hash: format!("req{}", timestamp_ms),
+                image_front: vec![0; image_bytes],
+                image_rear: vec![0; image_bytes],
+                ..Default::default()
+            },
+            status: ResponseStatus::Ok,
+            response_hash: String::from("response"),
+            neural_output: String::from("output"),
+            processing_us: 10,
+        }
+    }
+
+    #[test]
+    fn dates_from_timestamps() {
+        assert_eq!(date_from_ms(0), "1970-01-01");
+        assert_eq!(date_from_ms(951_782_400_000), "2000-02-29");
+        assert_eq!(date_from_ms(1_669_852_800_000 - 1), "2022-11-30");
+        assert_eq!(date_from_ms(1_669_852_800_000), "2022-12-01");
+    }
+
+    #[test]
+    fn sampler_spreads_evenly() {
+        let mut sampler = ArchiveSampler::new(0.25);
+        let picked = (0..100).filter(|_| sampler.sample()).count();
+        assert_eq!(picked, 25);
+
+        let mut everything = ArchiveSampler::new(1.0);
+        assert!((0..10).all(|_| everything.sample()));
+        let mut nothing = ArchiveSampler::new(0.0);
+        assert!(!(0..10).any(|_| nothing.sample()));
+    }
+
+    #[test]
+    fn stores_by_device_and_date() {
+        let root = scratch_dir("layout");
+        let mut archive = Archive::open(ArchiveConfig { root: root.clone(), retention_days: 30, max_bytes: 1 << 20 }).unwrap();
+        archive.store(&record("bike/1\n", 1_669_852_800_000, 10)).unwrap();
+
+        let day_dir = root.join("bike_1").join("2022-12-01");
+        assert!(day_dir.join("1669852800000_req1669852800000_front.jpg").exists());
+        assert!(day_dir.join("1669852800000_req1669852800000_rear.jpg").exists());
+        let metadata = fs::read_to_string(day_dir.join("1669852800000_req1669852800000_meta.json")).unwrap();
+        assert!(metadata.contains("\"neural_output\":\"output\""));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn removes_expired_days() {
+        let root = scratch_dir("retention");
+        let mut archive = Archive::open(ArchiveConfig { root: root.clone(), retention_days: 2, max_bytes: 1 << 20 }).unwrap();
+        let start = 1_669_852_800_000;
+        archive.store(&record("bike", start, 10)).unwrap();
+        archive.store(&record("bike", start + 5 * DAY_MS, 10)).unwrap();
+
+        assert!(!root.join("bike").join("2022-12-01").exists());
+        assert!(root.join("bike").join("2022-12-06").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn files_by_the_server_clock() {
+        let root = scratch_dir("clock");
+        let mut archive = Archive::open(ArchiveConfig { root: root.clone(), retention_days: 2, max_bytes: 1 << 20 }).unwrap();
+        let start = 1_669_852_800_000;
+        archive.store(&record("bike", start, 10)).unwrap();
+        // a client clock far ahead must not make the rest of the archive look expired
+        let mut skewed = record("intruder", start + 1, 10);
+        skewed.request.timestamp_ms = start + 1000 * DAY_MS;
+        archive.store(&skewed).unwrap();
+
+        assert!(root.join("bike").join("2022-12-01").exists());
+        let metadata = fs::read_to_string(root.join("intruder").join("2022-12-01").join(format!("{}_req{}_meta.json", start + 1, start + 1))).unwrap();
+        assert!(metadata.contains(&format!("\"timestamp_ms\":{}", start + 1000 * DAY_MS)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deletes_oldest_files_over_cap() {
+        let root = scratch_dir("cap");
+        let mut archive = Archive::open(ArchiveConfig { root: root.clone(), retention_days: 30, max_bytes: 2500 }).unwrap();
+        let start = 1_669_852_800_000;
+        for i in 0..3 {
+            archive.store(&record("bike", start + i, 500)).unwrap();
+        }
+
+        assert!(archive.usage_bytes() <= 2500);
+        let day_dir = root.join("bike").join("2022-12-01");
+        assert!(!day_dir.join(format!("{}_req{}_front.jpg", start, start)).exists());
+        assert!(day_dir.join(format!("{}_req{}_front.jpg", start + 2, start + 2)).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Checks that requests come from allowed devices, using the pre-shared keys in the server config.
+
+use std::collections::{HashMap, HashSet};
+
+use cornflakes::auth::{self, DeviceKey};
+use cornflakes::{ErrorCode, VmecHeartbeatFields, VmecRequestFields};
+
+use crate::config::AuthConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    UnknownDevice,
+    BadSignature,
+    Revoked,
+}
+
+impl AuthFailure {
+    pub fn error_code(self) -> ErrorCode {
+        match self {
+            AuthFailure::UnknownDevice | AuthFailure::BadSignature => ErrorCode::Unauthenticated,
+            AuthFailure::Revoked => ErrorCode::Revoked,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            AuthFailure::UnknownDevice => "device is not on the allowlist",
+            AuthFailure::BadSignature => "request signature is missing or invalid",
+            AuthFailure::Revoked => "device credentials have been revoked",
+        }
+    }
+}
+
+pub struct Authenticator {
+    required: bool,
+    // keyed by trimmed device hash, as clients send /etc/machine-id with its trailing newline
+    keys: HashMap<String, DeviceKey>,
+    revoked: HashSet<String>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for device in &config.devices {
+            let key = DeviceKey::from_hex(&device.key)
+                .map_err(|e| format!("key for device {}: {}", device.device_hash, e))?;
+            keys.insert(String::from(device.device_hash.trim()), key);
+        }
+        Ok(Authenticator {
+            required: config.required,
+            keys,
+            revoked: config.revoked.iter().map(|device_hash| String::from(device_hash.trim())).collect(),
+        })
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    pub fn check_frame(&self, request: &VmecRequestFields) -> Result<(), AuthFailure> {
+        self.check(&request.device_hash, |key| auth::verify_frame(request, key))
+    }
+
+    pub fn check_heartbeat(&self, heartbeat: &VmecHeartbeatFields) -> Result<(), AuthFailure> {
+        self.check(&heartbeat.device_hash, |key| auth::verify_heartbeat(heartbeat, key))
+    }
+
+    /// The key of an allowed device, whether or not signatures are required
+    pub fn device_key(&self, device_hash: &str) -> Result<&DeviceKey, AuthFailure> {
+        let device_hash = device_hash.trim();
+        if self.revoked.contains(device_hash) {
+            return Err(AuthFailure::Revoked);
+        }
+        self.keys.get(device_hash).ok_or(AuthFailure::UnknownDevice)
+    }
+
+    fn check(&self, device_hash: &str, verify: impl Fn(&DeviceKey) -> bool) -> Result<(), AuthFailure> {
+        if !self.required {
+            return Ok(());
+        }
+        let key = self.device_key(device_hash)?;
+        if !verify(key) {
+            return Err(AuthFailure::BadSignature);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceCredential;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            required: true,
+            devices: vec![
+                DeviceCredential { device_hash: String::from("good"), key: "11".repeat(32) },
+                DeviceCredential { device_hash: String::from("gone"), key: "22".repeat(32) },
+            ],
+            revoked: vec![String::from("gone")],
+        }).unwrap()
+    }
+
+    fn signed_frame(device_hash: &str, key_hex: &str) -> VmecRequestFields {
+        let mut frame = VmecRequestFields {
+            device_hash: String::from(device_hash),
+            request_hash: String::from("request"),
+            ..Default::default()
+        };
+        auth::sign_frame(&mut frame, &DeviceKey::from_hex(key_hex).unwrap());
+        frame
+    }
+
+    #[test]
+    fn allowed_device_with_valid_signature() {
+        let authenticator = authenticator();
+        assert_eq!(authenticator.check_frame(&signed_frame("good\n", &"11".repeat(32))), Ok(()));
+
+        let mut heartbeat = VmecHeartbeatFields { device_hash: String::from("good"), sequence: 3, ..Default::default() };
+        assert_eq!(authenticator.check_heartbeat(&heartbeat), Err(AuthFailure::BadSignature));
+        auth::sign_heartbeat(&mut heartbeat, &DeviceKey::from_hex(&"11".repeat(32)).unwrap());
+        assert_eq!(authenticator.check_heartbeat(&heartbeat), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_forged_and_revoked_devices() {
+        let authenticator = authenticator();
+        assert_eq!(authenticator.check_frame(&signed_frame("stranger", &"11".repeat(32))), Err(AuthFailure::UnknownDevice));
+        assert_eq!(authenticator.check_frame(&signed_frame("good", &"33".repeat(32))), Err(AuthFailure::BadSignature));
+        assert_eq!(authenticator.check_frame(&signed_frame("gone", &"22".repeat(32))), Err(AuthFailure::Revoked));
+    }
+
+    #[test]
+    fn not_required_accepts_anything() {
+        let authenticator = Authenticator::new(&AuthConfig::default()).unwrap();
+        assert_eq!(authenticator.check_frame(&VmecRequestFields::default()), Ok(()));
+    }
+}
@@ -0,0 +1,59 @@
+//! Dynamic batching: queued frames from any number of devices are run through the engine together,
+//! once enough of them have arrived or the oldest has waited long enough.
+
+use std::time::{Duration, Instant};
+
+pub struct Batcher {
+    max_batch_size: usize,
+    max_wait: Duration,
+}
+
+impl Batcher {
+    pub fn new(max_batch_size: usize, max_wait: Duration) -> Self {
+        Batcher {
+            max_batch_size: max_batch_size.max(1),
+            max_wait,
+        }
+    }
+
+    pub fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    /// How much longer to wait for more frames before running a batch. None when a batch should run
+    /// now, or when nothing is queued.
+    pub fn wait(&self, queued: usize, oldest_received: Option<Instant>, now: Instant) -> Option<Duration> {
+        let oldest_received = oldest_received?;
+        if queued >= self.max_batch_size {
+            return None;
+        }
+        let waited = now.saturating_duration_since(oldest_received);
+        if waited >= self.max_wait {
+            return None;
+        }
+        Some(self.max_wait - waited)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_when_full_or_out_of_time() {
+        let batcher = Batcher::new(4, Duration::from_millis(10));
+        let start = Instant::now();
+        assert_eq!(batcher.wait(0, None, start), None);
+        assert_eq!(batcher.wait(1, Some(start), start + Duration::from_millis(4)), Some(Duration::from_millis(6)));
+        assert_eq!(batcher.wait(4, Some(start), start), None);
+        assert_eq!(batcher.wait(2, Some(start), start + Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn unbatched_by_default() {
+        let batcher = Batcher::new(0, Duration::ZERO);
+        assert_eq!(batcher.max_batch_size(), 1);
+        let now = Instant::now();
+        assert_eq!(batcher.wait(1, Some(now), now), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! Recent replies keyed by device and `request_hash`, so a client that timed out and resent a frame
+//! gets the original reply instead of having the frame processed twice. Keying on the device too
+//! means one device cannot get another's reply by sending the same hash.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Least recently used cache of encoded replies
+pub struct ResponseCache {
+    capacity: usize,
+    // keyed by trimmed device hash and request hash
+    replies: HashMap<(String, String), Vec<u8>>,
+    // keys from least to most recently used
+    order: VecDeque<(String, String)>,
+}
+
+impl ResponseCache {
+    /// A cache holding up to `capacity` replies; a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The reply sent for an earlier request from the device with this hash, if it is still cached
+    pub fn get(&mut self, device_hash: &str, request_hash: &str) -> Option<&Vec<u8>> {
+        let key = (String::from(device_hash.trim()), String::from(request_hash));
+        if !self.replies.contains_key(&key) {
+            return None;
+        }
+        self.touch(&key);
+        self.replies.get(&key)
+    }
+
+    pub fn insert(&mut self, device_hash: &str, request_hash: &str, reply: &[u8]) {
+        // frames without a hash cannot be told apart from each other
+        if self.capacity == 0 || request_hash.is_empty() {
+            return;
+        }
+        let key = (String::from(device_hash.trim()), String::from(request_hash));
+        if self.replies.insert(key.clone(), Vec::from(reply)).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        if self.replies.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &(String, String)) {
+        if let Some(position) = self.order.iter().position(|used| used == key) {
+            let key = self.order.remove(position).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert("bike", "a", &[1]);
+        cache.insert("bike", "b", &[2]);
+        assert_eq!(cache.get("bike", "a"), Some(&vec![1]));
+        cache.insert("bike", "c", &[3]);
+
+        assert!(cache.get("bike", "b").is_none());
+        assert_eq!(cache.get("bike\n", "a"), Some(&vec![1]));
+        assert_eq!(cache.get("bike", "c"), Some(&vec![3]));
+    }
+
+    #[test]
+    fn zero_capacity_and_empty_hashes_are_not_cached() {
+        let mut disabled = ResponseCache::new(0);
+        disabled.insert("bike", "a", &[1]);
+        assert!(disabled.get("bike", "a").is_none());
+
+        let mut cache = ResponseCache::new(2);
+        cache.insert("bike", "", &[1]);
+        assert!(cache.get("bike", "").is_none());
+    }
+
+    #[test]
+    fn replies_are_only_for_the_device_that_sent_the_frame() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert("bike", "a", &[1]);
+        assert!(cache.get("intruder", "a").is_none());
+        cache.insert("intruder", "a", &[2]);
+        assert_eq!(cache.get("bike", "a"), Some(&vec![1]));
+        assert_eq!(cache.get("intruder", "a"), Some(&vec![2]));
+    }
+}
@@ -0,0 +1,442 @@
+//! Server settings read from a TOML file given with `--config`, for settings that are too
+//! structured for command line flags. Every section is optional; see `server.example.toml`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub auth: AuthConfig,
+    pub transport: TransportConfig,
+    pub replay_protection: ReplayProtectionConfig,
+    /// Frames are not rate limited when this section is missing
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Class of each device hash, for settings that differ between kinds of device
+    pub device_classes: HashMap<String, String>,
+    /// Inference engines by name; a single mock engine serves both cameras when none are given
+    pub engines: HashMap<String, EngineConfig>,
+    /// Which engine runs on each camera's images
+    pub routing: RoutingConfig,
+    /// Server to forward frames to when this one cannot serve them; no forwarding when missing
+    pub upstream: Option<UpstreamConfig>,
+    /// Servers devices move between, to take over their sessions from; no sessions when missing
+    pub handover: Option<HandoverConfig>,
+    /// Candidate engines to mirror a sample of images to; no mirroring when missing
+    pub shadow: Option<ShadowConfig>,
+    /// A/B experiment that puts devices in arms with their own engines or thresholds
+    pub experiment: Option<ExperimentConfig>,
+    /// Map of the hazards devices' engines detect, by location; no map when missing
+    pub hazard_map: Option<HazardMapConfig>,
+    /// Weights devices take turns by under `--queue-policy fair`
+    pub fair_queue: FairQueueConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Reject every request that is not signed by an allowed device
+    pub required: bool,
+    /// The allowlist: devices that may connect, with their pre-shared keys
+    pub devices: Vec<DeviceCredential>,
+    /// Device hashes that may no longer connect, even if they are on the allowlist
+    pub revoked: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    /// Reject plaintext requests; devices must seal them with the key from their `auth.devices` entry
+    pub require_encryption: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayProtectionConfig {
+    /// Reject requests with stale timestamps or sequence numbers that were already used
+    pub required: bool,
+    /// How far a request's timestamp may be from the server clock, either way
+    pub max_clock_skew_ms: u64,
+}
+
+impl Default for ReplayProtectionConfig {
+    fn default() -> Self {
+        ReplayProtectionConfig {
+            required: false,
+            max_clock_skew_ms: 5000,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit on frames from all devices together
+    pub global: Option<RateLimit>,
+    /// Limit for each device not in a class with a limit of its own; unlimited when not given
+    pub default: Option<RateLimit>,
+    /// Limit for each device in the class, by class name
+    pub classes: HashMap<String, RateLimit>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Sustained frames per second
+    pub per_second: f64,
+    /// Frames that may be sent at once after a quiet period
+    pub burst: f64,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FairQueueConfig {
+    /// Frames per turn for each device in the class, by class name; devices not named get 1
+    pub classes: HashMap<String, f64>,
+    /// Frames per turn for particular devices, by device hash, in place of their class's weight
+    pub devices: HashMap<String, f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    pub model: PathBuf,
+    /// Square size images are letterboxed to for this model; `--model-input-size` when not given
+    pub input_size: Option<u32>,
+    /// Confidence below which detections are dropped; 0.5 when not given
+    pub threshold: Option<f64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Engine for front camera images, or "upstream" to forward frames to the upstream server
+    pub front: Option<String>,
+    /// Engine for rear camera images
+    pub rear: Option<String>,
+    /// Engines for the devices in a class, by class name, where they differ from the above
+    pub classes: HashMap<String, CameraRoutes>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// ZMQ address of the upstream `vmec-server`, e.g. "tcp://regional.example.com:5555"
+    pub address: String,
+    /// How long to wait for the upstream server's reply before answering with an error
+    #[serde(default = "UpstreamConfig::default_timeout_ms")]
+    pub timeout_ms: i32,
+    /// Forward frames that would otherwise be refused as busy
+    #[serde(default)]
+    pub when_busy: bool,
+    /// Most frames waiting on the upstream server at once; further frames are refused as busy
+    #[serde(default = "UpstreamConfig::default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+impl UpstreamConfig {
+    fn default_timeout_ms() -> i32 {
+        2000
+    }
+
+    fn default_max_in_flight() -> usize {
+        64
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HazardMapConfig {
+    /// Time over which a hazard's score halves when nobody detects it again
+    pub half_life_s: f64,
+    /// Detections of the same kind closer than this to a hazard are pooled into it
+    pub merge_radius_m: f64,
+    /// Detections less confident than this are left off the map
+    pub min_confidence: f64,
+    /// Score a hazard needs to be reported to devices, e.g. 1.0 for two detections at 0.5
+    pub min_score: f64,
+    /// Devices are alerted to hazards others detected within this distance; 0 for no alerts
+    pub push_radius_m: f64,
+    /// Time before a device is alerted to the same hazard again
+    pub push_interval_s: u64,
+}
+
+impl Default for HazardMapConfig {
+    fn default() -> Self {
+        HazardMapConfig {
+            half_life_s: 3.0 * 24.0 * 3600.0,
+            merge_radius_m: 15.0,
+            min_confidence: 0.5,
+            min_score: 1.0,
+            push_radius_m: 150.0,
+            push_interval_s: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    /// Part of what devices are assigned by, so that each experiment splits devices differently
+    pub name: String,
+    pub arms: HashMap<String, ArmConfig>,
+    /// Arm for particular devices, by device hash, in place of the one they would be assigned
+    #[serde(default)]
+    pub devices: HashMap<String, String>,
+    /// Arm for the devices in a class, by class name
+    #[serde(default)]
+    pub classes: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ArmConfig {
+    /// Share of the devices not named in `devices` or `classes` put in this arm; with 0, only
+    /// devices named there are
+    #[serde(default = "ArmConfig::default_weight")]
+    pub weight: u32,
+    /// Engine for front camera images in this arm; the device's usual engine when not given
+    pub front: Option<String>,
+    /// Engine for rear camera images in this arm
+    pub rear: Option<String>,
+    /// Detection threshold in this arm, in place of the engines' own
+    pub threshold: Option<f64>,
+}
+
+impl ArmConfig {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// Candidate engine for front camera images; front images are not mirrored when not given
+    pub front: Option<String>,
+    /// Candidate engine for rear camera images
+    pub rear: Option<String>,
+    /// Fraction of frames to mirror, 0.0 to 1.0
+    #[serde(default = "ShadowConfig::default_sample_rate")]
+    pub sample_rate: f64,
+    /// File to append the primary and candidate output for each mirrored image to, as JSON lines
+    pub log: PathBuf,
+}
+
+impl ShadowConfig {
+    fn default_sample_rate() -> f64 {
+        0.1
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HandoverConfig {
+    /// ZMQ address of each peer server, by the name it is started with (`--name`)
+    #[serde(default)]
+    pub peers: HashMap<String, String>,
+    /// Key every peer signs its session pulls with, as 64 hex digits, e.g. from `vmec-server keygen`;
+    /// the same on every peer
+    pub peer_key: String,
+    /// How long to wait for a peer to hand over a session
+    #[serde(default = "HandoverConfig::default_timeout_ms")]
+    pub timeout_ms: i32,
+    /// Sessions of devices not heard from for this long are forgotten
+    #[serde(default = "HandoverConfig::default_max_idle_s")]
+    pub max_idle_s: u64,
+}
+
+impl HandoverConfig {
+    fn default_timeout_ms() -> i32 {
+        1000
+    }
+
+    fn default_max_idle_s() -> u64 {
+        3600
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraRoutes {
+    pub front: Option<String>,
+    pub rear: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceCredential {
+    pub device_hash: String,
+    /// 64 hex digits, as printed by `vmec-server keygen`
+    pub key: String,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_are_optional() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert!(!config.auth.required);
+        assert!(config.auth.devices.is_empty());
+        assert!(!config.transport.require_encryption);
+        assert_eq!(config.replay_protection.max_clock_skew_ms, 5000);
+    }
+
+    #[test]
+    fn parses_auth() {
+        let config: ServerConfig = toml::from_str(r#"
+            [auth]
+            required = true
+            revoked = ["old-device"]
+
+            [[auth.devices]]
+            device_hash = "device"
+            key = "00"
+        "#).unwrap();
+        assert!(config.auth.required);
+        assert_eq!(config.auth.devices[0].device_hash, "device");
+        assert_eq!(config.auth.revoked, vec!["old-device"]);
+
+        assert!(toml::from_str::<ServerConfig>("[auth]\nrequire = true").is_err());
+    }
+
+    #[test]
+    fn parses_rate_limits() {
+        let config: ServerConfig = toml::from_str(r#"
+            [rate_limit]
+            global = { per_second = 100.0, burst = 200.0 }
+
+            [rate_limit.classes.fleet]
+            per_second = 30.0
+            burst = 60.0
+
+            [device_classes]
+            truck = "fleet"
+        "#).unwrap();
+        let rate_limit = config.rate_limit.unwrap();
+        assert_eq!(rate_limit.global.unwrap().burst, 200.0);
+        assert!(rate_limit.default.is_none());
+        assert_eq!(rate_limit.classes["fleet"].per_second, 30.0);
+        assert_eq!(config.device_classes["truck"], "fleet");
+    }
+
+    #[test]
+    fn parses_engines_and_routing() {
+        let config: ServerConfig = toml::from_str(r#"
+            [engines.hazards]
+            model = "models/hazards.pt"
+            input_size = 640
+
+            [engines.vehicles]
+            model = "models/vehicles.pt"
+
+            [routing]
+            front = "hazards"
+            rear = "vehicles"
+
+            [routing.classes.fleet]
+            rear = "hazards"
+        "#).unwrap();
+        assert_eq!(config.engines["hazards"].input_size, Some(640));
+        assert_eq!(config.engines["vehicles"].model, PathBuf::from("models/vehicles.pt"));
+        assert_eq!(config.routing.front.as_deref(), Some("hazards"));
+        assert!(config.routing.classes["fleet"].front.is_none());
+    }
+
+    #[test]
+    fn parses_upstream() {
+        let config: ServerConfig = toml::from_str(r#"
+            [upstream]
+            address = "tcp://regional:5555"
+            when_busy = true
+        "#).unwrap();
+        let upstream = config.upstream.unwrap();
+        assert_eq!(upstream.address, "tcp://regional:5555");
+        assert!(upstream.when_busy);
+        assert_eq!((upstream.timeout_ms, upstream.max_in_flight), (2000, 64));
+    }
+
+    #[test]
+    fn parses_experiment() {
+        let config: ServerConfig = toml::from_str(r#"
+            [experiment]
+            name = "hazards-v2"
+            devices = { tester = "candidate" }
+
+            [experiment.arms.control]
+
+            [experiment.arms.candidate]
+            front = "hazards-v2"
+            threshold = 0.6
+            weight = 2
+        "#).unwrap();
+        let experiment = config.experiment.unwrap();
+        assert_eq!(experiment.arms["control"].weight, 1);
+        assert!(experiment.arms["control"].front.is_none());
+        assert_eq!(experiment.arms["candidate"].threshold, Some(0.6));
+        assert_eq!(experiment.devices["tester"], "candidate");
+    }
+
+    #[test]
+    fn parses_hazard_map() {
+        let config: ServerConfig = toml::from_str("[hazard_map]\nhalf_life_s = 3600.0").unwrap();
+        let hazard_map = config.hazard_map.unwrap();
+        assert_eq!(hazard_map.half_life_s, 3600.0);
+        assert_eq!(hazard_map.push_radius_m, 150.0);
+        assert!(toml::from_str::<ServerConfig>("[hazard_map]\nhalf_life = 3600.0").is_err());
+    }
+
+    #[test]
+    fn parses_fair_queue() {
+        let config: ServerConfig = toml::from_str(r#"
+            [fair_queue]
+            classes = { fleet = 0.5 }
+            devices = { ambulance = 4.0 }
+        "#).unwrap();
+        assert_eq!(config.fair_queue.classes["fleet"], 0.5);
+        assert_eq!(config.fair_queue.devices["ambulance"], 4.0);
+        assert!(ServerConfig::default().fair_queue.devices.is_empty());
+    }
+
+    #[test]
+    fn parses_shadow() {
+        let config: ServerConfig = toml::from_str(r#"
+            [shadow]
+            front = "hazards-candidate"
+            log = "shadow.jsonl"
+        "#).unwrap();
+        let shadow = config.shadow.unwrap();
+        assert_eq!(shadow.front.as_deref(), Some("hazards-candidate"));
+        assert!(shadow.rear.is_none());
+        assert_eq!(shadow.sample_rate, 0.1);
+    }
+
+    #[test]
+    fn parses_handover() {
+        let config: ServerConfig = toml::from_str(r#"
+            [handover]
+            peer_key = "00"
+            [handover.peers]
+            edge-b = "tcp://edge-b:5555"
+        "#).unwrap();
+        let handover = config.handover.unwrap();
+        assert_eq!(handover.peers["edge-b"], "tcp://edge-b:5555");
+        assert_eq!(handover.timeout_ms, 1000);
+        assert_eq!(handover.max_idle_s, 3600);
+        // pulls are always signed
+        assert!(toml::from_str::<ServerConfig>("[handover.peers]\nedge-b = \"tcp://edge-b:5555\"").is_err());
+    }
+}
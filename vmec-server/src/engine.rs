@@ -0,0 +1,429 @@
+//! Inference engines, and which one each camera's images go to. Front cameras look for road hazards
+//! and rear cameras for approaching vehicles, so they run different models; device classes can be
+//! routed to models of their own. Cameras routed to `upstream` need a model this server does not
+//! have, so their frames are forwarded whole to the upstream server. Devices in an experiment arm
+//! get the arm's engines and threshold in place of their usual ones; see `experiment`.
+//!
+//! Models can be reloaded while the server runs; see `reload`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use cornflakes::CameraDirection;
+use vmec_preproc::{PreprocError, Preprocessor, RgbImage, LETTERBOX_FILL};
+
+use crate::config::{CameraRoutes, ServerConfig};
+use crate::experiment::Experiment;
+
+/// Name of the engine that serves both cameras when the config defines none
+const MOCK_ENGINE: &str = "mock";
+
+/// Route for cameras whose model is only on the upstream server
+pub const UPSTREAM_ENGINE: &str = "upstream";
+
+/// Detection threshold of engines that do not set one
+const DEFAULT_THRESHOLD: f64 = 0.5;
+
+pub fn camera_name(camera: CameraDirection) -> &'static str {
+    match camera {
+        CameraDirection::Frontcam => "front",
+        CameraDirection::Rearcam => "rear",
+    }
+}
+
+#[derive(Clone)]
+pub struct Engine {
+    pub name: String,
+    pub model: PathBuf,
+    /// 1 for the model loaded at startup, and one more for every reload
+    pub version: u64,
+    pub threshold: f64,
+    // letterboxes images to the model's input size; images are not decoded when None
+    preprocessor: Option<Preprocessor>,
+}
+
+impl Engine {
+    /// Runs the model on one camera image, with the given threshold in place of the engine's own.
+    /// The mock engine reports which engine and model saw it.
+    pub fn infer(&self, jpeg: &[u8], threshold: Option<f64>) -> Result<serde_json::Value, PreprocError> {
+        self.infer_batch(&[(jpeg, threshold)]).pop().unwrap()
+    }
+
+    /// Runs the model once on all of these camera images, each with its own threshold. The outputs
+    /// are in the same order; an image that cannot be preprocessed fails on its own, and the model
+    /// runs on the rest.
+    pub fn infer_batch(&self, images: &[(&[u8], Option<f64>)]) -> Vec<Result<serde_json::Value, PreprocError>> {
+        let inputs: Vec<_> = images
+            .iter()
+            .map(|(jpeg, _)| match &self.preprocessor {
+                Some(preprocessor) => preprocessor.run(jpeg).map(drop),
+                None => Ok(()),
+            })
+            .collect();
+        // a real engine would stack the model inputs into one tensor here; the mock ignores them,
+        // but pays for making them
+        inputs
+            .into_iter()
+            .zip(images)
+            .map(|(input, (_, threshold))| {
+                input.map(|()| {
+                    serde_json::json!({
+                        "engine": self.name,
+                        "model": self.model.display().to_string(),
+                        "version": self.version,
+                        "threshold": threshold.unwrap_or(self.threshold),
+                    })
+                })
+            })
+            .collect()
+    }
+
+    /// Loads the model file again and warms the engine up, as the next version of this engine
+    pub fn reload(&self) -> Result<Engine, String> {
+        // a real engine would deserialize the weights here; the mock only checks that they can be read
+        let weights = fs::read(&self.model).map_err(|e| format!("could not read {}: {}", self.model.display(), e))?;
+        if weights.is_empty() {
+            return Err(format!("{} is empty", self.model.display()));
+        }
+        let engine = Engine { version: self.version + 1, ..self.clone() };
+        engine.warm_up()?;
+        Ok(engine)
+    }
+
+    /// Runs the engine once on a blank image, so that the first real frame is not slowed down by
+    /// one-off setup
+    fn warm_up(&self) -> Result<(), String> {
+        let jpeg = match &self.preprocessor {
+            Some(preprocessor) => {
+                let (width, height) = (preprocessor.width, preprocessor.height);
+                let blank = RgbImage::from_raw(width, height, vec![LETTERBOX_FILL[0]; (width * height * 3) as usize]).unwrap();
+                vmec_preproc::encode_jpeg(&blank, 90).map_err(|e| format!("warm-up failed: {}", e))?
+            }
+            None => Vec::new(),
+        };
+        self.infer(&jpeg, None).map(drop).map_err(|e| format!("warm-up failed: {}", e))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    // index into EngineRouter::engines
+    Local(usize),
+    Upstream,
+}
+
+#[derive(Clone, Copy)]
+struct Routes {
+    front: Target,
+    rear: Target,
+    // overrides the engines' thresholds
+    threshold: Option<f64>,
+}
+
+impl Routes {
+    fn get(&self, camera: CameraDirection) -> Target {
+        match camera {
+            CameraDirection::Frontcam => self.front,
+            CameraDirection::Rearcam => self.rear,
+        }
+    }
+}
+
+pub struct EngineRouter {
+    engines: Vec<Engine>,
+    default_routes: Routes,
+    class_routes: HashMap<String, Routes>,
+    // class of each device, keyed by trimmed device hash
+    device_classes: HashMap<String, String>,
+    experiment: Option<Experiment>,
+    // what each arm of the experiment changes, by arm index
+    arm_routes: Vec<ArmRoutes>,
+}
+
+struct ArmRoutes {
+    front: Option<Target>,
+    rear: Option<Target>,
+    threshold: Option<f64>,
+}
+
+impl EngineRouter {
+    /// Engines without an `input_size` of their own letterbox to `default_input_size`, if given
+    pub fn new(config: &ServerConfig, default_input_size: Option<u32>) -> Result<Self, String> {
+        let preprocessor = |input_size: Option<u32>| input_size.or(default_input_size).map(|size| Preprocessor::new(size, size));
+        let device_classes = config.device_classes.iter().map(|(device_hash, class)| (String::from(device_hash.trim()), class.clone())).collect();
+        let experiment = config.experiment.as_ref().map(Experiment::new).transpose()?;
+
+        if config.engines.is_empty() {
+            let routing = &config.routing;
+            let arm_engines = experiment.iter().flat_map(|experiment| experiment.arms()).any(|arm| arm.front.is_some() || arm.rear.is_some());
+            if routing.front.is_some() || routing.rear.is_some() || !routing.classes.is_empty() || arm_engines {
+                return Err(String::from("routing refers to engines, but none are configured"));
+            }
+            let arm_routes = experiment.iter().flat_map(|experiment| experiment.arms())
+                .map(|arm| ArmRoutes { front: None, rear: None, threshold: arm.threshold })
+                .collect();
+            return Ok(EngineRouter {
+                engines: vec![Engine { name: String::from(MOCK_ENGINE), model: PathBuf::new(), version: 1, threshold: DEFAULT_THRESHOLD, preprocessor: preprocessor(None) }],
+                default_routes: Routes { front: Target::Local(0), rear: Target::Local(0), threshold: None },
+                class_routes: HashMap::new(),
+                device_classes,
+                experiment,
+                arm_routes,
+            });
+        }
+
+        let mut names: Vec<&String> = config.engines.keys().collect();
+        names.sort();
+        let index = |name: &str| {
+            if name == UPSTREAM_ENGINE {
+                if config.upstream.is_none() {
+                    return Err(String::from("routing forwards to the upstream server, but [upstream] is not configured"));
+                }
+                return Ok(Target::Upstream);
+            }
+            names.iter().position(|engine| *engine == name).map(Target::Local).ok_or_else(|| format!("no engine named {}", name))
+        };
+
+        let front = config.routing.front.as_deref().ok_or("routing.front is not set")?;
+        let rear = config.routing.rear.as_deref().ok_or("routing.rear is not set")?;
+        let default_routes = Routes { front: index(front)?, rear: index(rear)?, threshold: None };
+
+        let mut class_routes = HashMap::new();
+        for (class, CameraRoutes { front, rear }) in &config.routing.classes {
+            let routes = Routes {
+                front: front.as_deref().map(index).transpose()?.unwrap_or(default_routes.front),
+                rear: rear.as_deref().map(index).transpose()?.unwrap_or(default_routes.rear),
+                threshold: None,
+            };
+            class_routes.insert(class.clone(), routes);
+        }
+
+        let mut arm_routes = Vec::new();
+        for arm in experiment.iter().flat_map(|experiment| experiment.arms()) {
+            arm_routes.push(ArmRoutes {
+                front: arm.front.as_deref().map(index).transpose()?,
+                rear: arm.rear.as_deref().map(index).transpose()?,
+                threshold: arm.threshold,
+            });
+        }
+
+        let engines = names.iter().map(|name| {
+            let engine = &config.engines[*name];
+            Engine {
+                name: String::clone(name),
+                model: engine.model.clone(),
+                version: 1,
+                threshold: engine.threshold.unwrap_or(DEFAULT_THRESHOLD),
+                preprocessor: preprocessor(engine.input_size),
+            }
+        }).collect();
+        Ok(EngineRouter { engines, default_routes, class_routes, device_classes, experiment, arm_routes })
+    }
+
+    pub fn engine_names(&self) -> impl Iterator<Item = &str> {
+        self.engines.iter().map(|engine| engine.name.as_str())
+    }
+
+    pub fn engines(&self) -> &[Engine] {
+        &self.engines
+    }
+
+    pub fn engine(&self, name: &str) -> Option<&Engine> {
+        self.engines.iter().find(|engine| engine.name == name)
+    }
+
+    /// Swaps in a reloaded engine in place of the one with the same name. Frames are processed one
+    /// batch at a time, so a batch already running finishes on the old model.
+    pub fn replace(&mut self, engine: Engine) -> Result<(), String> {
+        let current = self.engines.iter_mut().find(|current| current.name == engine.name).ok_or_else(|| format!("no engine named {}", engine.name))?;
+        *current = engine;
+        Ok(())
+    }
+
+    pub fn experiment(&self) -> Option<&Experiment> {
+        self.experiment.as_ref()
+    }
+
+    fn arm_index(&self, device_hash: &str) -> Option<usize> {
+        let class = self.device_classes.get(device_hash.trim()).map(String::as_str);
+        self.experiment.as_ref()?.assign(device_hash, class)
+    }
+
+    /// The experiment arm the device is in, if there is an experiment
+    pub fn arm(&self, device_hash: &str) -> Option<&str> {
+        let index = self.arm_index(device_hash)?;
+        Some(&self.experiment.as_ref()?.arms()[index].name)
+    }
+
+    fn routes(&self, device_hash: &str) -> Routes {
+        let routes = *self.device_classes
+            .get(device_hash.trim())
+            .and_then(|class| self.class_routes.get(class))
+            .unwrap_or(&self.default_routes);
+        match self.arm_index(device_hash).map(|index| &self.arm_routes[index]) {
+            Some(arm) => Routes {
+                front: arm.front.unwrap_or(routes.front),
+                rear: arm.rear.unwrap_or(routes.rear),
+                threshold: arm.threshold,
+            },
+            None => routes,
+        }
+    }
+
+    /// The engine for this device's images from this camera, or None if they go upstream
+    pub fn route(&self, device_hash: &str, camera: CameraDirection) -> Option<&Engine> {
+        match self.routes(device_hash).get(camera) {
+            Target::Local(index) => Some(&self.engines[index]),
+            Target::Upstream => None,
+        }
+    }
+
+    /// Threshold for this device's images in place of the engines' own, if its experiment arm sets one
+    pub fn threshold(&self, device_hash: &str) -> Option<f64> {
+        self.routes(device_hash).threshold
+    }
+
+    /// Whether this device's frames need a model that only the upstream server has
+    pub fn forwards(&self, device_hash: &str) -> bool {
+        let routes = self.routes(device_hash);
+        routes.front == Target::Upstream || routes.rear == Target::Upstream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ServerConfig {
+        toml::from_str(r#"
+            [engines.hazards]
+            model = "hazards.pt"
+
+            [engines.vehicles]
+            model = "vehicles.pt"
+
+            [engines.fleet-vehicles]
+            model = "fleet-vehicles.pt"
+
+            [routing]
+            front = "hazards"
+            rear = "vehicles"
+
+            [routing.classes.fleet]
+            rear = "fleet-vehicles"
+
+            [device_classes]
+            truck = "fleet"
+        "#).unwrap()
+    }
+
+    #[test]
+    fn routes_by_camera_and_class() {
+        let router = EngineRouter::new(&config(), None).unwrap();
+        assert_eq!(router.route("bike", CameraDirection::Frontcam).unwrap().name, "hazards");
+        assert_eq!(router.route("bike", CameraDirection::Rearcam).unwrap().name, "vehicles");
+        assert_eq!(router.route("truck\n", CameraDirection::Frontcam).unwrap().name, "hazards");
+        assert_eq!(router.route("truck\n", CameraDirection::Rearcam).unwrap().name, "fleet-vehicles");
+
+        let output = router.route("bike", CameraDirection::Rearcam).unwrap().infer(b"not decoded", None).unwrap();
+        assert_eq!(output["model"], "vehicles.pt");
+        assert_eq!(output["threshold"], DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn routes_experiment_arms() {
+        let mut config = config();
+        config.experiment = Some(toml::from_str(r#"
+            name = "vehicles-v2"
+            devices = { truck = "candidate", tester = "candidate" }
+
+            [arms.control]
+            weight = 1
+
+            [arms.candidate]
+            weight = 0
+            rear = "fleet-vehicles"
+            threshold = 0.7
+        "#).unwrap());
+        let router = EngineRouter::new(&config, None).unwrap();
+        assert_eq!(router.arm("bike"), Some("control"));
+        assert_eq!(router.route("bike", CameraDirection::Rearcam).unwrap().name, "vehicles");
+        assert_eq!(router.threshold("bike"), None);
+
+        assert_eq!(router.arm("tester\n"), Some("candidate"));
+        assert_eq!(router.route("tester", CameraDirection::Frontcam).unwrap().name, "hazards");
+        assert_eq!(router.route("tester", CameraDirection::Rearcam).unwrap().name, "fleet-vehicles");
+        assert_eq!(router.threshold("tester"), Some(0.7));
+
+        config.experiment.as_mut().unwrap().arms.get_mut("candidate").unwrap().front = Some(String::from("missing"));
+        assert!(EngineRouter::new(&config, None).is_err());
+    }
+
+    #[test]
+    fn mock_engine_without_config() {
+        let router = EngineRouter::new(&ServerConfig::default(), Some(32)).unwrap();
+        assert_eq!(router.engine_names().collect::<Vec<_>>(), vec![MOCK_ENGINE]);
+        // with an input size, images are decoded
+        assert!(router.route("bike", CameraDirection::Frontcam).unwrap().infer(b"not a jpeg", None).is_err());
+    }
+
+    #[test]
+    fn infers_batches_in_order() {
+        let router = EngineRouter::new(&ServerConfig::default(), Some(32)).unwrap();
+        let engine = router.route("bike", CameraDirection::Frontcam).unwrap();
+        let blank = RgbImage::from_raw(32, 32, vec![0; 32 * 32 * 3]).unwrap();
+        let jpeg = vmec_preproc::encode_jpeg(&blank, 90).unwrap();
+        let outputs = engine.infer_batch(&[(&jpeg, None), (b"not a jpeg", None), (&jpeg, Some(0.9))]);
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].as_ref().unwrap(), &engine.infer(&jpeg, None).unwrap());
+        assert!(outputs[1].is_err());
+        assert_eq!(outputs[2].as_ref().unwrap()["threshold"], 0.9);
+    }
+
+    #[test]
+    fn rejects_routes_to_missing_engines() {
+        let mut missing_engine = config();
+        missing_engine.routing.rear = Some(String::from("missing"));
+        assert!(EngineRouter::new(&missing_engine, None).is_err());
+
+        let mut unrouted = config();
+        unrouted.routing.front = None;
+        assert!(EngineRouter::new(&unrouted, None).is_err());
+
+        let routing_only: ServerConfig = toml::from_str("[routing]\nfront = \"hazards\"").unwrap();
+        assert!(EngineRouter::new(&routing_only, None).is_err());
+    }
+
+    #[test]
+    fn routes_to_upstream() {
+        let mut config = config();
+        config.routing.classes.get_mut("fleet").unwrap().rear = Some(String::from(UPSTREAM_ENGINE));
+        assert!(EngineRouter::new(&config, None).is_err());
+
+        config.upstream = Some(toml::from_str("address = \"tcp://regional:5555\"").unwrap());
+        let router = EngineRouter::new(&config, None).unwrap();
+        assert!(router.forwards("truck"));
+        assert!(router.route("truck", CameraDirection::Rearcam).is_none());
+        assert!(!router.forwards("bike"));
+    }
+
+    #[test]
+    fn reloads_models() {
+        let model = std::env::temp_dir().join(format!("vmec-engine-test-{}.pt", std::process::id()));
+        let mut config = config();
+        config.engines.get_mut("hazards").unwrap().model = model.clone();
+        config.engines.get_mut("hazards").unwrap().input_size = Some(32);
+        let mut router = EngineRouter::new(&config, None).unwrap();
+        let hazards = router.route("bike", CameraDirection::Frontcam).unwrap();
+        assert!(hazards.reload().is_err());
+
+        fs::write(&model, b"weights").unwrap();
+        let reloaded = hazards.reload().unwrap();
+        assert_eq!(reloaded.version, 2);
+        router.replace(reloaded).unwrap();
+        assert_eq!(router.route("bike", CameraDirection::Frontcam).unwrap().version, 2);
+        assert_eq!(router.route("bike", CameraDirection::Rearcam).unwrap().version, 1);
+        fs::remove_file(&model).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+//! Event store: every processed request, the detections in its reply and the alerts sent to
+//! devices, kept in an SQLite database for review after a ride or an incident.
+//!
+//! All events share one table, with the kind of event ("request", "detection" or "alert"), its
+//! class (the request's status, the detected hazard's kind or the alert's kind), the device and
+//! its class, where the device was when it sent a location, and the rest as JSON. The database
+//! can be read with any SQLite client; `query` filters it by device, time, class and place.
+//! Writing happens on a background thread so that it never delays a reply.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, warn};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+
+use cornflakes::{VmecAlertFields, VmecLocation, VmecResponseFields};
+
+use crate::alerts;
+use crate::hazards;
+
+/// Events waiting to be written; beyond this, new events are dropped rather than queued
+const EVENT_BACKLOG: usize = 1024;
+
+/// Most events written in one transaction
+const WRITE_BATCH: usize = 256;
+
+/// Events a query returns when it does not give a limit, and the most it may ask for
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        device_hash TEXT NOT NULL,
+        device_class TEXT,
+        kind TEXT NOT NULL,
+        class TEXT NOT NULL,
+        latitude REAL,
+        longitude REAL,
+        detail TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_by_time ON events (timestamp_ms);
+    CREATE INDEX IF NOT EXISTS events_by_device ON events (device_hash, timestamp_ms);
+    CREATE INDEX IF NOT EXISTS events_by_place ON events (latitude, longitude);
+";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Request,
+    Detection,
+    Alert,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Request => "request",
+            EventKind::Detection => "detection",
+            EventKind::Alert => "alert",
+        }
+    }
+}
+
+pub struct Event {
+    pub timestamp_ms: u64,
+    pub device_hash: String,
+    pub device_class: Option<String>,
+    pub kind: EventKind,
+    pub class: String,
+    pub location: Option<VmecLocation>,
+    pub detail: serde_json::Value,
+}
+
+/// Filters for `query`; each one that is given narrows the result
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventQuery {
+    pub device_hash: Option<String>,
+    pub device_class: Option<String>,
+    /// From this time on, in ms since the epoch
+    pub from_ms: Option<u64>,
+    /// Up to and not including this time
+    pub to_ms: Option<u64>,
+    pub kind: Option<EventKind>,
+    pub class: Option<String>,
+    /// Only events with a location within this distance of a place
+    pub near: Option<Near>,
+    /// Most events to return, oldest first; 100 when not given
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Near {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_m: f64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StoredEvent {
+    pub id: i64,
+    pub timestamp_ms: u64,
+    pub device_hash: String,
+    pub device_class: Option<String>,
+    pub kind: String,
+    pub class: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub detail: serde_json::Value,
+}
+
+pub struct EventStore {
+    sender: SyncSender<Event>,
+    // separate from the writer's connection, so that queries are not held up by writes
+    reader: Connection,
+    // keyed by trimmed device hash
+    device_classes: HashMap<String, String>,
+}
+
+impl EventStore {
+    /// Opens the database, creating it if need be, and starts the background writer
+    pub fn open(path: &Path, device_classes: &HashMap<String, String>) -> Result<Self, String> {
+        let describe = |e: rusqlite::Error| format!("{}: {}", path.display(), e);
+        let writer = Connection::open(path).map_err(describe)?;
+        // lets the reader see committed events while the writer carries on
+        writer.pragma_update(None, "journal_mode", "WAL").map_err(describe)?;
+        writer.execute_batch(SCHEMA).map_err(describe)?;
+        let reader = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(describe)?;
+
+        let (sender, receiver) = sync_channel::<Event>(EVENT_BACKLOG);
+        thread::spawn(move || run_writer(writer, receiver));
+        Ok(EventStore {
+            sender,
+            reader,
+            device_classes: device_classes.iter().map(|(device_hash, class)| (String::from(device_hash.trim()), class.clone())).collect(),
+        })
+    }
+
+    fn record(&self, event: Event) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => warn!("Event store backlogged; dropping {} event from {}", event.kind.as_str(), event.device_hash),
+            Err(TrySendError::Disconnected(_)) => error!("Event store writer has stopped"),
+        }
+    }
+
+    fn event(&self, timestamp_ms: u64, device_hash: &str, kind: EventKind, class: String, location: Option<VmecLocation>, detail: serde_json::Value) -> Event {
+        let device_hash = String::from(device_hash.trim());
+        Event { timestamp_ms, device_class: self.device_classes.get(&device_hash).cloned(), device_hash, kind, class, location, detail }
+    }
+
+    /// Records the request with what came of it, and every hazard detected in the reply
+    pub fn record_reply(&self, device_hash: &str, request_hash: &str, location: Option<VmecLocation>, response: &VmecResponseFields, latency: Duration, now_ms: u64) {
+        let detail = serde_json::json!({
+            "request_hash": request_hash,
+            "latency_us": latency.as_micros() as u64,
+            "response_hash": response.response_hash,
+            "error_code": format!("{:?}", response.error_code),
+            "error_message": response.error_message,
+            "model_version": response.model_version,
+            "experiment_arm": response.experiment_arm,
+        });
+        let status = format!("{:?}", response.status);
+        self.record(self.event(now_ms, device_hash, EventKind::Request, status, location, detail));
+        for (kind, confidence) in hazards::detections_in(&response.neural_output) {
+            let detail = serde_json::json!({ "request_hash": request_hash, "confidence": confidence });
+            self.record(self.event(now_ms, device_hash, EventKind::Detection, kind, location, detail));
+        }
+    }
+
+    /// Records an alert sent to a device
+    pub fn record_alert(&self, alert: &VmecAlertFields) {
+        let detail = serde_json::json!({ "message": alert.message, "detail": alert.detail });
+        let kind = String::from(alerts::kind_name(alert.kind));
+        self.record(self.event(alert.timestamp_ms, &alert.device_hash, EventKind::Alert, kind, None, detail));
+    }
+
+    /// Events matching the query, oldest first. Events still waiting on the writer are not included.
+    pub fn query(&self, query: &EventQuery) -> Result<Vec<StoredEvent>, String> {
+        query_events(&self.reader, query)
+    }
+}
+
+fn run_writer(mut connection: Connection, receiver: Receiver<Event>) {
+    while let Ok(first) = receiver.recv() {
+        let mut events = vec![first];
+        events.extend(receiver.try_iter().take(WRITE_BATCH - 1));
+        if let Err(e) = write_events(&mut connection, &events) {
+            error!("Failed to store {} events: {}", events.len(), e);
+        }
+    }
+}
+
+fn write_events(connection: &mut Connection, events: &[Event]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare_cached(
+            "INSERT INTO events (timestamp_ms, device_hash, device_class, kind, class, latitude, longitude, detail)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for event in events {
+            insert.execute(params![
+                event.timestamp_ms as i64,
+                event.device_hash,
+                event.device_class,
+                event.kind.as_str(),
+                event.class,
+                event.location.map(|location| location.latitude),
+                event.location.map(|location| location.longitude),
+                event.detail.to_string(),
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+fn query_events(connection: &Connection, query: &EventQuery) -> Result<Vec<StoredEvent>, String> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit > MAX_LIMIT {
+        return Err(format!("limit may be at most {}", MAX_LIMIT));
+    }
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    if let Some(device_hash) = &query.device_hash {
+        conditions.push("device_hash = ?");
+        values.push(Value::Text(String::from(device_hash.trim())));
+    }
+    if let Some(device_class) = &query.device_class {
+        conditions.push("device_class = ?");
+        values.push(Value::Text(device_class.clone()));
+    }
+    if let Some(from_ms) = query.from_ms {
+        conditions.push("timestamp_ms >= ?");
+        values.push(Value::Integer(from_ms as i64));
+    }
+    if let Some(to_ms) = query.to_ms {
+        conditions.push("timestamp_ms < ?");
+        values.push(Value::Integer(to_ms as i64));
+    }
+    if let Some(kind) = query.kind {
+        conditions.push("kind = ?");
+        values.push(Value::Text(String::from(kind.as_str())));
+    }
+    if let Some(class) = &query.class {
+        conditions.push("class = ?");
+        values.push(Value::Text(class.clone()));
+    }
+    if let Some(near) = &query.near {
+        if near.radius_m <= 0.0 || near.radius_m > hazards::MAX_NEARBY_RADIUS_M {
+            return Err(format!("radius_m must be above 0 and at most {}", hazards::MAX_NEARBY_RADIUS_M));
+        }
+        // a box around the circle, which the index can narrow down; its corners are left out below
+        let latitude_deg = (near.radius_m / hazards::EARTH_RADIUS_M).to_degrees();
+        let longitude_deg = latitude_deg / near.latitude.to_radians().cos().max(0.01);
+        conditions.push("latitude BETWEEN ? AND ?");
+        values.extend([Value::Real(near.latitude - latitude_deg), Value::Real(near.latitude + latitude_deg)]);
+        conditions.push("longitude BETWEEN ? AND ?");
+        values.extend([Value::Real(near.longitude - longitude_deg), Value::Real(near.longitude + longitude_deg)]);
+    }
+
+    let mut sql = String::from("SELECT id, timestamp_ms, device_hash, device_class, kind, class, latitude, longitude, detail FROM events");
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    sql.push_str(" ORDER BY timestamp_ms, id");
+
+    let describe = |e: rusqlite::Error| format!("could not query events: {}", e);
+    let mut statement = connection.prepare(&sql).map_err(describe)?;
+    let mut rows = statement.query(rusqlite::params_from_iter(values)).map_err(describe)?;
+    let mut events = Vec::new();
+    while events.len() < limit {
+        let Some(row) = rows.next().map_err(describe)? else {
+            break;
+        };
+        let detail: String = row.get(8).map_err(describe)?;
+        let event = StoredEvent {
+            id: row.get(0).map_err(describe)?,
+            timestamp_ms: row.get::<_, i64>(1).map_err(describe)? as u64,
+            device_hash: row.get(2).map_err(describe)?,
+            device_class: row.get(3).map_err(describe)?,
+            kind: row.get(4).map_err(describe)?,
+            class: row.get(5).map_err(describe)?,
+            latitude: row.get(6).map_err(describe)?,
+            longitude: row.get(7).map_err(describe)?,
+            detail: serde_json::from_str(&detail).unwrap_or(serde_json::Value::String(detail)),
+        };
+        if let (Some(near), Some(latitude), Some(longitude)) = (&query.near, event.latitude, event.longitude) {
+            let place = VmecLocation { latitude: near.latitude, longitude: near.longitude };
+            if hazards::distance_m(place, VmecLocation { latitude, longitude }) > near.radius_m {
+                continue;
+            }
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(timestamp_ms: u64, device_hash: &str, kind: EventKind, class: &str, location: Option<(f64, f64)>) -> Event {
+        Event {
+            timestamp_ms,
+            device_hash: String::from(device_hash),
+            device_class: (device_hash == "truck").then(|| String::from("fleet")),
+            kind,
+            class: String::from(class),
+            location: location.map(|(latitude, longitude)| VmecLocation { latitude, longitude }),
+            detail: json!({ "at": timestamp_ms }),
+        }
+    }
+
+    fn database() -> Connection {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        write_events(&mut connection, &[
+            event(300, "bike", EventKind::Detection, "pothole", Some((37.5665, 126.978))),
+            event(100, "bike", EventKind::Request, "Ok", Some((37.5665, 126.978))),
+            event(200, "truck", EventKind::Request, "Error", None),
+            event(400, "bike", EventKind::Detection, "pothole", Some((37.5765, 126.978))),
+            event(500, "truck", EventKind::Alert, "hazard_ahead", None),
+        ]).unwrap();
+        connection
+    }
+
+    fn times(connection: &Connection, query: EventQuery) -> Vec<u64> {
+        query_events(connection, &query).unwrap().iter().map(|event| event.timestamp_ms).collect()
+    }
+
+    #[test]
+    fn filters_by_device_time_and_class() {
+        let connection = database();
+        assert_eq!(times(&connection, EventQuery::default()), [100, 200, 300, 400, 500]);
+        assert_eq!(times(&connection, EventQuery { device_hash: Some(String::from("truck\n")), ..EventQuery::default() }), [200, 500]);
+        assert_eq!(times(&connection, EventQuery { device_class: Some(String::from("fleet")), kind: Some(EventKind::Alert), ..EventQuery::default() }), [500]);
+        assert_eq!(times(&connection, EventQuery { from_ms: Some(200), to_ms: Some(400), ..EventQuery::default() }), [200, 300]);
+        assert_eq!(times(&connection, EventQuery { class: Some(String::from("pothole")), limit: Some(1), ..EventQuery::default() }), [300]);
+        assert!(query_events(&connection, &EventQuery { limit: Some(MAX_LIMIT + 1), ..EventQuery::default() }).is_err());
+
+        let stored = query_events(&connection, &EventQuery { to_ms: Some(101), ..EventQuery::default() }).unwrap();
+        assert_eq!((stored[0].kind.as_str(), stored[0].latitude, &stored[0].detail), ("request", Some(37.5665), &json!({ "at": 100 })));
+    }
+
+    #[test]
+    fn filters_by_place() {
+        let connection = database();
+        // the second pothole is about 1.1 km north of the first
+        let near = |radius_m| EventQuery { near: Some(Near { latitude: 37.5665, longitude: 126.978, radius_m }), ..EventQuery::default() };
+        assert_eq!(times(&connection, near(100.0)), [100, 300]);
+        assert_eq!(times(&connection, near(1500.0)), [100, 300, 400]);
+        assert!(query_events(&connection, &near(0.0)).is_err());
+
+        let query: EventQuery = serde_json::from_str(r#"{"class": "pothole", "near": {"latitude": 37.5765, "longitude": 126.978, "radius_m": 50}}"#).unwrap();
+        assert_eq!(times(&connection, query), [400]);
+    }
+
+    #[test]
+    fn stores_in_the_background() {
+        let path = std::env::temp_dir().join(format!("vmec-events-test-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = EventStore::open(&path, &HashMap::from([(String::from("truck"), String::from("fleet"))])).unwrap();
+        store.record_alert(&VmecAlertFields {
+            timestamp_ms: 100,
+            device_hash: String::from("truck\n"),
+            kind: cornflakes::AlertKind::HazardAhead,
+            message: String::from("pothole"),
+            detail: String::new(),
+        });
+
+        let mut stored = Vec::new();
+        for _ in 0..100 {
+            stored = store.query(&EventQuery::default()).unwrap();
+            if !stored.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(stored.len(), 1);
+        assert_eq!((stored[0].device_hash.as_str(), stored[0].device_class.as_deref()), ("truck", Some("fleet")));
+        assert_eq!((stored[0].kind.as_str(), stored[0].class.as_str()), ("alert", "hazard_ahead"));
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! A/B experiments: devices are split between arms that run different engines or thresholds, so
+//! that a new model can be compared with the current one on live traffic. A device's arm follows
+//! from a hash of its device hash and the experiment's name, so it stays in the same arm across
+//! sessions and server restarts; devices and classes can also be put in an arm by name.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use cornflakes::ResponseStatus;
+
+use crate::config::ExperimentConfig;
+
+/// Latencies kept for each arm's percentiles
+const LATENCY_WINDOW: usize = 1000;
+
+pub struct Arm {
+    pub name: String,
+    weight: u32,
+    pub front: Option<String>,
+    pub rear: Option<String>,
+    pub threshold: Option<f64>,
+}
+
+pub struct Experiment {
+    pub name: String,
+    // sorted by name, so that assignment does not depend on the order of the config
+    arms: Vec<Arm>,
+    // arm indices, keyed by trimmed device hash
+    devices: HashMap<String, usize>,
+    classes: HashMap<String, usize>,
+}
+
+/// FNV-1a, which unlike the standard library's hasher is the same in every build
+fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl Experiment {
+    pub fn new(config: &ExperimentConfig) -> Result<Self, String> {
+        let mut names: Vec<&String> = config.arms.keys().collect();
+        names.sort();
+        if names.is_empty() {
+            return Err(format!("experiment {} has no arms", config.name));
+        }
+        let index = |arm: &str| names.iter().position(|name| *name == arm).ok_or_else(|| format!("no experiment arm named {}", arm));
+        let devices = config.devices.iter()
+            .map(|(device_hash, arm)| Ok((String::from(device_hash.trim()), index(arm)?)))
+            .collect::<Result<_, String>>()?;
+        let classes = config.classes.iter()
+            .map(|(class, arm)| Ok((class.clone(), index(arm)?)))
+            .collect::<Result<_, String>>()?;
+
+        let arms = names.iter().map(|name| {
+            let arm = &config.arms[*name];
+            Arm { name: String::clone(name), weight: arm.weight, front: arm.front.clone(), rear: arm.rear.clone(), threshold: arm.threshold }
+        }).collect();
+        Ok(Experiment { name: config.name.clone(), arms, devices, classes })
+    }
+
+    pub fn arms(&self) -> &[Arm] {
+        &self.arms
+    }
+
+    /// Index of the device's arm, or None if it is not named anywhere and every arm has weight 0
+    pub fn assign(&self, device_hash: &str, class: Option<&str>) -> Option<usize> {
+        let device_hash = device_hash.trim();
+        if let Some(arm) = self.devices.get(device_hash).or_else(|| class.and_then(|class| self.classes.get(class))) {
+            return Some(*arm);
+        }
+        let total_weight: u64 = self.arms.iter().map(|arm| arm.weight as u64).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let mut bucket = stable_hash(&format!("{}/{}", self.name, device_hash)) % total_weight;
+        self.arms.iter().position(|arm| {
+            if bucket < arm.weight as u64 {
+                return true;
+            }
+            bucket -= arm.weight as u64;
+            false
+        })
+    }
+}
+
+#[derive(Default)]
+struct ArmActivity {
+    devices: HashSet<String>,
+    frames: u64,
+    errors: u64,
+    // the most recent LATENCY_WINDOW latencies
+    latencies: VecDeque<Duration>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArmReport {
+    pub arm: String,
+    pub devices: usize,
+    pub frames: u64,
+    pub errors: u64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+/// Figures for each arm, to compare the arms by
+pub struct ExperimentMetrics {
+    arms: HashMap<String, ArmActivity>,
+}
+
+impl ExperimentMetrics {
+    pub fn new() -> Self {
+        ExperimentMetrics { arms: HashMap::new() }
+    }
+
+    /// Counts a processed frame from a device in the arm
+    pub fn record_frame(&mut self, arm: &str, device_hash: &str, status: ResponseStatus, latency: Duration) {
+        let activity = self.arms.entry(String::from(arm)).or_default();
+        activity.devices.insert(String::from(device_hash.trim()));
+        activity.frames += 1;
+        if status == ResponseStatus::Error {
+            activity.errors += 1;
+        }
+        if activity.latencies.len() == LATENCY_WINDOW {
+            activity.latencies.pop_front();
+        }
+        activity.latencies.push_back(latency);
+    }
+
+    /// Every arm of the experiment, including ones no frame has reached yet
+    pub fn report(&self, experiment: &Experiment) -> Vec<ArmReport> {
+        experiment.arms().iter().map(|arm| {
+            let empty = ArmActivity::default();
+            let activity = self.arms.get(&arm.name).unwrap_or(&empty);
+            let mut latencies: Vec<Duration> = activity.latencies.iter().copied().collect();
+            latencies.sort();
+            let percentile = |p: usize| match latencies.len() {
+                0 => 0.0,
+                n => latencies[(n - 1) * p / 100].as_secs_f64() * 1000.0,
+            };
+            ArmReport {
+                arm: arm.name.clone(),
+                devices: activity.devices.len(),
+                frames: activity.frames,
+                errors: activity.errors,
+                latency_p50_ms: percentile(50),
+                latency_p90_ms: percentile(90),
+                latency_p99_ms: percentile(99),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment(devices: &str) -> Experiment {
+        let config: ExperimentConfig = toml::from_str(&format!(r#"
+            name = "hazards-v2"
+            devices = {{ {} }}
+            classes = {{ fleet = "control" }}
+
+            [arms.control]
+
+            [arms.candidate]
+            front = "hazards-v2"
+            weight = 3
+        "#, devices)).unwrap();
+        Experiment::new(&config).unwrap()
+    }
+
+    #[test]
+    fn assigns_devices_stably_by_weight() {
+        let experiment = experiment("");
+        let assigned: Vec<Option<usize>> = (0..1000).map(|i| experiment.assign(&format!("device-{}", i), None)).collect();
+        assert_eq!(assigned, (0..1000).map(|i| experiment.assign(&format!("device-{}\n", i), None)).collect::<Vec<_>>());
+        // arms are sorted by name, so the candidate is arm 0 and gets about three quarters
+        let candidates = assigned.iter().filter(|arm| **arm == Some(0)).count();
+        assert!((650..850).contains(&candidates), "{} candidates", candidates);
+
+        // another experiment splits the same devices differently
+        let mut renamed = experiment;
+        renamed.name = String::from("hazards-v3");
+        assert_ne!(assigned, (0..1000).map(|i| renamed.assign(&format!("device-{}", i), None)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pins_devices_and_classes() {
+        let experiment = experiment(r#"tester = "candidate""#);
+        assert_eq!(experiment.assign("tester\n", Some("fleet")), Some(0));
+        let fleet = (0..100).map(|i| experiment.assign(&format!("truck-{}", i), Some("fleet")));
+        assert!(fleet.into_iter().all(|arm| arm == Some(1)));
+
+        let config: ExperimentConfig = toml::from_str("name = \"x\"\ndevices = { tester = \"missing\" }\n[arms.control]").unwrap();
+        assert!(Experiment::new(&config).is_err());
+    }
+
+    #[test]
+    fn reports_every_arm() {
+        let experiment = experiment("");
+        let mut metrics = ExperimentMetrics::new();
+        metrics.record_frame("candidate", "bike", ResponseStatus::Ok, Duration::from_millis(10));
+        metrics.record_frame("candidate", "bike\n", ResponseStatus::Error, Duration::from_millis(30));
+        let report = metrics.report(&experiment);
+        assert_eq!((report[0].arm.as_str(), report[0].devices, report[0].frames, report[0].errors), ("candidate", 1, 2, 1));
+        assert_eq!((report[0].latency_p50_ms, report[0].latency_p99_ms), (10.0, 10.0));
+        assert_eq!((report[1].arm.as_str(), report[1].frames), ("control", 0));
+    }
+}
@@ -0,0 +1,388 @@
+//! Crowd-sourced hazard map. Engines report hazards under "hazards" in a camera's output, each with
+//! a "kind" and a "confidence", and every detection from a device that sent its location is put on
+//! the map. Detections of the same kind close together are pooled into one hazard, whose score is
+//! the sum of their confidences, halved every `half_life_s` so that hazards nobody sees anymore
+//! fade away. Devices can ask for the hazards near them in a frame, or be alerted when they get
+//! close to one.
+//!
+//! Hazards are kept in a grid of cells about `CELL_M` across, so that a query only looks at the
+//! cells around it.
+
+use std::collections::{HashMap, HashSet};
+
+use cornflakes::{AlertKind, VmecAlertFields, VmecHazardFields, VmecLocation, VmecRequestFields};
+
+use crate::config::HazardMapConfig;
+
+/// Size of the grid cells, in metres north to south
+const CELL_M: f64 = 100.0;
+
+pub const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Largest radius a device may ask for hazards in
+pub const MAX_NEARBY_RADIUS_M: f64 = 5000.0;
+
+/// Most hazards returned for one query, nearest first
+const MAX_NEARBY: usize = 32;
+
+/// Score below which a hazard is forgotten
+const FORGET_SCORE: f64 = 0.05;
+
+/// Detections between sweeps for forgotten hazards
+const PRUNE_INTERVAL: u64 = 1000;
+
+/// Reporters remembered per hazard; beyond this, hazards count no more
+const MAX_REPORTERS: usize = 64;
+
+struct Hazard {
+    location: VmecLocation,
+    kind: String,
+    // as of last_detected_ms
+    score: f64,
+    last_detected_ms: u64,
+    // trimmed device hashes
+    reporters: HashSet<String>,
+}
+
+pub struct HazardMap {
+    half_life_ms: f64,
+    merge_radius_m: f64,
+    min_confidence: f64,
+    min_score: f64,
+    push_radius_m: f64,
+    push_interval_ms: u64,
+    hazards: HashMap<u64, Hazard>,
+    next_id: u64,
+    cells: HashMap<(i64, i64), Vec<u64>>,
+    detections: u64,
+    // when each device was last alerted about each hazard, keyed by trimmed device hash
+    pushed: HashMap<String, HashMap<u64, u64>>,
+}
+
+/// Checks the location and hazard radius a client sent with a frame, which the map cannot work with
+/// if they are not finite numbers or are off the globe
+pub fn check_request(request: &VmecRequestFields) -> Result<(), String> {
+    if let Some(location) = request.location {
+        if !(-90.0..=90.0).contains(&location.latitude) || !(-180.0..=180.0).contains(&location.longitude) {
+            return Err(String::from("latitude must be within ±90 and longitude within ±180"));
+        }
+    }
+    if !(request.nearby_radius_m >= 0.0 && request.nearby_radius_m.is_finite()) {
+        return Err(String::from("nearby radius must be a finite number of metres, at least 0"));
+    }
+    Ok(())
+}
+
+fn cell(location: VmecLocation) -> (i64, i64) {
+    let cell_deg = (CELL_M / EARTH_RADIUS_M).to_degrees();
+    ((location.latitude / cell_deg).floor() as i64, (location.longitude / cell_deg).floor() as i64)
+}
+
+/// Equirectangular approximation, which is close enough over the distances hazards are looked up in
+pub fn distance_m(a: VmecLocation, b: VmecLocation) -> f64 {
+    let mean_latitude = ((a.latitude + b.latitude) / 2.0).to_radians();
+    let x = (b.longitude - a.longitude).to_radians() * mean_latitude.cos();
+    let y = (b.latitude - a.latitude).to_radians();
+    x.hypot(y) * EARTH_RADIUS_M
+}
+
+/// Hazards an engine reported in a reply's neural output, with their confidence
+pub fn detections_in(neural_output: &str) -> Vec<(String, f64)> {
+    let Ok(serde_json::Value::Object(cameras)) = serde_json::from_str(neural_output) else {
+        return Vec::new();
+    };
+    cameras.values()
+        .flat_map(|output| output["hazards"].as_array().cloned().unwrap_or_default())
+        .filter_map(|hazard| Some((String::from(hazard["kind"].as_str()?), hazard["confidence"].as_f64()?)))
+        .collect()
+}
+
+impl HazardMap {
+    pub fn new(config: &HazardMapConfig) -> Self {
+        HazardMap {
+            half_life_ms: config.half_life_s * 1000.0,
+            merge_radius_m: config.merge_radius_m,
+            min_confidence: config.min_confidence,
+            min_score: config.min_score,
+            push_radius_m: config.push_radius_m,
+            push_interval_ms: config.push_interval_s * 1000,
+            hazards: HashMap::new(),
+            next_id: 0,
+            cells: HashMap::new(),
+            detections: 0,
+            pushed: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hazards.len()
+    }
+
+    fn score(&self, hazard: &Hazard, now_ms: u64) -> f64 {
+        let age_ms = now_ms.saturating_sub(hazard.last_detected_ms) as f64;
+        hazard.score * 0.5f64.powf(age_ms / self.half_life_ms)
+    }
+
+    /// Ids of the hazards within `radius_m`, with their distance
+    fn within(&self, location: VmecLocation, radius_m: f64) -> Vec<(u64, f64)> {
+        let (row, column) = cell(location);
+        let rows = (radius_m / CELL_M).ceil() as i64;
+        // cells get narrower away from the equator
+        let columns = (radius_m / (CELL_M * location.latitude.to_radians().cos().max(0.01))).ceil() as i64;
+        let mut found = Vec::new();
+        for row in row - rows..=row + rows {
+            for column in column - columns..=column + columns {
+                for id in self.cells.get(&(row, column)).into_iter().flatten() {
+                    let distance = distance_m(location, self.hazards[id].location);
+                    if distance <= radius_m {
+                        found.push((*id, distance));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn move_hazard(&mut self, id: u64, from: (i64, i64), to: (i64, i64)) {
+        if from == to {
+            return;
+        }
+        if let Some(ids) = self.cells.get_mut(&from) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                self.cells.remove(&from);
+            }
+        }
+        self.cells.entry(to).or_default().push(id);
+    }
+
+    /// Puts a detection on the map, pooling it with the nearest hazard of the same kind if there is
+    /// one within `merge_radius_m`
+    pub fn record(&mut self, device_hash: &str, location: VmecLocation, kind: &str, confidence: f64, now_ms: u64) {
+        if confidence < self.min_confidence {
+            return;
+        }
+        self.detections += 1;
+        if self.detections.is_multiple_of(PRUNE_INTERVAL) {
+            self.prune(now_ms);
+        }
+
+        let nearest = self.within(location, self.merge_radius_m).into_iter()
+            .filter(|(id, _)| self.hazards[id].kind == kind)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        let Some((id, _)) = nearest else {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.hazards.insert(id, Hazard {
+                location,
+                kind: String::from(kind),
+                score: confidence,
+                last_detected_ms: now_ms,
+                reporters: HashSet::from([String::from(device_hash.trim())]),
+            });
+            self.cells.entry(cell(location)).or_default().push(id);
+            return;
+        };
+
+        let score = self.score(&self.hazards[&id], now_ms);
+        let hazard = self.hazards.get_mut(&id).unwrap();
+        let from = cell(hazard.location);
+        // the more confident the detections, the more they pull the hazard towards themselves
+        let weight = confidence / (score + confidence);
+        hazard.location = VmecLocation {
+            latitude: hazard.location.latitude + (location.latitude - hazard.location.latitude) * weight,
+            longitude: hazard.location.longitude + (location.longitude - hazard.location.longitude) * weight,
+        };
+        hazard.score = score + confidence;
+        hazard.last_detected_ms = hazard.last_detected_ms.max(now_ms);
+        if hazard.reporters.len() < MAX_REPORTERS {
+            hazard.reporters.insert(String::from(device_hash.trim()));
+        }
+        let to = cell(hazard.location);
+        self.move_hazard(id, from, to);
+    }
+
+    /// Puts every hazard the engines reported in a reply on the map
+    pub fn record_output(&mut self, device_hash: &str, location: VmecLocation, neural_output: &str, now_ms: u64) {
+        for (kind, confidence) in detections_in(neural_output) {
+            self.record(device_hash, location, &kind, confidence, now_ms);
+        }
+    }
+
+    fn prune(&mut self, now_ms: u64) {
+        let forgotten: Vec<u64> = self.hazards.iter()
+            .filter(|(_, hazard)| self.score(hazard, now_ms) < FORGET_SCORE)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in forgotten {
+            let hazard = self.hazards.remove(&id).unwrap();
+            let from = cell(hazard.location);
+            if let Some(ids) = self.cells.get_mut(&from) {
+                ids.retain(|other| *other != id);
+                if ids.is_empty() {
+                    self.cells.remove(&from);
+                }
+            }
+        }
+        let push_interval_ms = self.push_interval_ms;
+        for pushed in self.pushed.values_mut() {
+            pushed.retain(|_, pushed_ms| now_ms.saturating_sub(*pushed_ms) < push_interval_ms);
+        }
+        self.pushed.retain(|_, pushed| !pushed.is_empty());
+    }
+
+    fn nearby(&self, location: VmecLocation, radius_m: f64, now_ms: u64) -> Vec<(u64, VmecHazardFields)> {
+        let mut nearby: Vec<(u64, VmecHazardFields)> = self.within(location, radius_m.min(MAX_NEARBY_RADIUS_M)).into_iter()
+            .filter_map(|(id, distance)| {
+                let hazard = &self.hazards[&id];
+                let score = self.score(hazard, now_ms);
+                (score >= self.min_score).then(|| (id, VmecHazardFields {
+                    location: hazard.location,
+                    kind: hazard.kind.clone(),
+                    score: score as f32,
+                    reporters: hazard.reporters.len() as u32,
+                    last_detected_ms: hazard.last_detected_ms,
+                    distance_m: distance as f32,
+                }))
+            })
+            .collect();
+        nearby.sort_by(|(_, a), (_, b)| a.distance_m.total_cmp(&b.distance_m));
+        nearby.truncate(MAX_NEARBY);
+        nearby
+    }
+
+    /// Hazards within `radius_m` that enough detections back up, nearest first
+    pub fn near(&self, location: VmecLocation, radius_m: f64, now_ms: u64) -> Vec<VmecHazardFields> {
+        self.nearby(location, radius_m, now_ms).into_iter().map(|(_, hazard)| hazard).collect()
+    }
+
+    /// Alerts for the hazards within `push_radius_m` of the device that others detected, each at
+    /// most once per `push_interval_s`
+    pub fn pushes(&mut self, device_hash: &str, location: VmecLocation, now_ms: u64) -> Vec<VmecAlertFields> {
+        if self.push_radius_m <= 0.0 {
+            return Vec::new();
+        }
+        let device_hash = device_hash.trim();
+        let nearby = self.nearby(location, self.push_radius_m, now_ms);
+        let pushed = self.pushed.entry(String::from(device_hash)).or_default();
+        let mut alerts = Vec::new();
+        for (id, hazard) in nearby {
+            let reporters = &self.hazards[&id].reporters;
+            if reporters.len() == 1 && reporters.contains(device_hash) {
+                continue;
+            }
+            if pushed.get(&id).is_some_and(|pushed_ms| now_ms.saturating_sub(*pushed_ms) < self.push_interval_ms) {
+                continue;
+            }
+            pushed.insert(id, now_ms);
+            alerts.push(VmecAlertFields {
+                timestamp_ms: now_ms,
+                device_hash: String::from(device_hash),
+                kind: AlertKind::HazardAhead,
+                message: format!("{} in {:.0} m", hazard.kind, hazard.distance_m),
+                detail: serde_json::json!({
+                    "kind": hazard.kind,
+                    "latitude": hazard.location.latitude,
+                    "longitude": hazard.location.longitude,
+                    "distance_m": hazard.distance_m,
+                    "reporters": hazard.reporters,
+                }).to_string(),
+            });
+        }
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 3600 * 1000;
+
+    fn config() -> HazardMapConfig {
+        toml::from_str("half_life_s = 3600.0").unwrap()
+    }
+
+    /// About `north_m` metres north and `east_m` metres east of a point in Seoul
+    fn at(north_m: f64, east_m: f64) -> VmecLocation {
+        let origin = VmecLocation { latitude: 37.5665, longitude: 126.978 };
+        VmecLocation {
+            latitude: origin.latitude + (north_m / EARTH_RADIUS_M).to_degrees(),
+            longitude: origin.longitude + (east_m / (EARTH_RADIUS_M * origin.latitude.to_radians().cos())).to_degrees(),
+        }
+    }
+
+    #[test]
+    fn measures_distances() {
+        assert!((distance_m(at(0.0, 0.0), at(300.0, 400.0)) - 500.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn rejects_locations_off_the_globe() {
+        let request = |latitude: f64, longitude: f64, nearby_radius_m: f32| VmecRequestFields {
+            location: Some(VmecLocation { latitude, longitude }),
+            nearby_radius_m,
+            ..Default::default()
+        };
+        assert!(check_request(&VmecRequestFields::default()).is_ok());
+        assert!(check_request(&request(-90.0, 180.0, 300.0)).is_ok());
+        assert!(check_request(&request(1e300, 0.0, 0.0)).is_err());
+        assert!(check_request(&request(0.0, -180.5, 0.0)).is_err());
+        assert!(check_request(&request(f64::NAN, 0.0, 0.0)).is_err());
+        assert!(check_request(&request(0.0, f64::INFINITY, 0.0)).is_err());
+        assert!(check_request(&request(0.0, 0.0, f32::NAN)).is_err());
+        assert!(check_request(&request(0.0, 0.0, -1.0)).is_err());
+    }
+
+    #[test]
+    fn pools_nearby_detections() {
+        let mut map = HazardMap::new(&config());
+        map.record("bike-1", at(0.0, 0.0), "pothole", 0.8, 0);
+        map.record("bike-2", at(5.0, 5.0), "pothole", 0.8, 0);
+        map.record("bike-2", at(5.0, 5.0), "glass", 0.8, 0);
+        map.record("bike-3", at(500.0, 0.0), "pothole", 0.8, 0);
+        map.record("bike-3", at(1000.0, 0.0), "pothole", 0.1, 0);
+        assert_eq!(map.len(), 3);
+
+        // only the pooled pothole is backed up by enough detections
+        let near = map.near(at(0.0, 100.0), 1000.0, 0);
+        assert_eq!(near.len(), 1);
+        assert_eq!((near[0].kind.as_str(), near[0].reporters), ("pothole", 2));
+        assert!((near[0].score - 1.6).abs() < 1e-6);
+        assert!((near[0].distance_m - 97.0).abs() < 1.0, "{}", near[0].distance_m);
+        assert!(map.near(at(0.0, 100.0), 50.0, 0).is_empty());
+    }
+
+    #[test]
+    fn decays_with_time() {
+        let mut map = HazardMap::new(&config());
+        map.record("bike-1", at(0.0, 0.0), "pothole", 1.0, 0);
+        map.record("bike-2", at(0.0, 0.0), "pothole", 1.0, 0);
+        assert_eq!(map.near(at(0.0, 0.0), 10.0, HOUR_MS)[0].score, 1.0);
+        assert!(map.near(at(0.0, 0.0), 10.0, 2 * HOUR_MS).is_empty());
+
+        // seen again, so it is back
+        map.record("bike-3", at(0.0, 0.0), "pothole", 1.0, 2 * HOUR_MS);
+        assert_eq!(map.near(at(0.0, 0.0), 10.0, 2 * HOUR_MS)[0].score, 1.5);
+
+        map.prune(20 * HOUR_MS);
+        assert_eq!(map.len(), 0);
+        assert!(map.cells.is_empty());
+    }
+
+    #[test]
+    fn pushes_hazards_others_reported() {
+        let mut map = HazardMap::new(&config());
+        map.record("bike-1", at(0.0, 0.0), "pothole", 1.0, 0);
+        map.record("bike-1", at(0.0, 0.0), "pothole", 1.0, 0);
+        assert!(map.pushes("bike-1", at(50.0, 0.0), 0).is_empty());
+
+        let alerts = map.pushes("bike-2\n", at(50.0, 0.0), 0);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].kind, alerts[0].message.as_str()), (AlertKind::HazardAhead, "pothole in 50 m"));
+        // once per interval
+        assert!(map.pushes("bike-2", at(40.0, 0.0), 1000).is_empty());
+        assert_eq!(map.pushes("bike-2", at(40.0, 0.0), 600 * 1000).len(), 1);
+        assert!(map.pushes("bike-3", at(500.0, 0.0), 0).is_empty());
+    }
+}
@@ -1,18 +1,76 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use zmq;
+use clap::{Parser, Subcommand};
+use log::{debug, info, warn, LevelFilter};
 
+mod admin;
+mod alerts;
+mod admission;
+mod archive;
+mod auth;
+mod batch;
+mod cache;
+mod config;
+mod engine;
+mod events;
+mod experiment;
+mod hazards;
+mod mock;
+mod queue;
+mod ratelimit;
+mod reload;
+mod replay;
+mod session;
+mod shadow;
+mod stats;
+mod transport;
+mod upstream;
+
+use admin::{Admin, Controls};
+use alerts::AlertPublisher;
+use admission::{Admission, AdmissionController};
+use auth::Authenticator;
+use archive::{Archive, ArchiveConfig, ArchiveRecord, ArchiveSampler};
+use batch::Batcher;
+use cache::ResponseCache;
+use config::ServerConfig;
+use engine::{Engine, EngineRouter};
+use experiment::ExperimentMetrics;
+use events::EventStore;
+use hazards::HazardMap;
+use mock::{Fault, FaultConfig, FaultInjector, LatencyDistribution};
+use queue::{PendingRequest, QueuePolicy, RequestQueue};
+use ratelimit::RateLimiter;
+use reload::ModelReloader;
+use session::Handover;
+use shadow::Shadow;
+use stats::{DeviceStats, ServerStats};
+use transport::Seal;
+use upstream::{Forwarded, Upstream};
+
+use cornflakes::validation::ReplayGuard;
 use cornflakes::{
-    capnp_bytes_io,
+    recording,
+    CameraDirection,
+    ErrorCode,
+    ResponseStatus,
+    VmecAlertFields,
     VmecRequestFields,
+    VmecHopFields,
+    VmecRequestKind,
     VmecResponseFields,
-    vmec_request_capnp,
-    vmec_response_capnp,
     vmec_request_transport,
     vmec_response_transport,
 };
 
+const SERVER_HASH: &str = "server_hash blah blash";
+
+const DISCONNECTED_MESSAGE: &str = "disconnected by the server's operator";
+
 fn ms_now() -> u64 {
     let now = std::time::SystemTime::now();
     let since_the_epoch = now
@@ -22,38 +80,800 @@ fn ms_now() -> u64 {
     in_ms as u64
 }
 
-fn process_request(request: &VmecRequestFields) -> VmecResponseFields {
-    // mock function for server-side work
+/// Runs each camera's image through the engine routed to it, and merges the outputs by camera
+fn process_request(request: &VmecRequestFields, router: &EngineRouter) -> VmecResponseFields {
+    process_batch(&[request], router).pop().unwrap()
+}
+
+/// Runs frames from any number of devices, with one engine call for all the images routed to the
+/// same engine; the replies are in the same order as the frames
+fn process_batch(requests: &[&VmecRequestFields], router: &EngineRouter) -> Vec<VmecResponseFields> {
+    // the images routed to each engine, with the frame and the camera they came from
+    let mut groups: Vec<(&Engine, Vec<_>)> = Vec::new();
+    let mut unrouted: Vec<Option<CameraDirection>> = vec![None; requests.len()];
+    for (index, request) in requests.iter().enumerate() {
+        let images = request.images();
+        // a frame with an image that has to go upstream is not run here at all
+        if let Some((camera, _)) = images.iter().find(|(camera, _)| router.route(&request.device_hash, *camera).is_none()) {
+            unrouted[index] = Some(*camera);
+            continue;
+        }
+        for (slot, (camera, image)) in images.into_iter().enumerate() {
+            let engine = router.route(&request.device_hash, camera).unwrap();
+            match groups.iter_mut().find(|(grouped, _)| grouped.name == engine.name) {
+                Some((_, images)) => images.push((index, slot, image)),
+                None => groups.push((engine, vec![(index, slot, image)])),
+            }
+        }
+    }
+
+    let mut outputs: Vec<Vec<_>> = requests.iter().map(|_| Vec::new()).collect();
+    for (engine, images) in &groups {
+        let inputs: Vec<_> = images.iter().map(|&(index, _, image)| (image, router.threshold(&requests[index].device_hash))).collect();
+        for (&(index, slot, _), output) in images.iter().zip(engine.infer_batch(&inputs)) {
+            outputs[index].push((slot, *engine, output));
+        }
+    }
+
+    requests
+        .iter()
+        .zip(unrouted)
+        .zip(outputs)
+        .map(|((request, unrouted), mut outputs)| {
+            if let Some(camera) = unrouted {
+                let message = format!("the model for the {} camera is only on the upstream server", engine::camera_name(camera));
+                return error_response(ErrorCode::UpstreamUnavailable, &message);
+            }
+            outputs.sort_by_key(|(slot, _, _)| *slot);
+            let mut merged = serde_json::Map::new();
+            let mut versions = Vec::new();
+            for (slot, engine, output) in outputs {
+                let camera = engine::camera_name(request.images()[slot].0);
+                match output {
+                    Ok(output) => {
+                        versions.push(format!("{}:{}@{}", camera, engine.name, engine.version));
+                        merged.insert(String::from(camera), output);
+                    }
+                    Err(e) => return error_response(ErrorCode::InvalidImage, &format!("{} camera: {}", camera, e)),
+                }
+            }
+
+            // mock function for server-side work
+            VmecResponseFields {
+                timestamp_ms: ms_now(),
+                server_hash: String::from(SERVER_HASH),
+                response_hash: String::from("respond_hash"),
+                neural_output: serde_json::Value::Object(merged).to_string(),
+                status: ResponseStatus::Ok,
+                retry_after_ms: 0,
+                model_version: versions.join(" "),
+                experiment_arm: router.arm(&request.device_hash).map(String::from).unwrap_or_default(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Reply for a frame that was not (fully) served, e.g. because the server is busy
+fn status_response(status: ResponseStatus, retry_after_ms: u32) -> VmecResponseFields {
     VmecResponseFields {
         timestamp_ms: ms_now(),
-        server_hash: String::from("server_hash blah blash"),
-        response_hash: String::from("respond_hash"),
-        neural_output: String::from("neural_output"),
+        server_hash: String::from(SERVER_HASH),
+        status,
+        retry_after_ms,
+        ..Default::default()
     }
 }
 
+/// Reply for a frame that could not be processed
+fn error_response(error_code: ErrorCode, error_message: &str) -> VmecResponseFields {
+    VmecResponseFields {
+        timestamp_ms: ms_now(),
+        server_hash: String::from(SERVER_HASH),
+        status: ResponseStatus::Error,
+        error_code,
+        error_message: String::from(error_message),
+        ..Default::default()
+    }
+}
+
+/// Sends the alert to its device, and keeps it in the event store
+fn publish_alert(alerts: &AlertPublisher, events: Option<&EventStore>, alert: VmecAlertFields) {
+    if let Some(events) = events {
+        events.record_alert(&alert);
+    }
+    alerts.publish(alert);
+}
+
+/// Timing of a frame on this server, for the reply's list of hops
+fn hop(server_name: &str, received: Instant) -> VmecHopFields {
+    VmecHopFields {
+        server: String::from(server_name),
+        elapsed_us: received.elapsed().as_micros() as u64,
+    }
+}
+
+fn send_reply(responder: &zmq::Socket, identity: &[u8], seal: Option<&Seal>, reply: Vec<u8>) {
+    let reply = match seal {
+        Some(seal) => seal.seal(&reply),
+        None => reply,
+    };
+    // REQ clients expect an empty delimiter frame between the envelope and the body
+    responder.send_multipart([identity, &[][..], &reply[..]], 0).unwrap();
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long, default_value="5555")]
+    port: u16,
+
+    #[arg(long)]
+    /// Name of this server in the hops listed in replies; "vmec-server:<port>" when not given
+    name: Option<String>,
+
+    #[arg(long, default_value="info")]
+    /// Least severe messages to log: error, warn, info, debug or trace; can be changed at runtime
+    /// through the admin interface
+    log_level: LevelFilter,
+
+    #[arg(long)]
+    /// ZMQ address to take admin commands on, e.g. "tcp://127.0.0.1:5560"; see `admin`. Keep it
+    /// local, as commands are not authenticated.
+    admin_address: Option<String>,
+
+    #[arg(long)]
+    /// Port to publish alerts to devices on; see `alerts`. No alerts are sent without it.
+    alert_port: Option<u16>,
+
+    #[arg(long)]
+    /// SQLite database to keep requests, detections and alerts in, for review later; see `events`
+    event_db: Option<PathBuf>,
+
+    #[arg(long)]
+    /// TOML file with device credentials, engines and other settings; see server.example.toml
+    config: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Decode and letterbox both images of each frame to this square size before running the mock
+    /// engine, as a real model would; frames whose images are not JPEGs get an error reply. Engines
+    /// in the config may set their own size.
+    model_input_size: Option<u32>,
+
+    #[arg(long, default_value="0")]
+    /// How often to check engines' model files for changes, in ms, and reload those that changed;
+    /// 0 turns watching off. Models can also be reloaded through the admin interface.
+    model_watch_interval_ms: u64,
+
+    #[arg(long, default_value="4")]
+    /// Frames in flight at which clients are asked to reduce their rate
+    soft_in_flight_limit: usize,
+
+    #[arg(long, default_value="16")]
+    /// Frames in flight at which new frames are rejected as busy
+    hard_in_flight_limit: usize,
+
+    #[arg(long, value_enum, default_value="fifo")]
+    /// Order in which queued frames are processed
+    queue_policy: QueuePolicy,
+
+    #[arg(long, default_value="1")]
+    /// Most frames run through the engine in one call, from any number of devices
+    max_batch_size: usize,
+
+    #[arg(long, default_value="0")]
+    /// How long the oldest queued frame may wait for a batch to fill up, in ms
+    max_batch_wait_ms: u64,
+
+    #[arg(long)]
+    /// Directory to archive received frames in; archival is off when not given
+    archive_dir: Option<PathBuf>,
+
+    #[arg(long, default_value="1.0")]
+    /// Fraction of frames to archive, 0.0 to 1.0
+    archive_sample_rate: f64,
+
+    #[arg(long, default_value="30")]
+    /// Days to keep archived frames for
+    archive_retention_days: u64,
+
+    #[arg(long, default_value="10240")]
+    /// Disk space the archive may use, in MiB; oldest frames are deleted beyond this
+    archive_max_mib: u64,
+
+    #[arg(long)]
+    /// Append every received frame request to this file, for later use with `replay`
+    record_requests: Option<PathBuf>,
+
+    #[arg(long, default_value="256")]
+    /// Replies kept to answer resent frames without processing them again; 0 disables the cache
+    response_cache_size: usize,
+
+    #[arg(long, value_enum, default_value="fixed")]
+    /// Distribution the mock engine's processing time is drawn from
+    mock_latency: LatencyDistribution,
+
+    #[arg(long, default_value="1.0")]
+    /// Mean processing time of the mock engine, in ms
+    mock_latency_ms: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Half-width of a uniform, or standard deviation of a normal, latency distribution, in ms
+    mock_latency_spread_ms: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Up to this many ms of uniformly random latency added to every frame
+    mock_jitter_ms: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Processing time the mock engine adds to a batch for each frame after the first, in ms
+    mock_batch_item_ms: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Fraction of frames that get no reply at all
+    mock_drop_rate: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Fraction of frames that get a reply that does not decode
+    mock_malformed_rate: f64,
+
+    #[arg(long, default_value="0.0")]
+    /// Fraction of frames that get an error reply
+    mock_error_rate: f64,
+
+    #[arg(long)]
+    /// Seed for the mock engine's latencies and faults, to reproduce a run
+    mock_seed: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run recorded requests through process_request offline and write the responses and timings
+    Replay {
+        #[arg(long)]
+        /// Recording made with --record-requests
+        input: PathBuf,
+
+        #[arg(long)]
+        /// File to write one JSON line per replayed frame to
+        output: PathBuf,
+    },
+    /// Print a new random device key, for the server config and the client's --device-key file
+    Keygen,
+    /// Send a JSON command to a running server's admin interface and print the answer, e.g.
+    /// '{"command": "devices"}'
+    Admin {
+        #[arg(long, default_value="tcp://127.0.0.1:5560")]
+        /// The server's --admin-address
+        address: String,
+
+        command: String,
+    },
+}
+
 fn main() {
+    let mut args = Args::parse();
+    if let Some(Command::Keygen) = args.command {
+        println!("{}", cornflakes::auth::DeviceKey::from_bytes(rand::random()).to_hex());
+        return;
+    }
+    if let Some(Command::Admin { address, command }) = &args.command {
+        match admin::send_command(address, command, 2000) {
+            Ok(answer) => println!("{}", answer),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    simple_logging::log_to(std::io::stdout(), args.log_level);
+
+    let config = match &args.config {
+        Some(path) => ServerConfig::load(path).expect("Could not read server config"),
+        None => ServerConfig::default(),
+    };
+    let router = EngineRouter::new(&config, args.model_input_size).expect("Invalid engine config");
+
+    match args.command.take() {
+        Some(Command::Replay { input, output }) => replay::run(&input, &output, &router).expect("Replay failed"),
+        _ => serve(args, config, router),
+    }
+}
+
+fn serve(args: Args, config: ServerConfig, mut router: EngineRouter) {
+    let admission = AdmissionController::new(args.soft_in_flight_limit, args.hard_in_flight_limit).expect("Invalid in-flight limits");
     let context = zmq::Context::new();
-    let responder = context.socket(zmq::REP).unwrap();
-    assert!(responder.bind("tcp://*:5555").is_ok());
+    // ROUTER rather than REP, so that frames queued behind the one being processed can be seen and refused
+    let responder = context.socket(zmq::ROUTER).unwrap();
+    assert!(responder.bind(&format!("tcp://*:{}", args.port)).is_ok());
+
+    info!("Engines: {}", router.engine_names().collect::<Vec<_>>().join(", "));
+    let server_name = args.name.clone().unwrap_or_else(|| format!("vmec-server:{}", args.port));
+    let mut upstream = config.upstream.as_ref().map(|upstream_config| {
+        info!("Forwarding to {}{}", upstream_config.address, if upstream_config.when_busy { ", also when busy" } else { "" });
+        Upstream::new(&context, upstream_config)
+    });
+    let mut handover = config.handover.as_ref().map(|handover_config| {
+        let mut peers: Vec<&str> = handover_config.peers.keys().map(String::as_str).collect();
+        peers.sort();
+        info!("Handing device sessions over with {}", peers.join(", "));
+        Handover::new(&context, &server_name, handover_config).expect("Invalid handover config")
+    });
+    let forward_when_busy = config.upstream.as_ref().is_some_and(|upstream_config| upstream_config.when_busy);
+    let authenticator = Authenticator::new(&config.auth).expect("Invalid auth config");
+    if authenticator.required() {
+        info!("Accepting signed requests from {} devices ({} revoked)", config.auth.devices.len(), config.auth.revoked.len());
+    }
+    let mut replay_guard = config.replay_protection.required.then(|| {
+        info!("Rejecting requests more than {} ms off the server clock, or with reused sequence numbers", config.replay_protection.max_clock_skew_ms);
+        ReplayGuard::new(config.replay_protection.max_clock_skew_ms)
+    });
+    let mut rate_limiter = config.rate_limit.as_ref().map(|rate_limit| {
+        info!("Rate limiting frames ({} device classes)", rate_limit.classes.len());
+        RateLimiter::new(rate_limit, &config.device_classes)
+    });
+    let require_encryption = config.transport.require_encryption;
+    if require_encryption {
+        info!("Accepting sealed requests only");
+    }
+
+    let mut stats = ServerStats::new();
+    let mut devices = DeviceStats::new();
+    let watch_interval = (args.model_watch_interval_ms > 0).then(|| Duration::from_millis(args.model_watch_interval_ms));
+    if let Some(watch_interval) = watch_interval {
+        info!("Checking model files for changes every {} ms", watch_interval.as_millis());
+    }
+    let mut experiment_metrics = ExperimentMetrics::new();
+    if let Some(experiment) = router.experiment() {
+        let arms: Vec<&str> = experiment.arms().iter().map(|arm| arm.name.as_str()).collect();
+        info!("Running experiment {} with arms {}", experiment.name, arms.join(", "));
+    }
+    let mut reloader = ModelReloader::spawn(&context, router.engines(), watch_interval);
+    let mut shadow = config.shadow.as_ref().map(|shadow_config| {
+        info!("Mirroring {:.0}% of frames to candidate engines, logging to {}", shadow_config.sample_rate * 100.0, shadow_config.log.display());
+        Shadow::spawn(shadow_config, &router).expect("Invalid shadow config")
+    });
+    let mut alerts = args.alert_port.map(|alert_port| {
+        info!("Publishing alerts on port {}", alert_port);
+        AlertPublisher::bind(&context, &format!("tcp://*:{}", alert_port), require_encryption)
+    });
+    let mut hazard_map = config.hazard_map.as_ref().map(|hazard_map_config| {
+        info!("Mapping hazards, with scores halving every {} s", hazard_map_config.half_life_s);
+        if hazard_map_config.push_radius_m > 0.0 && alerts.is_none() {
+            warn!("Not alerting devices to nearby hazards without --alert-port");
+        }
+        HazardMap::new(hazard_map_config)
+    });
+    let events = args.event_db.as_ref().map(|path| {
+        info!("Storing events in {}", path.display());
+        EventStore::open(path, &config.device_classes).expect("Could not open event store")
+    });
+    let mut admin = args.admin_address.as_ref().map(|address| {
+        info!("Taking admin commands on {}", address);
+        Admin::bind(&context, address)
+    });
+    let mut pending = RequestQueue::new(args.queue_policy)
+        .with_weights(&config.fair_queue, &config.device_classes)
+        .expect("Invalid fair queue config");
+    let mut cache = ResponseCache::new(args.response_cache_size);
+    let batcher = Batcher::new(args.max_batch_size, Duration::from_millis(args.max_batch_wait_ms));
+    if batcher.max_batch_size() > 1 {
+        info!("Batching up to {} frames, waiting at most {} ms", batcher.max_batch_size(), args.max_batch_wait_ms);
+    }
+
+    let archiver = args.archive_dir.map(|root| {
+        let root_display = root.display().to_string();
+        let archive = Archive::open(ArchiveConfig {
+            root,
+            retention_days: args.archive_retention_days,
+            max_bytes: args.archive_max_mib * 1024 * 1024,
+        }).expect("Could not open frame archive");
+        info!("Archiving frames to {} ({} MiB in use)", root_display, archive.usage_bytes() / 1024 / 1024);
+        archive::spawn_archiver(archive)
+    });
+    let mut archive_sampler = ArchiveSampler::new(args.archive_sample_rate);
+
+    let mut faults = FaultInjector::new(FaultConfig {
+        latency: args.mock_latency,
+        latency_ms: args.mock_latency_ms,
+        latency_spread_ms: args.mock_latency_spread_ms,
+        jitter_ms: args.mock_jitter_ms,
+        batch_item_ms: args.mock_batch_item_ms,
+        drop_rate: args.mock_drop_rate,
+        malformed_rate: args.mock_malformed_rate,
+        error_rate: args.mock_error_rate,
+        seed: args.mock_seed,
+    }).expect("Invalid mock engine settings");
+
+    let mut recorder = args.record_requests.map(|path| {
+        let file = OpenOptions::new().create(true).append(true).open(path).expect("Could not open request recording");
+        BufWriter::new(file)
+    });
 
     loop {
-        let byte_msg = responder.recv_bytes(0).unwrap();
+        // wait for a message only when there is nothing left to work on, or while a batch fills up
+        let batch_wait = batcher.wait(pending.len(), pending.oldest_received(), Instant::now());
+        if pending.is_empty() || batch_wait.is_some() {
+            let timeout_ms = batch_wait.map_or(-1, |wait| wait.as_micros().div_ceil(1000) as i64);
+            let mut poll_items = vec![responder.as_poll_item(zmq::POLLIN), reloader.socket().as_poll_item(zmq::POLLIN)];
+            if let Some(upstream) = &upstream {
+                poll_items.push(upstream.results_socket().as_poll_item(zmq::POLLIN));
+            }
+            if let Some(handover) = &handover {
+                poll_items.push(handover.results_socket().as_poll_item(zmq::POLLIN));
+            }
+            if let Some(admin) = &admin {
+                poll_items.push(admin.socket().as_poll_item(zmq::POLLIN));
+            }
+            zmq::poll(&mut poll_items, timeout_ms).unwrap();
+        }
+        while let Ok(mut parts) = responder.recv_multipart(zmq::DONTWAIT) {
+            let received = Instant::now();
+            if parts.len() != 3 {
+                warn!("Dropping message with {} parts", parts.len());
+                continue;
+            }
+            let byte_msg = parts.pop().unwrap();
+            let identity = parts.swap_remove(0);
+
+            // server
+            let mut request_received: Vec<u8> = byte_msg;
+            let mut seal: Option<Seal> = None;
+            let mut request_kind = match vmec_request_transport::decode_request_kind(&request_received) {
+                Ok(request_kind) => request_kind,
+                Err(e) => {
+                    warn!("Rejecting request that could not be decoded: {}", e);
+                    let reply = error_response(ErrorCode::MalformedRequest, "request could not be decoded");
+                    send_reply(&responder, &identity, None, vmec_response_transport::encode_response(reply).unwrap());
+                    continue;
+                }
+            };
+            // from a peer server rather than a device, so none of the checks on device requests apply
+            if let VmecRequestKind::SessionPull(pull) = &request_kind {
+                info!("Peer asked for the session of {}", pull.device_hash.trim());
+                let reply = match handover.as_mut() {
+                    Some(handover) => handover.answer(pull, ms_now()),
+                    None => vmec_response_transport::encode_session(None).unwrap(),
+                };
+                send_reply(&responder, &identity, None, reply);
+                continue;
+            }
+            if let VmecRequestKind::Sealed(sealed) = &request_kind {
+                match transport::open(sealed, &authenticator) {
+                    Ok(opened) => {
+                        request_kind = opened.kind;
+                        request_received = opened.encoded;
+                        seal = Some(opened.seal);
+                    }
+                    Err(failure) => {
+                        warn!("Rejecting sealed request from {}: {}", sealed.device_hash.trim(), failure.message());
+                        let reply = error_response(failure.error_code(), failure.message());
+                        send_reply(&responder, &identity, None, vmec_response_transport::encode_response(reply).unwrap());
+                        continue;
+                    }
+                }
+            } else if require_encryption {
+                warn!("Rejecting plaintext request");
+                let reply = error_response(ErrorCode::EncryptionRequired, "requests must be sealed");
+                send_reply(&responder, &identity, None, vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+
+            let parsed_request: VmecRequestFields = match request_kind {
+                VmecRequestKind::Frame(parsed_request) => parsed_request,
+                VmecRequestKind::Heartbeat(heartbeat) => {
+                    debug!("Heartbeat {} from {}", heartbeat.sequence, heartbeat.device_hash.trim());
+                    if let Err(failure) = authenticator.check_heartbeat(&heartbeat) {
+                        warn!("Rejecting heartbeat from {}: {}", heartbeat.device_hash.trim(), failure.message());
+                        let reply = error_response(failure.error_code(), failure.message());
+                        send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                        continue;
+                    }
+                    devices.record_seen(&heartbeat.device_hash, ms_now());
+                    if admin.as_mut().is_some_and(|admin| admin.is_disconnected(&heartbeat.device_hash, Instant::now())) {
+                        debug!("Refusing heartbeat from disconnected {}", heartbeat.device_hash.trim());
+                        let reply = error_response(ErrorCode::Disconnected, DISCONNECTED_MESSAGE);
+                        send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                        continue;
+                    }
+                    if let Some(Err(violation)) = replay_guard.as_mut().map(|guard| guard.check_heartbeat(&heartbeat, ms_now())) {
+                        warn!("Rejecting heartbeat from {}: {}", heartbeat.device_hash.trim(), violation.message());
+                        let reply = error_response(violation.error_code(), &violation.message());
+                        send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                        continue;
+                    }
+                    if let Some(handover) = &mut handover {
+                        handover.sessions.record_heartbeat(&heartbeat, ms_now());
+                    }
+                    let health = stats.health_report(ms_now(), SERVER_HASH, pending.len());
+                    send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_health(health).unwrap());
+                    continue;
+                }
+                // transport::open never yields another sealed request, and session pulls were answered above
+                VmecRequestKind::Sealed(_) | VmecRequestKind::SessionPull(_) => unreachable!(),
+            };
+            debug!("Received");
+
+            if let Err(failure) = authenticator.check_frame(&parsed_request) {
+                warn!("Rejecting frame from {}: {}", parsed_request.device_hash.trim(), failure.message());
+                let reply = error_response(failure.error_code(), failure.message());
+                send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+            devices.record_seen(&parsed_request.device_hash, ms_now());
+            if admin.as_mut().is_some_and(|admin| admin.is_disconnected(&parsed_request.device_hash, Instant::now())) {
+                debug!("Refusing frame from disconnected {}", parsed_request.device_hash.trim());
+                let reply = error_response(ErrorCode::Disconnected, DISCONNECTED_MESSAGE);
+                send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+
+            // the hazard map and the event store must not get garbage coordinates
+            if let Err(e) = hazards::check_request(&parsed_request) {
+                warn!("Rejecting frame from {}: {}", parsed_request.device_hash.trim(), e);
+                devices.record_frame(&parsed_request.device_hash, ResponseStatus::Error, received.elapsed(), ms_now());
+                let reply = error_response(ErrorCode::InvalidRequest, &e);
+                send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+
+            // the device has moved here from a peer; its session follows
+            if let Some(handover) = &mut handover {
+                if let Some(peer) = handover.pull(&parsed_request.device_hash, &parsed_request.session_token, ms_now()) {
+                    info!("Taking over the session of {} from {}", parsed_request.device_hash.trim(), peer);
+                }
+            }
+
+            if let Some(recorder) = &mut recorder {
+                recording::write_record(recorder, &request_received).unwrap();
+                recorder.flush().unwrap();
+            }
+
+            // a client that timed out resends the same frame; don't process it twice
+            if let Some(cached) = cache.get(&parsed_request.device_hash, &parsed_request.request_hash) {
+                debug!("Answering resent frame {} from cache", parsed_request.request_hash);
+                stats.record_cache_hit();
+                send_reply(&responder, &identity, seal.as_ref(), cached.clone());
+                continue;
+            }
+            if pending.redirect(&parsed_request.device_hash, &parsed_request.request_hash, &identity) {
+                debug!("Frame {} resent while still queued", parsed_request.request_hash);
+                continue;
+            }
+            if upstream.as_mut().is_some_and(|upstream| upstream.redirect(&parsed_request.device_hash, &parsed_request.request_hash, &identity)) {
+                debug!("Frame {} resent while still forwarded upstream", parsed_request.request_hash);
+                continue;
+            }
 
-        //println!("Received request: [{:?}]", byte_msg);
-        println!("Received");
-        // server
-        let request_received: &[u8] = &byte_msg;
-        let parsed_request: VmecRequestFields = vmec_request_transport::decode_request(request_received).unwrap();
-        //println!("Parsed request image data: {:?}", parsed_request.image_front);
-        let response_vals: VmecResponseFields = process_request(&parsed_request);
-        let response_to_send = vmec_response_transport::encode_response(response_vals).unwrap();
+            // after the cache, so that a resent frame is answered rather than taken for a replay
+            if let Some(Err(violation)) = replay_guard.as_mut().map(|guard| guard.check_frame(&parsed_request, ms_now())) {
+                warn!("Rejecting frame from {}: {}", parsed_request.device_hash.trim(), violation.message());
+                devices.record_frame(&parsed_request.device_hash, ResponseStatus::Error, received.elapsed(), ms_now());
+                let reply = error_response(violation.error_code(), &violation.message());
+                send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+
+            if let Some(Err(retry_after_ms)) = rate_limiter.as_mut().map(|limiter| limiter.check(&parsed_request.device_hash, Instant::now())) {
+                info!("Rate limiting {}; asking it to retry after {} ms", parsed_request.device_hash.trim(), retry_after_ms);
+                devices.record_frame(&parsed_request.device_hash, ResponseStatus::RateLimited, received.elapsed(), ms_now());
+                let reply = status_response(ResponseStatus::RateLimited, retry_after_ms);
+                send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                continue;
+            }
+
+            // frames that need a model this server lacks, or that it is too busy for, go upstream
+            let decision = admission.decide(pending.len(), stats.mean_processing_time());
+            let needs_upstream = router.forwards(&parsed_request.device_hash);
+            if needs_upstream || (forward_when_busy && matches!(decision, Admission::Reject { .. })) {
+                if let Some(upstream) = upstream.as_mut() {
+                    if upstream.has_capacity() {
+                        debug!("Forwarding frame {} to {}", parsed_request.request_hash, upstream.address());
+                        upstream.forward(request_received, Forwarded {
+                            identity,
+                            device_hash: parsed_request.device_hash,
+                            seal,
+                            request_hash: parsed_request.request_hash,
+                            location: parsed_request.location,
+                            received,
+                        });
+                        continue;
+                    }
+                    if needs_upstream {
+                        warn!("Too many frames waiting on {}; asking client to retry", upstream.address());
+                        devices.record_frame(&parsed_request.device_hash, ResponseStatus::Busy, received.elapsed(), ms_now());
+                        let busy = status_response(ResponseStatus::Busy, config.upstream.as_ref().unwrap().timeout_ms as u32);
+                        send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(busy).unwrap());
+                        continue;
+                    }
+                }
+            }
+
+            match decision {
+                Admission::Reject { retry_after_ms } => {
+                    info!("Busy with {} frames in flight; asking client to retry after {} ms", pending.len(), retry_after_ms);
+                    devices.record_frame(&parsed_request.device_hash, ResponseStatus::Busy, received.elapsed(), ms_now());
+                    let busy = status_response(ResponseStatus::Busy, retry_after_ms);
+                    send_reply(&responder, &identity, seal.as_ref(), vmec_response_transport::encode_response(busy).unwrap());
+                }
+                admitted => {
+                    let superseded = pending.push(PendingRequest {
+                        identity,
+                        seal,
+                        request: parsed_request,
+                        admission: admitted,
+                        received,
+                    });
+                    if let Some(superseded) = superseded {
+                        let reply = status_response(ResponseStatus::Superseded, 0);
+                        send_reply(&responder, &superseded.identity, superseded.seal.as_ref(), vmec_response_transport::encode_response(reply).unwrap());
+                    }
+                }
+            }
+        }
+
+        if let Some(upstream) = &mut upstream {
+            for (forwarded, reply) in upstream.receive() {
+                let mut response_vals = match reply.map(|reply| vmec_response_transport::decode_response(&reply)) {
+                    Some(Ok(response_vals)) => response_vals,
+                    _ => {
+                        warn!("No reply from {} for frame {}", upstream.address(), forwarded.request_hash);
+                        error_response(ErrorCode::UpstreamUnavailable, "upstream server did not answer")
+                    }
+                };
+                response_vals.hops.push(hop(&server_name, forwarded.received));
+                // the device's session is with this server, not the upstream one
+                response_vals.session_token.clear();
+                if let Some(alerts) = &mut alerts {
+                    alerts.record_seal(&forwarded.device_hash, forwarded.seal.as_ref());
+                    for alert in alerts::alerts_in(&forwarded.device_hash, &response_vals.neural_output, ms_now()) {
+                        publish_alert(alerts, events.as_ref(), alert);
+                    }
+                }
+                devices.record_frame(&forwarded.device_hash, response_vals.status, forwarded.received.elapsed(), ms_now());
+                if let Some(events) = &events {
+                    events.record_reply(&forwarded.device_hash, &forwarded.request_hash, forwarded.location, &response_vals, forwarded.received.elapsed(), ms_now());
+                }
+                let cacheable = matches!(response_vals.status, ResponseStatus::Ok | ResponseStatus::ReduceRate);
+                let response_to_send = vmec_response_transport::encode_response(response_vals).unwrap();
+                if cacheable {
+                    cache.insert(&forwarded.device_hash, &forwarded.request_hash, &response_to_send);
+                }
+                send_reply(&responder, &forwarded.identity, forwarded.seal.as_ref(), response_to_send);
+            }
+        }
+
+        if let Some(handover) = &mut handover {
+            for session in handover.receive() {
+                if let Some(guard) = replay_guard.as_mut() {
+                    guard.restore(&session.device_hash, session.last_frame_sequence, session.last_heartbeat_sequence);
+                }
+                let session = handover.sessions.import(session);
+                info!("Took over the session of {} ({} frames so far)", session.device_hash.trim(), session.frames);
+            }
+        }
+
+        if let Some(admin) = &mut admin {
+            admin.serve(Controls { devices: &mut devices, rate_limiter: &mut rate_limiter, router: &router, reloader: &reloader, shadow: shadow.as_ref(), experiment: &experiment_metrics, alerts: alerts.as_ref(), hazard_map: hazard_map.as_ref(), events: events.as_ref() });
+        }
+
+        // between batches, so that every frame of a batch runs on the same model
+        for reloaded in reloader.receive() {
+            let swapped = reloaded.and_then(|engine| {
+                info!("Swapping in version {} of engine {} ({})", engine.version, engine.name, engine.model.display());
+                router.replace(engine)
+            });
+            if let Err(e) = swapped {
+                warn!("Keeping the current model: {}", e);
+            }
+        }
+
+        if batcher.wait(pending.len(), pending.oldest_received(), Instant::now()).is_some() {
+            continue;
+        }
+        let batch = pending.pop_batch(batcher.max_batch_size());
+        if batch.is_empty() {
+            continue;
+        }
+
+        let work_started = Instant::now();
+        let requests: Vec<&VmecRequestFields> = batch.iter().map(|next| &next.request).collect();
+        let responses = process_batch(&requests, &router);
 
         // do some 'work'
-        thread::sleep(Duration::from_millis(1));
+        thread::sleep(faults.batch_latency(batch.len()));
+        let processing_time = work_started.elapsed();
+        let batch_size = batch.len();
+        if batch_size > 1 {
+            debug!("Processed a batch of {} frames in {} μs", batch_size, processing_time.as_micros());
+        }
+
+        for (next, mut response_vals) in batch.into_iter().zip(responses) {
+            if let Some(shadow) = &mut shadow {
+                shadow.mirror(&next.request, &response_vals, &router);
+            }
+            // ahead of the reply, which may be held up by the faults below
+            if let Some(alerts) = &mut alerts {
+                alerts.record_seal(&next.request.device_hash, next.seal.as_ref());
+                for alert in alerts::alerts_in(&next.request.device_hash, &response_vals.neural_output, ms_now()) {
+                    publish_alert(alerts, events.as_ref(), alert);
+                }
+            }
+            if let (Admission::AcceptReduceRate { retry_after_ms }, ResponseStatus::Ok) = (next.admission, response_vals.status) {
+                response_vals.status = ResponseStatus::ReduceRate;
+                response_vals.retry_after_ms = retry_after_ms;
+            }
+            let fault = faults.fault();
+            if fault == Fault::Error {
+                response_vals = error_response(ErrorCode::Internal, "injected fault");
+            }
+            if let (Some(hazard_map), Some(location)) = (&mut hazard_map, next.request.location) {
+                let device_hash = &next.request.device_hash;
+                if response_vals.status != ResponseStatus::Error {
+                    hazard_map.record_output(device_hash, location, &response_vals.neural_output, ms_now());
+                    if next.request.nearby_radius_m > 0.0 {
+                        response_vals.nearby_hazards = hazard_map.near(location, next.request.nearby_radius_m as f64, ms_now());
+                    }
+                }
+                if let Some(alerts) = &alerts {
+                    for alert in hazard_map.pushes(device_hash, location, ms_now()) {
+                        publish_alert(alerts, events.as_ref(), alert);
+                    }
+                }
+            }
+            if let Some(handover) = &mut handover {
+                response_vals.session_token = handover.sessions.record_frame(&next.request, &response_vals, ms_now());
+            }
+            // each frame's share of the batch, so that queue drain estimates stay right
+            stats.record_request(processing_time / batch_size as u32);
+            response_vals.hops.push(hop(&server_name, next.received));
+            devices.record_frame(&next.request.device_hash, response_vals.status, next.received.elapsed(), ms_now());
+            if let Some(events) = &events {
+                events.record_reply(&next.request.device_hash, &next.request.request_hash, next.request.location, &response_vals, next.received.elapsed(), ms_now());
+            }
+            if let Some(arm) = router.arm(&next.request.device_hash) {
+                experiment_metrics.record_frame(arm, &next.request.device_hash, response_vals.status, next.received.elapsed());
+            }
+            let (device_hash, request_hash) = (next.request.device_hash.clone(), next.request.request_hash.clone());
+
+            if let Some(archiver) = &archiver {
+                if archive_sampler.sample() {
+                    archive::submit(archiver, ArchiveRecord {
+                        request: next.request,
+                        received_ms: ms_now().saturating_sub(next.received.elapsed().as_millis() as u64),
+                        status: response_vals.status,
+                        response_hash: response_vals.response_hash.clone(),
+                        neural_output: response_vals.neural_output.clone(),
+                        processing_us: processing_time.as_micros() as u64,
+                    });
+                }
+            }
+            let cacheable = matches!(response_vals.status, ResponseStatus::Ok | ResponseStatus::ReduceRate);
+            let mut response_to_send = vmec_response_transport::encode_response(response_vals).unwrap();
+            if cacheable {
+                cache.insert(&device_hash, &request_hash, &response_to_send);
+            }
+
+            match fault {
+                Fault::Drop => {
+                    debug!("Injected fault: dropping reply");
+                    continue;
+                }
+                Fault::Malformed => {
+                    debug!("Injected fault: sending malformed reply");
+                    response_to_send.truncate(response_to_send.len() / 2);
+                }
+                Fault::Error => debug!("Injected fault: sending error reply"),
+                Fault::None => {}
+            }
 
-        // send reply back to client
-        responder.send(response_to_send, 0).unwrap();
+            // send reply back to client
+            send_reply(&responder, &next.identity, next.seal.as_ref(), response_to_send);
+        }
     }
 
 }
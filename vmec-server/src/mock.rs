@@ -0,0 +1,197 @@
+//! Fault injection for the mock engine, so clients can be tested against a slow or misbehaving
+//! server without one.
+//!
+//! Every engine call, on one frame or a batch, draws a latency from the configured distribution, plus
+//! uniform jitter. Every frame draws at most one fault: the reply is dropped, sent malformed, or sent
+//! as an error. With a seed the sequence of latencies and faults is the same on every run.
+
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Exp, Normal};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyDistribution {
+    /// Always the mean latency
+    Fixed,
+    /// Uniform within the spread on either side of the mean
+    Uniform,
+    /// Normal around the mean, with the spread as standard deviation
+    Normal,
+    /// Exponential with the given mean; the spread is ignored
+    Exponential,
+}
+
+pub struct FaultConfig {
+    pub latency: LatencyDistribution,
+    pub latency_ms: f64,
+    pub latency_spread_ms: f64,
+    /// Up to this much uniformly random latency is added on top of the distribution
+    pub jitter_ms: f64,
+    /// Latency added to a batch for each frame in it after the first
+    pub batch_item_ms: f64,
+    pub drop_rate: f64,
+    pub malformed_rate: f64,
+    pub error_rate: f64,
+    /// Seed for a reproducible run; fresh entropy when not given
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    None,
+    /// Send no reply at all
+    Drop,
+    /// Send bytes that do not decode as a response
+    Malformed,
+    /// Reply with an error status instead of a result
+    Error,
+}
+
+pub struct FaultInjector {
+    config: FaultConfig,
+    rng: StdRng,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Result<Self, String> {
+        let latencies = [
+            ("latency", config.latency_ms),
+            ("latency spread", config.latency_spread_ms),
+            ("jitter", config.jitter_ms),
+            ("batch item latency", config.batch_item_ms),
+        ];
+        if let Some((name, ms)) = latencies.iter().find(|(_, ms)| !(ms.is_finite() && *ms >= 0.0)) {
+            return Err(format!("mock {} must be at least 0 ms, not {}", name, ms));
+        }
+        let rates = [("drop", config.drop_rate), ("malformed", config.malformed_rate), ("error", config.error_rate)];
+        if let Some((name, rate)) = rates.iter().find(|(_, rate)| !(0.0..=1.0).contains(rate)) {
+            return Err(format!("mock {} rate must be within 0 and 1, not {}", name, rate));
+        }
+        if config.drop_rate + config.malformed_rate + config.error_rate > 1.0 {
+            return Err(String::from("mock drop, malformed and error rates must add up to at most 1"));
+        }
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(FaultInjector { config, rng })
+    }
+
+    /// How long the mock engine takes for the next frame
+    pub fn latency(&mut self) -> Duration {
+        let mean = self.config.latency_ms;
+        let spread = self.config.latency_spread_ms;
+        let base_ms = match self.config.latency {
+            LatencyDistribution::Fixed => mean,
+            LatencyDistribution::Uniform => mean + self.rng.gen_range(-1.0..=1.0) * spread,
+            LatencyDistribution::Normal => Normal::new(mean, spread).unwrap().sample(&mut self.rng),
+            LatencyDistribution::Exponential if mean > 0.0 => Exp::new(1.0 / mean).unwrap().sample(&mut self.rng),
+            LatencyDistribution::Exponential => 0.0,
+        };
+        let jitter_ms = self.rng.gen::<f64>() * self.config.jitter_ms;
+        Duration::from_secs_f64((base_ms + jitter_ms).max(0.0) / 1000.0)
+    }
+
+    /// How long the mock engine takes for one call on a batch of frames
+    pub fn batch_latency(&mut self, batch_size: usize) -> Duration {
+        let extra_frames = batch_size.saturating_sub(1) as f64;
+        self.latency() + Duration::from_secs_f64(extra_frames * self.config.batch_item_ms / 1000.0)
+    }
+
+    /// Which fault, if any, to inject into the reply for the next frame
+    pub fn fault(&mut self) -> Fault {
+        let draw: f64 = self.rng.gen();
+        let mut threshold = self.config.drop_rate;
+        if draw < threshold {
+            return Fault::Drop;
+        }
+        threshold += self.config.malformed_rate;
+        if draw < threshold {
+            return Fault::Malformed;
+        }
+        threshold += self.config.error_rate;
+        if draw < threshold {
+            return Fault::Error;
+        }
+        Fault::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(latency: LatencyDistribution) -> FaultConfig {
+        FaultConfig {
+            latency,
+            latency_ms: 10.0,
+            latency_spread_ms: 5.0,
+            jitter_ms: 0.0,
+            batch_item_ms: 2.0,
+            drop_rate: 0.0,
+            malformed_rate: 0.0,
+            error_rate: 0.0,
+            seed: Some(7),
+        }
+    }
+
+    #[test]
+    fn fixed_latency_without_jitter_is_exact() {
+        let mut injector = FaultInjector::new(config(LatencyDistribution::Fixed)).unwrap();
+        for _ in 0..10 {
+            assert_eq!(injector.latency(), Duration::from_millis(10));
+            assert_eq!(injector.fault(), Fault::None);
+        }
+        assert_eq!(injector.batch_latency(1), Duration::from_millis(10));
+        assert_eq!(injector.batch_latency(4), Duration::from_millis(16));
+    }
+
+    #[test]
+    fn latencies_stay_within_bounds() {
+        let mut injector = FaultInjector::new(FaultConfig { jitter_ms: 2.0, ..config(LatencyDistribution::Uniform) }).unwrap();
+        for _ in 0..1000 {
+            let latency = injector.latency();
+            assert!(latency >= Duration::from_millis(5) && latency <= Duration::from_millis(17));
+        }
+
+        // a normal distribution wider than its mean is clamped at zero rather than going negative
+        let mut injector = FaultInjector::new(FaultConfig { latency_spread_ms: 50.0, ..config(LatencyDistribution::Normal) }).unwrap();
+        for _ in 0..1000 {
+            injector.latency();
+        }
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let faulty = || FaultConfig { drop_rate: 0.2, malformed_rate: 0.2, error_rate: 0.2, ..config(LatencyDistribution::Exponential) };
+        let mut first = FaultInjector::new(faulty()).unwrap();
+        let mut second = FaultInjector::new(faulty()).unwrap();
+        for _ in 0..100 {
+            assert_eq!(first.latency(), second.latency());
+            assert_eq!(first.fault(), second.fault());
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_settings() {
+        assert!(FaultInjector::new(FaultConfig { drop_rate: 1.0, ..config(LatencyDistribution::Fixed) }).is_ok());
+        assert!(FaultInjector::new(FaultConfig { drop_rate: 0.6, error_rate: 0.6, ..config(LatencyDistribution::Fixed) }).is_err());
+        // a negative rate would otherwise make room for others in the sum
+        assert!(FaultInjector::new(FaultConfig { drop_rate: -0.5, error_rate: 0.9, ..config(LatencyDistribution::Fixed) }).is_err());
+        assert!(FaultInjector::new(FaultConfig { malformed_rate: f64::NAN, ..config(LatencyDistribution::Fixed) }).is_err());
+        assert!(FaultInjector::new(FaultConfig { latency_ms: -1.0, ..config(LatencyDistribution::Fixed) }).is_err());
+        assert!(FaultInjector::new(FaultConfig { jitter_ms: f64::INFINITY, ..config(LatencyDistribution::Fixed) }).is_err());
+    }
+
+    #[test]
+    fn fault_rates_are_respected() {
+        let mut injector = FaultInjector::new(FaultConfig { drop_rate: 0.1, error_rate: 0.3, ..config(LatencyDistribution::Fixed) }).unwrap();
+        let faults: Vec<Fault> = (0..10_000).map(|_| injector.fault()).collect();
+        let count = |fault: Fault| faults.iter().filter(|&&f| f == fault).count();
+        assert!((800..1200).contains(&count(Fault::Drop)));
+        assert!((2700..3300).contains(&count(Fault::Error)));
+        assert_eq!(count(Fault::Malformed), 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! Frames that have been admitted but not yet processed, and the order they are processed in.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use cornflakes::VmecRequestFields;
+
+use crate::admission::Admission;
+use crate::config::FairQueueConfig;
+use crate::transport::Seal;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Process every frame in arrival order
+    Fifo,
+    /// Keep only the newest pending frame of each device; older ones are answered as superseded
+    LatestPerDevice,
+    /// Take turns between devices with frames waiting, each getting its weight's worth of frames
+    /// per turn, so that a chatty device cannot crowd out quiet ones
+    Fair,
+}
+
+/// A frame that has been admitted but not yet answered
+pub struct PendingRequest {
+    // ZMQ ROUTER identity of the client that sent the frame
+    pub identity: Vec<u8>,
+    // set when the frame arrived sealed, so that its reply is sealed too
+    pub seal: Option<Seal>,
+    pub request: VmecRequestFields,
+    pub admission: Admission,
+    pub received: Instant,
+}
+
+pub struct RequestQueue {
+    policy: QueuePolicy,
+    pending: VecDeque<PendingRequest>,
+    // frames each device may take per turn under the fair policy, where not 1; keyed by trimmed device hash
+    weights: HashMap<String, f64>,
+    // devices with frames waiting, the one whose turn it is first
+    rotation: VecDeque<String>,
+    // frames each device in the rotation may still take, deficit round-robin style
+    deficits: HashMap<String, f64>,
+    // whether the device at the front of the rotation has been granted its weight for this turn
+    turn_started: bool,
+}
+
+impl RequestQueue {
+    pub fn new(policy: QueuePolicy) -> Self {
+        RequestQueue {
+            policy,
+            pending: VecDeque::new(),
+            weights: HashMap::new(),
+            rotation: VecDeque::new(),
+            deficits: HashMap::new(),
+            turn_started: false,
+        }
+    }
+
+    /// Sets the weights devices take turns by under the fair policy
+    pub fn with_weights(mut self, config: &FairQueueConfig, device_classes: &HashMap<String, String>) -> Result<Self, String> {
+        if let Some((name, weight)) = config.classes.iter().chain(&config.devices).find(|(_, weight)| !(weight.is_finite() && **weight > 0.0)) {
+            return Err(format!("weight of {} must be above 0, not {}", name, weight));
+        }
+        for (device_hash, class) in device_classes {
+            if let Some(weight) = config.classes.get(class) {
+                self.weights.insert(String::from(device_hash.trim()), *weight);
+            }
+        }
+        for (device_hash, weight) in &config.devices {
+            self.weights.insert(String::from(device_hash.trim()), *weight);
+        }
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues a frame. Returns the older frame it replaced, if the policy coalesces them.
+    pub fn push(&mut self, request: PendingRequest) -> Option<PendingRequest> {
+        match self.policy {
+            QueuePolicy::Fair => {
+                let device_hash = request.request.device_hash.trim();
+                if !self.rotation.iter().any(|waiting| waiting == device_hash) {
+                    self.rotation.push_back(String::from(device_hash));
+                }
+                self.pending.push_back(request);
+                None
+            }
+            QueuePolicy::Fifo => {
+                self.pending.push_back(request);
+                None
+            }
+            QueuePolicy::LatestPerDevice => {
+                let existing = self
+                    .pending
+                    .iter_mut()
+                    .find(|pending| pending.request.device_hash.trim() == request.request.device_hash.trim());
+                match existing {
+                    // the newer frame takes over the older one's place in line
+                    Some(existing) => Some(std::mem::replace(existing, request)),
+                    None => {
+                        self.pending.push_back(request);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the reply for a queued frame to `identity` instead, for when a client resends a frame
+    /// it gave up waiting on. Returns whether a frame with this hash was queued for the device; a
+    /// frame queued for another device is left alone.
+    pub fn redirect(&mut self, device_hash: &str, request_hash: &str, identity: &[u8]) -> bool {
+        if request_hash.is_empty() {
+            return false;
+        }
+        let device_hash = device_hash.trim();
+        match self.pending.iter_mut().find(|pending| pending.request.request_hash == request_hash && pending.request.device_hash.trim() == device_hash) {
+            Some(pending) => {
+                pending.identity = Vec::from(identity);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<PendingRequest> {
+        match self.policy {
+            QueuePolicy::Fair => self.pop_fair(),
+            _ => self.pending.pop_front(),
+        }
+    }
+
+    /// The oldest frame of the device whose turn it is
+    fn pop_fair(&mut self) -> Option<PendingRequest> {
+        while let Some(device_hash) = self.rotation.front().cloned() {
+            let deficit = self.deficits.entry(device_hash.clone()).or_insert(0.0);
+            if !self.turn_started {
+                *deficit += self.weights.get(&device_hash).copied().unwrap_or(1.0);
+                self.turn_started = true;
+            }
+            let position = self.pending.iter().position(|pending| pending.request.device_hash.trim() == device_hash);
+            if let (true, Some(position)) = (*deficit >= 1.0, position) {
+                *deficit -= 1.0;
+                let request = self.pending.remove(position);
+                if !self.pending.iter().any(|pending| pending.request.device_hash.trim() == device_hash) {
+                    // a device does not save up turns while it has nothing waiting
+                    self.end_turn(true);
+                }
+                return request;
+            }
+            self.end_turn(position.is_none());
+        }
+        None
+    }
+
+    /// Moves on to the next device in the rotation, dropping the current one if it has nothing waiting
+    fn end_turn(&mut self, drained: bool) {
+        self.turn_started = false;
+        if drained {
+            if let Some(device_hash) = self.rotation.pop_front() {
+                self.deficits.remove(&device_hash);
+            }
+        } else {
+            self.rotation.rotate_left(1);
+        }
+    }
+
+    /// Takes up to `max` frames from the front of the queue
+    pub fn pop_batch(&mut self, max: usize) -> Vec<PendingRequest> {
+        (0..max).map_while(|_| self.pop()).collect()
+    }
+
+    /// When the longest-waiting queued frame arrived
+    pub fn oldest_received(&self) -> Option<Instant> {
+        self.pending.iter().map(|pending| pending.received).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(device_hash: &str, request_hash: &str) -> PendingRequest {
+        PendingRequest {
+            identity: Vec::from(device_hash.as_bytes()),
+            seal: None,
+            request: VmecRequestFields {
+                device_hash: String::from(device_hash),
+                request_hash: String::from(request_hash),
+                ..Default::default()
+            },
+            admission: Admission::Accept,
+            received: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn fifo_keeps_every_frame() {
+        let mut queue = RequestQueue::new(QueuePolicy::Fifo);
+        assert!(queue.push(pending("a", "1")).is_none());
+        assert!(queue.push(pending("a", "2")).is_none());
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().unwrap().request.request_hash, "1");
+        assert_eq!(queue.pop().unwrap().request.request_hash, "2");
+    }
+
+    #[test]
+    fn latest_per_device_supersedes_older_frames() {
+        let mut queue = RequestQueue::new(QueuePolicy::LatestPerDevice);
+        assert!(queue.push(pending("a", "1")).is_none());
+        assert!(queue.push(pending("b", "1")).is_none());
+        let superseded = queue.push(pending("a", "2")).unwrap();
+        assert_eq!(superseded.request.request_hash, "1");
+
+        assert_eq!(queue.len(), 2);
+        let first = queue.pop().unwrap();
+        assert_eq!((first.request.device_hash.as_str(), first.request.request_hash.as_str()), ("a", "2"));
+        assert_eq!(queue.pop().unwrap().request.device_hash, "b");
+        assert!(queue.is_empty());
+
+        // device hashes are compared without the line ending some clients send
+        queue.push(pending("a\n", "3"));
+        assert_eq!(queue.push(pending("a", "4")).unwrap().request.request_hash, "3");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn resent_frames_are_redirected() {
+        let mut queue = RequestQueue::new(QueuePolicy::Fifo);
+        queue.push(pending("a", "1"));
+        assert!(!queue.redirect("b", "1", b"intruder"));
+        assert!(queue.redirect("a\n", "1", b"retry"));
+        assert!(!queue.redirect("a", "2", b"retry"));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop().unwrap().identity, b"retry");
+    }
+
+    fn order(queue: &mut RequestQueue) -> String {
+        std::iter::from_fn(|| queue.pop()).map(|pending| pending.request.device_hash).collect()
+    }
+
+    #[test]
+    fn fair_takes_turns_between_devices() {
+        let mut queue = RequestQueue::new(QueuePolicy::Fair);
+        for i in 0..6 {
+            queue.push(pending("a", &i.to_string()));
+        }
+        queue.push(pending("b", "1"));
+        queue.push(pending("b\n", "2"));
+        assert_eq!(queue.len(), 8);
+        let first = queue.pop().unwrap();
+        assert_eq!((first.request.device_hash.as_str(), first.request.request_hash.as_str()), ("a", "0"));
+        assert_eq!(order(&mut queue), "bab\naaaa");
+
+        // a device that had nothing waiting does not get to catch up
+        queue.push(pending("a", "7"));
+        queue.push(pending("a", "8"));
+        queue.push(pending("b", "3"));
+        assert_eq!(order(&mut queue), "aba");
+    }
+
+    #[test]
+    fn fair_shares_by_weight() {
+        let config: FairQueueConfig = toml::from_str("devices = { b = 2.0 }\nclasses = { slow = 0.5 }").unwrap();
+        let device_classes = HashMap::from([(String::from("c"), String::from("slow"))]);
+        let mut queue = RequestQueue::new(QueuePolicy::Fair).with_weights(&config, &device_classes).unwrap();
+        for device_hash in ["a", "b", "c"] {
+            for i in 0..4 {
+                queue.push(pending(device_hash, &i.to_string()));
+            }
+        }
+        assert_eq!(order(&mut queue), "abbabbcaaccc");
+
+        let config: FairQueueConfig = toml::from_str("devices = { b = 0.0 }").unwrap();
+        assert!(RequestQueue::new(QueuePolicy::Fair).with_weights(&config, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn batches_take_from_the_front() {
+        let mut queue = RequestQueue::new(QueuePolicy::Fifo);
+        let first = pending("a", "1");
+        let oldest = first.received;
+        queue.push(first);
+        queue.push(pending("b", "1"));
+        queue.push(pending("c", "1"));
+        assert_eq!(queue.oldest_received(), Some(oldest));
+
+        let batch = queue.pop_batch(2);
+        assert_eq!(batch.iter().map(|pending| pending.request.device_hash.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(queue.pop_batch(2).len(), 1);
+        assert_eq!(queue.oldest_received(), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Token bucket rate limits on frames, per device and across all devices, so that one client
+//! sending too fast cannot starve the others. Heartbeats are not limited.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::config::{RateLimit, RateLimitConfig};
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.refilled = now;
+    }
+
+    /// How long until a token is available, or None if one is available now
+    fn wait_ms(&self) -> Option<u32> {
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        if self.limit.per_second <= 0.0 {
+            return Some(u32::MAX);
+        }
+        Some(((1.0 - self.tokens) / self.limit.per_second * 1000.0).ceil() as u32)
+    }
+}
+
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    default_limit: Option<RateLimit>,
+    class_limits: HashMap<String, RateLimit>,
+    // class of each device, keyed by trimmed device hash
+    device_classes: HashMap<String, String>,
+    buckets: HashMap<String, TokenBucket>,
+    // limits set at runtime in place of the configured ones, keyed by trimmed device hash
+    throttled: HashMap<String, RateLimit>,
+}
+
+impl RateLimiter {
+    /// Devices in a class with no limit of its own, e.g. one that only exists for routing, get the
+    /// default limit
+    pub fn new(config: &RateLimitConfig, device_classes: &HashMap<String, String>) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            global: config.global.map(|limit| TokenBucket::new(limit, now)),
+            default_limit: config.default,
+            class_limits: config.classes.clone(),
+            device_classes: device_classes.iter().map(|(device_hash, class)| (String::from(device_hash.trim()), class.clone())).collect(),
+            buckets: HashMap::new(),
+            throttled: HashMap::new(),
+        }
+    }
+
+    /// Limits a device to `limit` in place of its configured limit, or returns it to that for None
+    pub fn throttle(&mut self, device_hash: &str, limit: Option<RateLimit>) {
+        let device_hash = device_hash.trim();
+        self.buckets.remove(device_hash);
+        match limit {
+            Some(limit) => self.throttled.insert(String::from(device_hash), limit),
+            None => self.throttled.remove(device_hash),
+        };
+    }
+
+    pub fn throttled(&self, device_hash: &str) -> Option<RateLimit> {
+        self.throttled.get(device_hash.trim()).copied()
+    }
+
+    /// Takes a token for a frame from this device. When over the limit, returns how long the
+    /// device should wait before sending again.
+    pub fn check(&mut self, device_hash: &str, now: Instant) -> Result<(), u32> {
+        let device_hash = device_hash.trim();
+        let limit = match (self.throttled.get(device_hash), self.device_classes.get(device_hash)) {
+            (Some(throttled), _) => Some(*throttled),
+            (None, Some(class)) => self.class_limits.get(class).copied().or(self.default_limit),
+            (None, None) => self.default_limit,
+        };
+
+        let mut device_bucket = match limit {
+            Some(limit) => {
+                let bucket = self.buckets.entry(String::from(device_hash)).or_insert_with(|| TokenBucket::new(limit, now));
+                bucket.refill(now);
+                if let Some(wait_ms) = bucket.wait_ms() {
+                    return Err(wait_ms);
+                }
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        // only take from the device's bucket once the global one has room too
+        if let Some(global) = &mut self.global {
+            global.refill(now);
+            if let Some(wait_ms) = global.wait_ms() {
+                return Err(wait_ms);
+            }
+            global.tokens -= 1.0;
+        }
+        if let Some(bucket) = &mut device_bucket {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limit(per_second: f64, burst: f64) -> RateLimit {
+        RateLimit { per_second, burst }
+    }
+
+    #[test]
+    fn device_limit_allows_burst_then_refills() {
+        let config = RateLimitConfig { default: Some(limit(10.0, 2.0)), ..Default::default() };
+        let mut limiter = RateLimiter::new(&config, &HashMap::new());
+        let start = Instant::now();
+        assert_eq!(limiter.check("a", start), Ok(()));
+        assert_eq!(limiter.check("a", start), Ok(()));
+        assert_eq!(limiter.check("a", start), Err(100));
+        // other devices have their own bucket
+        assert_eq!(limiter.check("b", start), Ok(()));
+        assert_eq!(limiter.check("a", start + Duration::from_millis(100)), Ok(()));
+    }
+
+    #[test]
+    fn classes_and_global_limit() {
+        let mut classes = HashMap::new();
+        classes.insert(String::from("fleet"), limit(1.0, 5.0));
+        let config = RateLimitConfig {
+            global: Some(limit(1.0, 3.0)),
+            default: Some(limit(1.0, 1.0)),
+            classes,
+        };
+        let mut device_classes = HashMap::new();
+        device_classes.insert(String::from("truck"), String::from("fleet"));
+        let mut limiter = RateLimiter::new(&config, &device_classes);
+
+        let now = Instant::now();
+        assert_eq!(limiter.check("truck\n", now), Ok(()));
+        assert_eq!(limiter.check("truck", now), Ok(()));
+        assert_eq!(limiter.check("bike", now), Ok(()));
+        assert!(limiter.check("bike", now).is_err());
+        // the fleet class still has tokens, but the global limit is used up
+        assert_eq!(limiter.check("truck", now), Err(1000));
+
+        // a class with no limit of its own, e.g. one that is only routed on, gets the default limit
+        device_classes.insert(String::from("car"), String::from("unlimited"));
+        let mut limiter = RateLimiter::new(&RateLimitConfig { global: None, ..config }, &device_classes);
+        assert_eq!(limiter.check("car", now), Ok(()));
+        assert!(limiter.check("car", now).is_err());
+    }
+
+    #[test]
+    fn throttled_devices() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig::default(), &HashMap::new());
+        let now = Instant::now();
+        assert_eq!(limiter.check("bike", now), Ok(()));
+        assert_eq!(limiter.check("bike", now), Ok(()));
+
+        limiter.throttle("bike\n", Some(limit(2.0, 1.0)));
+        assert_eq!(limiter.throttled("bike").map(|limit| limit.per_second), Some(2.0));
+        assert_eq!(limiter.check("bike", now), Ok(()));
+        assert_eq!(limiter.check("bike", now), Err(500));
+
+        limiter.throttle("bike", None);
+        assert_eq!(limiter.check("bike", now), Ok(()));
+    }
+}
@@ -0,0 +1,150 @@
+//! Hot reload of engine models, so that a model can be swapped without dropping every connected
+//! rider. A background thread loads and warms up the new model, on request through the admin
+//! interface or when it sees the model file change, and hands the engine back to the main loop to
+//! swap in between batches. The main loop is woken up through an inproc socket, like for upstream
+//! replies.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::engine::Engine;
+
+const LOADED_ADDRESS: &str = "inproc://model-reloads";
+
+pub struct ModelReloader {
+    requests: Sender<String>,
+    loaded: Receiver<Result<Engine, String>>,
+    doorbell: zmq::Socket,
+}
+
+impl ModelReloader {
+    /// Reloads the given engines on request, and also whenever their model file changes if a watch
+    /// interval is given
+    pub fn spawn(context: &zmq::Context, engines: &[Engine], watch_interval: Option<Duration>) -> Self {
+        let doorbell = context.socket(zmq::PULL).unwrap();
+        doorbell.bind(LOADED_ADDRESS).unwrap();
+        let (requests, requested) = mpsc::channel();
+        let (done, loaded) = mpsc::channel();
+
+        let context = context.clone();
+        let engines: HashMap<String, Engine> = engines.iter().map(|engine| (engine.name.clone(), engine.clone())).collect();
+        thread::spawn(move || {
+            let ring = context.socket(zmq::PUSH).unwrap();
+            ring.connect(LOADED_ADDRESS).unwrap();
+            let report = |result| {
+                if done.send(result).is_ok() {
+                    ring.send(&[][..], 0).unwrap();
+                }
+            };
+            reload_loop(engines, watch_interval, requested, report);
+        });
+        ModelReloader { requests, loaded, doorbell }
+    }
+
+    /// Readable when an engine has been reloaded, or has failed to
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.doorbell
+    }
+
+    pub fn request(&self, engine_name: &str) {
+        self.requests.send(String::from(engine_name)).unwrap();
+    }
+
+    /// Engines reloaded since the last call, ready to be swapped in, and reloads that failed
+    pub fn receive(&mut self) -> Vec<Result<Engine, String>> {
+        while self.doorbell.recv_bytes(zmq::DONTWAIT).is_ok() {}
+        self.loaded.try_iter().collect()
+    }
+}
+
+fn modified(engine: &Engine) -> Option<SystemTime> {
+    fs::metadata(&engine.model).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn reload_loop(mut engines: HashMap<String, Engine>, watch_interval: Option<Duration>, requested: Receiver<String>, report: impl Fn(Result<Engine, String>)) {
+    // model files without a modification time, e.g. the mock engine's, are not watched
+    let mut last_modified: HashMap<String, SystemTime> = engines.iter()
+        .filter_map(|(name, engine)| modified(engine).map(|modified| (name.clone(), modified)))
+        .collect();
+
+    loop {
+        let request = match watch_interval {
+            Some(watch_interval) => requested.recv_timeout(watch_interval),
+            None => requested.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let names = match request {
+            Ok(name) => vec![name],
+            Err(RecvTimeoutError::Timeout) => {
+                let mut changed = Vec::new();
+                for (name, last_modified) in &mut last_modified {
+                    if let Some(modified) = modified(&engines[name]).filter(|modified| modified != last_modified) {
+                        *last_modified = modified;
+                        changed.push(name.clone());
+                    }
+                }
+                changed
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        for name in names {
+            let Some(engine) = engines.get(&name) else {
+                report(Err(format!("no engine named {}", name)));
+                continue;
+            };
+            let reloaded = engine.reload().map_err(|e| format!("could not reload {}: {}", name, e));
+            if let Ok(reloaded) = &reloaded {
+                engines.insert(name, reloaded.clone());
+            }
+            report(reloaded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::engine::EngineRouter;
+
+    fn next_result(reloader: &mut ModelReloader) -> Result<Engine, String> {
+        loop {
+            reloader.socket().poll(zmq::POLLIN, 1000).unwrap();
+            if let Some(result) = reloader.receive().pop() {
+                return result;
+            }
+        }
+    }
+
+    #[test]
+    fn reloads_on_request_and_on_change() {
+        let model = std::env::temp_dir().join(format!("vmec-reload-test-{}.pt", std::process::id()));
+        fs::write(&model, b"weights").unwrap();
+        let config: ServerConfig = toml::from_str(&format!(r#"
+            [engines.hazards]
+            model = "{}"
+
+            [routing]
+            front = "hazards"
+            rear = "hazards"
+        "#, model.display())).unwrap();
+        let router = EngineRouter::new(&config, None).unwrap();
+
+        let context = zmq::Context::new();
+        let mut reloader = ModelReloader::spawn(&context, router.engines(), Some(Duration::from_millis(10)));
+
+        reloader.request("hazards");
+        assert_eq!(next_result(&mut reloader).unwrap().version, 2);
+        reloader.request("vehicles");
+        assert!(next_result(&mut reloader).is_err());
+
+        // file systems may only keep whole seconds
+        let file = fs::File::options().write(true).open(&model).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(next_result(&mut reloader).unwrap().version, 3);
+        fs::remove_file(&model).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Offline replay: runs recorded requests through the live decode and `process_request` path,
+//! without a client or camera, for regression checks and model comparisons.
+//!
+//! The input is a recording as written by `--record-requests`. The output has one JSON object per
+//! line for each replayed frame, holding the response and how long decoding and processing took.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use cornflakes::recording::RecordReader;
+use cornflakes::{vmec_request_transport, VmecRequestKind};
+
+use crate::engine::EngineRouter;
+use crate::process_request;
+
+pub fn run(input: &Path, output: &Path, router: &EngineRouter) -> io::Result<()> {
+    let records = RecordReader::new(BufReader::new(File::open(input)?));
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    let mut processing_times: Vec<Duration> = Vec::new();
+    let mut skipped = 0;
+
+    for (index, encoded) in records.enumerate() {
+        let encoded = encoded?;
+
+        let decode_started = Instant::now();
+        let request = match vmec_request_transport::decode_request_kind(&encoded) {
+            Ok(VmecRequestKind::Frame(request)) => request,
+            Ok(VmecRequestKind::Heartbeat(_) | VmecRequestKind::Sealed(_) | VmecRequestKind::SessionPull(_)) => {
+                skipped += 1;
+                continue;
+            }
+            Err(e) => {
+                println!("Record {}: could not decode request: {}", index, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let decode_time = decode_started.elapsed();
+
+        let process_started = Instant::now();
+        let response = process_request(&request, router);
+        let process_time = process_started.elapsed();
+        processing_times.push(process_time);
+
+        let line = serde_json::json!({
+            "index": index,
+            "device_hash": request.device_hash.trim(),
+            "request_hash": request.request_hash,
+            "request_timestamp_ms": request.timestamp_ms,
+            "decode_us": decode_time.as_micros() as u64,
+            "process_us": process_time.as_micros() as u64,
+            "status": format!("{:?}", response.status),
+            "response_hash": response.response_hash,
+            "neural_output": response.neural_output,
+        });
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+
+    println!("Replayed {} frames ({} records skipped)", processing_times.len(), skipped);
+    if !processing_times.is_empty() {
+        processing_times.sort();
+        let percentile = |p: usize| processing_times[(processing_times.len() - 1) * p / 100].as_micros();
+        println!("Processing time p50 {} μs, p95 {} μs, max {} μs", percentile(50), percentile(95), percentile(100));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use cornflakes::recording;
+    use cornflakes::{VmecHeartbeatFields, VmecRequestFields};
+
+    use crate::config::ServerConfig;
+
+    fn recording() -> Vec<u8> {
+        let frame = |device_hash: &str| {
+            let request = VmecRequestFields { device_hash: String::from(device_hash), request_hash: String::from("r1"), ..Default::default() };
+            vmec_request_transport::encode_request(request).unwrap()
+        };
+        let heartbeat = vmec_request_transport::encode_heartbeat(VmecHeartbeatFields::default()).unwrap();
+        let mut file = Vec::new();
+        for encoded in [frame("bike\n"), heartbeat, b"not a request".to_vec(), frame("truck")] {
+            recording::write_record(&mut file, &encoded).unwrap();
+        }
+        file
+    }
+
+    fn replay(name: &str, recording: &[u8]) -> (io::Result<()>, Vec<serde_json::Value>) {
+        let input = std::env::temp_dir().join(format!("vmec-replay-test-{}-{}.rec", name, std::process::id()));
+        let output = input.with_extension("jsonl");
+        fs::write(&input, recording).unwrap();
+        let router = EngineRouter::new(&ServerConfig::default(), None).unwrap();
+        let result = run(&input, &output, &router);
+        let lines = fs::read_to_string(&output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+        (result, lines)
+    }
+
+    #[test]
+    fn replays_recorded_frames() {
+        let (result, lines) = replay("frames", &recording());
+        result.unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["index"], 0);
+        assert_eq!(lines[0]["device_hash"], "bike");
+        assert_eq!(lines[0]["status"], "Ok");
+        assert_eq!(lines[1]["index"], 3);
+        assert_eq!(lines[1]["device_hash"], "truck");
+    }
+
+    #[test]
+    fn stops_at_truncated_or_corrupt_records() {
+        let full = recording();
+        let (result, lines) = replay("truncated", &full[..full.len() - 1]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(lines.len(), 1);
+
+        let mut corrupt = full.clone();
+        corrupt.extend_from_slice(&u32::MAX.to_le_bytes());
+        let (result, lines) = replay("corrupt", &corrupt);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(lines.len(), 2);
+    }
+}
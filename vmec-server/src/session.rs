@@ -0,0 +1,398 @@
+//! Device sessions, and their handover between edge servers as a rider moves from one's coverage
+//! into another's.
+//!
+//! Replies carry a session token naming the server that issued it. When a frame arrives with a
+//! token from a peer server, this server pulls the session from that peer, the same way frames are
+//! forwarded upstream: a thread per pull, reporting back on an inproc socket. The peer hands the
+//! session over only once, so a token cannot be used to pull it again.
+//!
+//! Tokens travel in plain replies, so a token alone is not enough to pull a session: pulls are
+//! signed with a key all peers share, and only answered when the signature checks out and the pull
+//! is recent. A pull that fails or times out is tried again with the device's next frame.
+//!
+//! Some of what a server knows about a device deliberately stays behind:
+//! - admin throttles and disconnects, which the operator of that server set for that server;
+//! - the device's rate limit bucket, which refills within seconds anyway;
+//! - its latency stats, which measure the old server rather than the device.
+//!
+//! Experiment arms need not travel: they follow from the device hash, so every server with the
+//! same experiment config puts the device in the same arm.
+
+use std::collections::{HashMap, HashSet};
+use std::thread;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use cornflakes::auth::{self, DeviceKey};
+use cornflakes::{
+    ResponseStatus,
+    VmecHeartbeatFields,
+    VmecRequestFields,
+    VmecResponseFields,
+    VmecSessionPullFields,
+    vmec_request_transport,
+    vmec_response_transport,
+};
+
+use crate::config::HandoverConfig;
+use crate::upstream::request_server;
+
+const RESULTS_ADDRESS: &str = "inproc://session-pulls";
+
+/// Pulls stamped further than this from the server clock, either way, are not answered
+const PULL_MAX_SKEW_MS: u64 = 30_000;
+
+/// How often idle sessions are looked for
+const EXPIRE_INTERVAL_MS: u64 = 60_000;
+
+/// What a server knows about a device, handed over whole to the next server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceSession {
+    pub device_hash: String,
+    /// `<server name>/<random hex>`
+    pub token: String,
+    pub started_ms: u64,
+    pub last_seen_ms: u64,
+    pub frames: u64,
+    pub errors: u64,
+    /// Highest sequence numbers the device has used, so that its requests cannot be replayed
+    pub last_frame_sequence: u64,
+    pub last_heartbeat_sequence: u64,
+    /// Neural output of the last frame, which object tracks continue from
+    pub last_output: String,
+}
+
+/// Server that issued a session token
+pub fn issuer(token: &str) -> Option<&str> {
+    token.rsplit_once('/').map(|(server_name, _)| server_name)
+}
+
+pub struct SessionStore {
+    server_name: String,
+    max_idle_ms: u64,
+    // keyed by trimmed device hash
+    sessions: HashMap<String, DeviceSession>,
+    last_expired_ms: u64,
+}
+
+impl SessionStore {
+    pub fn new(server_name: &str, max_idle_s: u64) -> Self {
+        SessionStore {
+            server_name: String::from(server_name),
+            max_idle_ms: max_idle_s * 1000,
+            sessions: HashMap::new(),
+            last_expired_ms: 0,
+        }
+    }
+
+    /// Forgets the sessions of devices not heard from for `max_idle_s`, at most once per
+    /// `EXPIRE_INTERVAL_MS`
+    fn expire(&mut self, now_ms: u64) {
+        if now_ms < self.last_expired_ms + EXPIRE_INTERVAL_MS {
+            return;
+        }
+        self.last_expired_ms = now_ms;
+        let max_idle_ms = self.max_idle_ms;
+        self.sessions.retain(|_, session| now_ms.saturating_sub(session.last_seen_ms) <= max_idle_ms);
+    }
+
+    fn new_token(&self) -> String {
+        format!("{}/{:016x}", self.server_name, rand::random::<u64>())
+    }
+
+    fn session(&mut self, device_hash: &str, now_ms: u64) -> &mut DeviceSession {
+        self.expire(now_ms);
+        let token = self.new_token();
+        let session = self.sessions.entry(String::from(device_hash.trim())).or_insert_with(|| DeviceSession {
+            device_hash: String::from(device_hash),
+            token,
+            started_ms: now_ms,
+            last_seen_ms: now_ms,
+            frames: 0,
+            errors: 0,
+            last_frame_sequence: 0,
+            last_heartbeat_sequence: 0,
+            last_output: String::new(),
+        });
+        session.last_seen_ms = now_ms;
+        session
+    }
+
+    /// Counts a processed frame, and returns the device's session token for the reply
+    pub fn record_frame(&mut self, request: &VmecRequestFields, response: &VmecResponseFields, now_ms: u64) -> String {
+        let session = self.session(&request.device_hash, now_ms);
+        session.frames += 1;
+        session.last_frame_sequence = session.last_frame_sequence.max(request.sequence);
+        match response.status {
+            ResponseStatus::Ok | ResponseStatus::ReduceRate => session.last_output = response.neural_output.clone(),
+            ResponseStatus::Error => session.errors += 1,
+            _ => {}
+        }
+        session.token.clone()
+    }
+
+    pub fn record_heartbeat(&mut self, heartbeat: &VmecHeartbeatFields, now_ms: u64) {
+        let session = self.session(&heartbeat.device_hash, now_ms);
+        session.last_heartbeat_sequence = session.last_heartbeat_sequence.max(heartbeat.sequence);
+    }
+
+    /// Removes and returns the device's session, if the token is the current one
+    pub fn export(&mut self, device_hash: &str, token: &str) -> Option<DeviceSession> {
+        let key = device_hash.trim();
+        if self.sessions.get(key)?.token != token {
+            return None;
+        }
+        self.sessions.remove(key)
+    }
+
+    /// Takes over a session from another server under a token of this server's. Frames the device
+    /// sent here while the session was being pulled are added to it.
+    pub fn import(&mut self, mut imported: DeviceSession) -> &DeviceSession {
+        imported.token = self.new_token();
+        let session = match self.sessions.remove(imported.device_hash.trim()) {
+            Some(local) => DeviceSession {
+                started_ms: imported.started_ms.min(local.started_ms),
+                last_seen_ms: local.last_seen_ms.max(imported.last_seen_ms),
+                frames: imported.frames + local.frames,
+                errors: imported.errors + local.errors,
+                last_frame_sequence: imported.last_frame_sequence.max(local.last_frame_sequence),
+                last_heartbeat_sequence: imported.last_heartbeat_sequence.max(local.last_heartbeat_sequence),
+                last_output: if local.last_output.is_empty() { imported.last_output.clone() } else { local.last_output },
+                ..imported
+            },
+            None => imported,
+        };
+        self.sessions.entry(String::from(session.device_hash.trim())).or_insert(session)
+    }
+}
+
+pub struct Handover {
+    pub sessions: SessionStore,
+    context: zmq::Context,
+    server_name: String,
+    // peer addresses by server name
+    peers: HashMap<String, String>,
+    // signs pulls to peers, and checks theirs
+    peer_key: DeviceKey,
+    timeout_ms: i32,
+    results: zmq::Socket,
+    // tokens being pulled
+    pulling: HashSet<String>,
+}
+
+impl Handover {
+    pub fn new(context: &zmq::Context, server_name: &str, config: &HandoverConfig) -> Result<Self, String> {
+        let peer_key = DeviceKey::from_hex(&config.peer_key).map_err(|e| format!("handover peer_key: {}", e))?;
+        let results = context.socket(zmq::PULL).unwrap();
+        results.bind(RESULTS_ADDRESS).unwrap();
+        Ok(Handover {
+            sessions: SessionStore::new(server_name, config.max_idle_s),
+            context: context.clone(),
+            server_name: String::from(server_name),
+            peers: config.peers.clone(),
+            peer_key,
+            timeout_ms: config.timeout_ms,
+            results,
+            pulling: HashSet::new(),
+        })
+    }
+
+    /// Readable when a pulled session has arrived, or the peer did not have it
+    pub fn results_socket(&self) -> &zmq::Socket {
+        &self.results
+    }
+
+    /// Starts pulling the device's session from the peer that issued the token, if it is a peer and
+    /// the token is not being pulled already. Returns the peer's name if a pull was started.
+    pub fn pull(&mut self, device_hash: &str, token: &str, now_ms: u64) -> Option<&str> {
+        let server_name = issuer(token).filter(|server_name| *server_name != self.server_name)?;
+        let (server_name, address) = self.peers.get_key_value(server_name)?;
+        if !self.pulling.insert(String::from(token)) {
+            return None;
+        }
+
+        let mut pull = VmecSessionPullFields {
+            device_hash: String::from(device_hash),
+            session_token: String::from(token),
+            timestamp_ms: now_ms,
+            signature: Vec::new(),
+        };
+        auth::sign_session_pull(&mut pull, &self.peer_key);
+        let request = vmec_request_transport::encode_session_pull(pull).unwrap();
+        let context = self.context.clone();
+        let address = address.clone();
+        let timeout_ms = self.timeout_ms;
+        let token = String::from(token);
+        thread::spawn(move || {
+            let session = request_server(&context, &address, timeout_ms, request)
+                .and_then(|reply| vmec_response_transport::decode_session(&reply).ok().flatten());
+            let results = context.socket(zmq::PUSH).unwrap();
+            results.connect(RESULTS_ADDRESS).unwrap();
+            // an empty session stands for none
+            results.send_multipart([token.as_bytes(), &session.unwrap_or_default()[..]], 0).unwrap();
+        });
+        Some(server_name)
+    }
+
+    /// Sessions pulled from peers since the last call. Tokens whose pull failed can be pulled again.
+    pub fn receive(&mut self) -> Vec<DeviceSession> {
+        let mut sessions = Vec::new();
+        while let Ok(parts) = self.results.recv_multipart(zmq::DONTWAIT) {
+            let [token, session] = &parts[..] else {
+                continue;
+            };
+            self.pulling.remove(&*String::from_utf8_lossy(token));
+            if let Ok(session) = serde_json::from_slice(session) {
+                sessions.push(session);
+            }
+        }
+        sessions
+    }
+
+    /// Answers a peer's pull, handing over the session if this server has it and the pull is signed
+    /// with the peer key
+    pub fn answer(&mut self, pull: &VmecSessionPullFields, now_ms: u64) -> Vec<u8> {
+        if !auth::verify_session_pull(pull, &self.peer_key) || pull.timestamp_ms.abs_diff(now_ms) > PULL_MAX_SKEW_MS {
+            warn!("Refusing session pull for {}: not signed with the peer key, or stale", pull.device_hash.trim());
+            return vmec_response_transport::encode_session(None).unwrap();
+        }
+        let session = self.sessions.export(&pull.device_hash, &pull.session_token);
+        let session = session.map(|session| serde_json::to_vec(&session).unwrap());
+        vmec_response_transport::encode_session(session.as_deref()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(device_hash: &str, sequence: u64) -> VmecRequestFields {
+        VmecRequestFields {
+            device_hash: String::from(device_hash),
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    const PEER_KEY: &str = "4c7d9e1f2a3b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d";
+
+    fn handover_config(peers: HashMap<String, String>) -> HandoverConfig {
+        HandoverConfig { peers, peer_key: String::from(PEER_KEY), timeout_ms: 1000, max_idle_s: 3600 }
+    }
+
+    fn output(neural_output: &str) -> VmecResponseFields {
+        VmecResponseFields {
+            neural_output: String::from(neural_output),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exports_with_the_current_token_only() {
+        let mut store = SessionStore::new("edge-a", 3600);
+        let token = store.record_frame(&frame("bike\n", 3), &output("tracks"), 100);
+        assert_eq!(issuer(&token), Some("edge-a"));
+        assert_eq!(store.record_frame(&frame("bike", 4), &output("tracks"), 200), token);
+
+        assert_eq!(store.export("bike", "edge-a/0"), None);
+        let session = store.export("bike\n", &token).unwrap();
+        assert_eq!((session.frames, session.last_frame_sequence, session.last_seen_ms), (2, 4, 200));
+        assert_eq!(store.export("bike", &token), None);
+    }
+
+    #[test]
+    fn imports_merge_with_frames_sent_meanwhile() {
+        let mut edge_a = SessionStore::new("edge-a", 3600);
+        let token = edge_a.record_frame(&frame("bike", 10), &output("old tracks"), 100);
+        let exported = edge_a.export("bike", &token).unwrap();
+
+        let mut edge_b = SessionStore::new("edge-b", 3600);
+        edge_b.record_frame(&frame("bike", 12), &output("new tracks"), 300);
+        let imported = edge_b.import(exported).clone();
+        assert_eq!(issuer(&imported.token), Some("edge-b"));
+        assert_eq!((imported.started_ms, imported.frames, imported.last_frame_sequence), (100, 2, 12));
+        assert_eq!(imported.last_output, "new tracks");
+    }
+
+    #[test]
+    fn forgets_idle_sessions() {
+        let mut store = SessionStore::new("edge-a", 60);
+        let token = store.record_frame(&frame("bike", 1), &output("tracks"), 100_000);
+        store.record_frame(&frame("scooter", 1), &output("tracks"), 150_000);
+        assert_eq!(store.sessions.len(), 2);
+
+        store.record_frame(&frame("scooter", 2), &output("tracks"), 200_000);
+        assert_eq!(store.sessions.len(), 1);
+        assert_eq!(store.export("bike", &token), None);
+    }
+
+    #[test]
+    fn pulls_sessions_from_peers() {
+        // each server has a context of its own, as it would in its own process
+        let edge_a_context = zmq::Context::new();
+        let mut edge_a = Handover::new(&edge_a_context, "edge-a", &handover_config(HashMap::new())).unwrap();
+        let token = edge_a.sessions.record_frame(&frame("bike", 5), &output("tracks"), 100);
+
+        let edge_b_context = zmq::Context::new();
+        let edge_a_socket = edge_b_context.socket(zmq::REP).unwrap();
+        edge_a_socket.bind("inproc://edge-a").unwrap();
+        let mut edge_b = Handover::new(&edge_b_context, "edge-b", &handover_config(HashMap::from([(String::from("edge-a"), String::from("inproc://edge-a"))]))).unwrap();
+        assert_eq!(edge_b.pull("bike", "edge-b/1", 1000), None);
+        assert_eq!(edge_b.pull("bike", "elsewhere/1", 1000), None);
+        assert_eq!(edge_b.pull("bike", &token, 1000), Some("edge-a"));
+        assert_eq!(edge_b.pull("bike", &token, 1000), None);
+
+        let pull = match vmec_request_transport::decode_request_kind(&edge_a_socket.recv_bytes(0).unwrap()).unwrap() {
+            cornflakes::VmecRequestKind::SessionPull(pull) => pull,
+            _ => panic!("not a session pull"),
+        };
+        edge_a_socket.send(edge_a.answer(&pull, 1000), 0).unwrap();
+
+        edge_b.results_socket().poll(zmq::POLLIN, 1000).unwrap();
+        let sessions = edge_b.receive();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].frames, sessions[0].last_output.as_str()), (1, "tracks"));
+        // handed over only once
+        assert_eq!(edge_a.answer(&pull, 1000), vmec_response_transport::encode_session(None).unwrap());
+    }
+
+    #[test]
+    fn retries_failed_pulls() {
+        let context = zmq::Context::new();
+        let silent_peer = context.socket(zmq::REP).unwrap();
+        silent_peer.bind("inproc://silent-edge-a").unwrap();
+        let config = HandoverConfig {
+            timeout_ms: 50,
+            ..handover_config(HashMap::from([(String::from("edge-a"), String::from("inproc://silent-edge-a"))]))
+        };
+        let mut edge_b = Handover::new(&context, "edge-b", &config).unwrap();
+        assert_eq!(edge_b.pull("bike", "edge-a/1f", 1000), Some("edge-a"));
+        assert_eq!(edge_b.pull("bike", "edge-a/1f", 1000), None);
+
+        edge_b.results_socket().poll(zmq::POLLIN, 1000).unwrap();
+        assert!(edge_b.receive().is_empty());
+        assert!(edge_b.pulling.is_empty());
+        assert_eq!(edge_b.pull("bike", "edge-a/1f", 2000), Some("edge-a"));
+    }
+
+    #[test]
+    fn answers_only_signed_recent_pulls() {
+        let context = zmq::Context::new();
+        let mut edge_a = Handover::new(&context, "edge-a", &handover_config(HashMap::new())).unwrap();
+        let token = edge_a.sessions.record_frame(&frame("bike", 5), &output("tracks"), 100);
+        let no_session = vmec_response_transport::encode_session(None).unwrap();
+
+        // knowing the token is not enough
+        let mut pull = VmecSessionPullFields { device_hash: String::from("bike"), session_token: token.clone(), timestamp_ms: 100_000, signature: Vec::new() };
+        assert_eq!(edge_a.answer(&pull, 100_000), no_session);
+        auth::sign_session_pull(&mut pull, &DeviceKey::from_bytes([1; 32]));
+        assert_eq!(edge_a.answer(&pull, 100_000), no_session);
+        auth::sign_session_pull(&mut pull, &DeviceKey::from_hex(PEER_KEY).unwrap());
+        assert_eq!(edge_a.answer(&pull, 100_000 + PULL_MAX_SKEW_MS + 1), no_session);
+        assert_ne!(edge_a.answer(&pull, 100_000), no_session);
+
+        let config = HandoverConfig { peer_key: String::from("short"), ..handover_config(HashMap::new()) };
+        assert!(Handover::new(&context, "edge-a", &config).is_err());
+    }
+}
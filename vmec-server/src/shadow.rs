@@ -0,0 +1,238 @@
+//! Shadow traffic: a sampled fraction of images is also run through a candidate engine, e.g. a new
+//! detector before it is rolled out, and both outputs are logged side by side. Candidates run on a
+//! background thread, so riders never wait for them, and their output never reaches a client.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use cornflakes::{CameraDirection, ResponseStatus, VmecRequestFields, VmecResponseFields};
+
+use crate::archive::ArchiveSampler;
+use crate::config::ShadowConfig;
+use crate::engine::{self, Engine, EngineRouter, UPSTREAM_ENGINE};
+
+/// Images waiting for a candidate; beyond this, new ones are dropped rather than queued
+const SHADOW_BACKLOG: usize = 64;
+
+/// Mirrored images between summaries in the log
+const SUMMARY_INTERVAL: u64 = 100;
+
+/// Fields of an engine's output that say which engine produced it, rather than what it found
+const IDENTITY_FIELDS: [&str; 3] = ["engine", "model", "version"];
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ShadowMetrics {
+    pub mirrored: u64,
+    /// Images on which the candidate found something else than the primary engine
+    pub disagreements: u64,
+    /// Images the candidate failed on, but the primary engine did not
+    pub candidate_errors: u64,
+    /// Images not mirrored because the candidate was backlogged
+    pub dropped: u64,
+}
+
+struct ShadowJob {
+    device_hash: String,
+    request_hash: String,
+    camera: &'static str,
+    image: Vec<u8>,
+    // the primary engine's output for this camera, or the error the frame got
+    primary: Result<serde_json::Value, String>,
+    candidate: Engine,
+}
+
+pub struct Shadow {
+    front: Option<String>,
+    rear: Option<String>,
+    sampler: ArchiveSampler,
+    sender: SyncSender<ShadowJob>,
+    metrics: Arc<Mutex<ShadowMetrics>>,
+}
+
+impl Shadow {
+    pub fn spawn(config: &ShadowConfig, router: &EngineRouter) -> Result<Self, String> {
+        for candidate in [&config.front, &config.rear].into_iter().flatten() {
+            if candidate == UPSTREAM_ENGINE || router.engine(candidate).is_none() {
+                return Err(format!("no engine named {} to mirror to", candidate));
+            }
+        }
+        let log = OpenOptions::new().create(true).append(true).open(&config.log)
+            .map_err(|e| format!("could not open {}: {}", config.log.display(), e))?;
+
+        let (sender, receiver) = sync_channel::<ShadowJob>(SHADOW_BACKLOG);
+        let metrics = Arc::new(Mutex::new(ShadowMetrics::default()));
+        let thread_metrics = metrics.clone();
+        thread::spawn(move || {
+            let mut log = BufWriter::new(log);
+            for job in receiver {
+                run_candidate(job, &mut log, &thread_metrics);
+            }
+        });
+        Ok(Shadow {
+            front: config.front.clone(),
+            rear: config.rear.clone(),
+            sampler: ArchiveSampler::new(config.sample_rate),
+            sender,
+            metrics,
+        })
+    }
+
+    pub fn metrics(&self) -> ShadowMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Mirrors the frame's images to the candidate engines, if the frame is sampled. The candidates
+    /// are looked up in the router each time, so that they can be reloaded like any engine.
+    pub fn mirror(&mut self, request: &VmecRequestFields, response: &VmecResponseFields, router: &EngineRouter) {
+        if !self.sampler.sample() {
+            return;
+        }
+        let outputs: Result<serde_json::Value, String> = match response.status {
+            ResponseStatus::Error => Err(response.error_message.clone()),
+            _ => serde_json::from_str(&response.neural_output).map_err(|e| e.to_string()),
+        };
+        for (camera, image) in request.images() {
+            let candidate = match camera {
+                CameraDirection::Frontcam => &self.front,
+                CameraDirection::Rearcam => &self.rear,
+            };
+            let Some(candidate) = candidate.as_deref().and_then(|name| router.engine(name)) else {
+                continue;
+            };
+            let camera = engine::camera_name(camera);
+            let job = ShadowJob {
+                device_hash: String::from(request.device_hash.trim()),
+                request_hash: request.request_hash.clone(),
+                camera,
+                image: image.to_vec(),
+                primary: outputs.clone().map(|outputs| outputs[camera].clone()),
+                candidate: candidate.clone(),
+            };
+            match self.sender.try_send(job) {
+                Ok(()) => {}
+                Err(TrySendError::Full(job)) => {
+                    warn!("Shadow engine backlogged; not mirroring frame {}", job.request_hash);
+                    self.metrics.lock().unwrap().dropped += 1;
+                }
+                Err(TrySendError::Disconnected(_)) => warn!("Shadow engine has stopped"),
+            }
+        }
+    }
+}
+
+/// What an engine found, without the fields that say which engine it was
+fn findings(output: &Result<serde_json::Value, String>) -> Result<serde_json::Value, ()> {
+    match output {
+        Ok(serde_json::Value::Object(fields)) => Ok(serde_json::Value::Object(
+            fields.iter().filter(|(key, _)| !IDENTITY_FIELDS.contains(&key.as_str())).map(|(key, value)| (key.clone(), value.clone())).collect(),
+        )),
+        Ok(output) => Ok(output.clone()),
+        Err(_) => Err(()),
+    }
+}
+
+fn run_candidate(job: ShadowJob, log: &mut BufWriter<File>, metrics: &Mutex<ShadowMetrics>) {
+    let started = Instant::now();
+    let candidate = job.candidate.infer(&job.image, None).map_err(|e| e.to_string());
+    let candidate_us = started.elapsed().as_micros() as u64;
+    let agree = findings(&job.primary) == findings(&candidate);
+
+    let output = |output: &Result<serde_json::Value, String>| match output {
+        Ok(output) => output.clone(),
+        Err(e) => json!({ "error": e }),
+    };
+    let line = json!({
+        "device_hash": job.device_hash,
+        "request_hash": job.request_hash,
+        "camera": job.camera,
+        "primary": output(&job.primary),
+        "candidate": output(&candidate),
+        "candidate_us": candidate_us,
+        "agree": agree,
+    });
+    if let Err(e) = writeln!(log, "{}", line).and_then(|_| log.flush()) {
+        warn!("Could not write shadow log: {}", e);
+    }
+
+    let mut metrics = metrics.lock().unwrap();
+    metrics.mirrored += 1;
+    if !agree {
+        metrics.disagreements += 1;
+    }
+    if candidate.is_err() && job.primary.is_ok() {
+        metrics.candidate_errors += 1;
+    }
+    if metrics.mirrored.is_multiple_of(SUMMARY_INTERVAL) {
+        info!("Shadow: {} images mirrored, {} disagreements, {} candidate errors", metrics.mirrored, metrics.disagreements, metrics.candidate_errors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn compares_findings_only() {
+        let primary = Ok(json!({ "engine": "hazards", "version": 1, "boxes": [1, 2] }));
+        assert_eq!(findings(&primary), findings(&Ok(json!({ "engine": "candidate", "version": 3, "boxes": [1, 2] }))));
+        assert_ne!(findings(&primary), findings(&Ok(json!({ "engine": "candidate", "boxes": [] }))));
+        assert_ne!(findings(&primary), findings(&Err(String::from("not a jpeg"))));
+    }
+
+    #[test]
+    fn mirrors_to_candidates() {
+        let log = std::env::temp_dir().join(format!("vmec-shadow-test-{}.jsonl", std::process::id()));
+        let config: ServerConfig = toml::from_str(&format!(r#"
+            [engines.hazards]
+            model = "hazards.pt"
+
+            [engines.hazards-candidate]
+            model = "hazards-candidate.pt"
+            input_size = 32
+
+            [routing]
+            front = "hazards"
+            rear = "hazards"
+
+            [shadow]
+            front = "hazards-candidate"
+            sample_rate = 1.0
+            log = "{}"
+        "#, log.display())).unwrap();
+        let router = EngineRouter::new(&config, None).unwrap();
+        let mut shadow = Shadow::spawn(config.shadow.as_ref().unwrap(), &router).unwrap();
+
+        // the candidate decodes images and the primary engine does not, so they disagree on these
+        let request = VmecRequestFields { request_hash: String::from("frame"), image_front: vec![1, 2, 3], ..Default::default() };
+        let response = VmecResponseFields {
+            neural_output: json!({ "front": { "engine": "hazards" }, "rear": { "engine": "hazards" } }).to_string(),
+            ..Default::default()
+        };
+        shadow.mirror(&request, &response, &router);
+
+        let started = Instant::now();
+        while shadow.metrics().mirrored < 1 && started.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(shadow.metrics(), ShadowMetrics { mirrored: 1, disagreements: 1, candidate_errors: 1, dropped: 0 });
+        let line: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!((line["camera"].as_str(), line["agree"].as_bool()), (Some("front"), Some(false)));
+        assert_eq!(line["primary"]["engine"], "hazards");
+        std::fs::remove_file(&log).unwrap();
+
+        let mut missing = config.shadow.unwrap();
+        missing.rear = Some(String::from("missing"));
+        assert!(Shadow::spawn(&missing, &router).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Running load and health figures for the server, reported back to clients in heartbeat replies,
+//! and for each device, reported through the admin interface.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use cornflakes::{ResponseStatus, VmecHealthFields};
+
+/// Requests older than this no longer count towards the load figures
+const LOAD_WINDOW: Duration = Duration::from_secs(10);
+
+/// Mean processing time above which the server reports itself as unhealthy
+const HEALTHY_PROCESSING_BUDGET: Duration = Duration::from_millis(100);
+
+pub struct ServerStats {
+    started: Instant,
+    requests_served: u64,
+    cache_hits: u64,
+    // (completion time, processing time) of each request inside LOAD_WINDOW
+    recent: VecDeque<(Instant, Duration)>,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats {
+            started: Instant::now(),
+            requests_served: 0,
+            cache_hits: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn record_request(&mut self, processing_time: Duration) {
+        self.requests_served += 1;
+        self.recent.push_back((Instant::now(), processing_time));
+        self.expire_old();
+    }
+
+    /// A resent frame was answered from the response cache
+    pub fn record_cache_hit(&mut self) {
+        self.cache_hits += 1;
+    }
+
+    pub fn requests_per_second(&mut self) -> f32 {
+        self.expire_old();
+        let window = LOAD_WINDOW.min(self.started.elapsed()).as_secs_f32();
+        if window <= 0.0 {
+            return 0.0;
+        }
+        self.recent.len() as f32 / window
+    }
+
+    pub fn mean_processing_time(&mut self) -> Duration {
+        self.expire_old();
+        if self.recent.is_empty() {
+            return Duration::ZERO;
+        }
+        let total: Duration = self.recent.iter().map(|(_, processing_time)| *processing_time).sum();
+        total / self.recent.len() as u32
+    }
+
+    pub fn health_report(&mut self, timestamp_ms: u64, server_hash: &str, in_flight: usize) -> VmecHealthFields {
+        let mean_processing_time = self.mean_processing_time();
+        VmecHealthFields {
+            timestamp_ms,
+            server_hash: String::from(server_hash),
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            requests_served: self.requests_served,
+            requests_per_second: self.requests_per_second(),
+            mean_processing_us: mean_processing_time.as_micros() as u64,
+            healthy: mean_processing_time <= HEALTHY_PROCESSING_BUDGET,
+            in_flight: in_flight as u32,
+            cache_hits: self.cache_hits,
+        }
+    }
+
+    fn expire_old(&mut self) {
+        while let Some((completed, _)) = self.recent.front() {
+            if completed.elapsed() > LOAD_WINDOW {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+struct DeviceActivity {
+    first_seen: Instant,
+    last_seen_ms: u64,
+    frames: u64,
+    errors: u64,
+    refused: u64,
+    // (reply time, latency) of each frame answered inside LOAD_WINDOW; no latency for refused frames
+    recent: VecDeque<(Instant, Option<Duration>)>,
+}
+
+/// A device's figures, as reported through the admin interface
+#[derive(Serialize, Debug)]
+pub struct DeviceReport {
+    pub device_hash: String,
+    pub last_seen_ms: u64,
+    pub frames: u64,
+    pub errors: u64,
+    /// Frames refused as busy or rate limited
+    pub refused: u64,
+    pub requests_per_second: f32,
+    /// Time from receiving a frame to replying, over the frames that were not refused
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+/// Figures for each device, keyed by trimmed device hash
+pub struct DeviceStats {
+    devices: HashMap<String, DeviceActivity>,
+}
+
+impl DeviceStats {
+    pub fn new() -> Self {
+        DeviceStats {
+            devices: HashMap::new(),
+        }
+    }
+
+    fn activity(&mut self, device_hash: &str, now_ms: u64) -> &mut DeviceActivity {
+        let activity = self.devices.entry(String::from(device_hash.trim())).or_insert_with(|| DeviceActivity {
+            first_seen: Instant::now(),
+            last_seen_ms: now_ms,
+            frames: 0,
+            errors: 0,
+            refused: 0,
+            recent: VecDeque::new(),
+        });
+        activity.last_seen_ms = now_ms;
+        activity
+    }
+
+    /// Any request from the device, e.g. a heartbeat
+    pub fn record_seen(&mut self, device_hash: &str, now_ms: u64) {
+        self.activity(device_hash, now_ms);
+    }
+
+    /// A reply to one of the device's frames, `latency` after the frame was received
+    pub fn record_frame(&mut self, device_hash: &str, status: ResponseStatus, latency: Duration, now_ms: u64) {
+        let activity = self.activity(device_hash, now_ms);
+        activity.frames += 1;
+        let refused = matches!(status, ResponseStatus::Busy | ResponseStatus::RateLimited);
+        if refused {
+            activity.refused += 1;
+        }
+        if status == ResponseStatus::Error {
+            activity.errors += 1;
+        }
+        activity.recent.push_back((Instant::now(), (!refused).then_some(latency)));
+    }
+
+    /// Every device seen so far, by device hash
+    pub fn report(&mut self) -> Vec<DeviceReport> {
+        let mut reports: Vec<DeviceReport> = self.devices.iter_mut().map(|(device_hash, activity)| {
+            while activity.recent.front().is_some_and(|(replied, _)| replied.elapsed() > LOAD_WINDOW) {
+                activity.recent.pop_front();
+            }
+            let window = LOAD_WINDOW.min(activity.first_seen.elapsed()).as_secs_f32();
+            let mut latencies: Vec<Duration> = activity.recent.iter().filter_map(|(_, latency)| *latency).collect();
+            latencies.sort();
+            let percentile = |p: usize| match latencies.len() {
+                0 => 0.0,
+                n => latencies[(n - 1) * p / 100].as_secs_f64() * 1000.0,
+            };
+            DeviceReport {
+                device_hash: device_hash.clone(),
+                last_seen_ms: activity.last_seen_ms,
+                frames: activity.frames,
+                errors: activity.errors,
+                refused: activity.refused,
+                requests_per_second: if window > 0.0 { activity.recent.len() as f32 / window } else { 0.0 },
+                latency_p50_ms: percentile(50),
+                latency_p90_ms: percentile(90),
+                latency_p99_ms: percentile(99),
+            }
+        }).collect();
+        reports.sort_by(|a, b| a.device_hash.cmp(&b.device_hash));
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_server_is_healthy() {
+        let mut stats = ServerStats::new();
+        let report = stats.health_report(0, "server", 0);
+        assert_eq!(report.requests_served, 0);
+        assert_eq!(report.mean_processing_us, 0);
+        assert!(report.healthy);
+    }
+
+    #[test]
+    fn slow_requests_are_unhealthy() {
+        let mut stats = ServerStats::new();
+        stats.record_request(Duration::from_millis(50));
+        stats.record_request(Duration::from_millis(250));
+        stats.record_cache_hit();
+        let report = stats.health_report(0, "server", 1);
+        assert_eq!(report.requests_served, 2);
+        assert_eq!(report.in_flight, 1);
+        assert_eq!(report.cache_hits, 1);
+        assert_eq!(report.mean_processing_us, 150_000);
+        assert!(!report.healthy);
+    }
+
+    #[test]
+    fn per_device_figures() {
+        let mut devices = DeviceStats::new();
+        for latency_ms in 1..=10 {
+            devices.record_frame("bike\n", ResponseStatus::Ok, Duration::from_millis(latency_ms), 100);
+        }
+        devices.record_frame("bike", ResponseStatus::Error, Duration::from_millis(20), 200);
+        devices.record_frame("bike", ResponseStatus::Busy, Duration::ZERO, 300);
+        devices.record_seen("truck", 400);
+
+        let reports = devices.report();
+        assert_eq!(reports.len(), 2);
+        let bike = &reports[0];
+        assert_eq!(bike.device_hash, "bike");
+        assert_eq!((bike.frames, bike.errors, bike.refused, bike.last_seen_ms), (12, 1, 1, 300));
+        assert_eq!((bike.latency_p50_ms, bike.latency_p99_ms), (6.0, 10.0));
+        assert!(bike.requests_per_second > 0.0);
+        assert_eq!((reports[1].device_hash.as_str(), reports[1].frames), ("truck", 0));
+    }
+}
@@ -0,0 +1,144 @@
+//! Opens sealed (encrypted) requests and seals the replies to them, using the device keys from the
+//! server config. Plaintext requests are still accepted unless the config requires encryption.
+
+use cornflakes::envelope::{self, TransportKey};
+use cornflakes::{vmec_request_transport, ErrorCode, VmecRequestKind, VmecSealedFields};
+
+use crate::auth::{AuthFailure, Authenticator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenFailure {
+    Auth(AuthFailure),
+    /// Sealed with a key other than the device's, or tampered with on the way
+    Undecryptable,
+    /// Opened, but what was inside is not a frame or heartbeat from the device named outside
+    BadContents,
+}
+
+impl OpenFailure {
+    pub fn error_code(self) -> ErrorCode {
+        match self {
+            OpenFailure::Auth(failure) => failure.error_code(),
+            OpenFailure::Undecryptable | OpenFailure::BadContents => ErrorCode::Unauthenticated,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            OpenFailure::Auth(failure) => failure.message(),
+            OpenFailure::Undecryptable => "sealed request could not be decrypted",
+            OpenFailure::BadContents => "sealed request does not hold a request from its device",
+        }
+    }
+}
+
+/// Seals replies for the device a sealed request came from
+#[derive(Clone)]
+pub struct Seal {
+    device_hash: String,
+    key: TransportKey,
+}
+
+impl Seal {
+    pub fn seal(&self, encoded: &[u8]) -> Vec<u8> {
+        envelope::seal_response(encoded, &self.device_hash, &self.key)
+    }
+
+    /// Seals a request as the device did, for passing it on to a server that has the device's key
+    pub fn seal_request(&self, encoded: &[u8]) -> Vec<u8> {
+        envelope::seal_request(encoded, &self.device_hash, &self.key)
+    }
+
+    /// Opens a reply that another server sealed for the device; None if it is not sealed with its key
+    pub fn open_reply(&self, reply: &[u8]) -> Option<Vec<u8>> {
+        envelope::open_response(reply, &self.device_hash, &self.key).ok()
+    }
+}
+
+/// A sealed request, opened
+pub struct Opened {
+    pub kind: VmecRequestKind,
+    // the request as encoded inside the envelope, e.g. for recording
+    pub encoded: Vec<u8>,
+    pub seal: Seal,
+}
+
+pub fn open(sealed: &VmecSealedFields, authenticator: &Authenticator) -> Result<Opened, OpenFailure> {
+    let device_key = authenticator.device_key(&sealed.device_hash).map_err(OpenFailure::Auth)?;
+    let key = TransportKey::derive(device_key);
+    let encoded = envelope::open_request(sealed, &key).map_err(|_| OpenFailure::Undecryptable)?;
+
+    let kind = vmec_request_transport::decode_request_kind(&encoded).map_err(|_| OpenFailure::BadContents)?;
+    let inner_device_hash = match &kind {
+        VmecRequestKind::Frame(request) => &request.device_hash,
+        VmecRequestKind::Heartbeat(heartbeat) => &heartbeat.device_hash,
+        VmecRequestKind::Sealed(_) | VmecRequestKind::SessionPull(_) => return Err(OpenFailure::BadContents),
+    };
+    if inner_device_hash != &sealed.device_hash {
+        return Err(OpenFailure::BadContents);
+    }
+
+    Ok(Opened {
+        kind,
+        encoded,
+        seal: Seal { device_hash: sealed.device_hash.clone(), key },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cornflakes::auth::DeviceKey;
+    use cornflakes::VmecRequestFields;
+
+    use crate::config::{AuthConfig, DeviceCredential};
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            devices: vec![DeviceCredential { device_hash: String::from("device"), key: "11".repeat(32) }],
+            ..Default::default()
+        }).unwrap()
+    }
+
+    fn sealed_frame(device_hash: &str, sealed_as: &str, key_hex: &str) -> VmecSealedFields {
+        let encoded = vmec_request_transport::encode_request(VmecRequestFields {
+            device_hash: String::from(device_hash),
+            request_hash: String::from("request"),
+            ..Default::default()
+        }).unwrap();
+        let key = TransportKey::derive(&DeviceKey::from_hex(key_hex).unwrap());
+        match vmec_request_transport::decode_request_kind(&envelope::seal_request(&encoded, sealed_as, &key)).unwrap() {
+            VmecRequestKind::Sealed(sealed) => sealed,
+            _ => panic!("not sealed"),
+        }
+    }
+
+    #[test]
+    fn opens_requests_from_known_devices() {
+        let opened = open(&sealed_frame("device", "device", &"11".repeat(32)), &authenticator()).ok().unwrap();
+        assert!(matches!(opened.kind, VmecRequestKind::Frame(request) if request.request_hash == "request"));
+    }
+
+    #[test]
+    fn reseals_for_other_servers() {
+        let seal = open(&sealed_frame("device", "device", &"11".repeat(32)), &authenticator()).ok().unwrap().seal;
+        let resealed = match vmec_request_transport::decode_request_kind(&seal.seal_request(b"request")).unwrap() {
+            VmecRequestKind::Sealed(sealed) => sealed,
+            _ => panic!("not sealed"),
+        };
+        // opens with the device's key, though what is inside is no request
+        assert_eq!(open(&resealed, &authenticator()).err(), Some(OpenFailure::BadContents));
+        assert_eq!(seal.open_reply(&seal.seal(b"reply")).as_deref(), Some(&b"reply"[..]));
+        assert_eq!(seal.open_reply(b"reply"), None);
+    }
+
+    #[test]
+    fn refuses_wrong_keys_and_devices() {
+        let authenticator = authenticator();
+        let failure = |sealed: VmecSealedFields| open(&sealed, &authenticator).err().unwrap();
+        assert_eq!(failure(sealed_frame("device", "device", &"22".repeat(32))), OpenFailure::Undecryptable);
+        assert_eq!(failure(sealed_frame("stranger", "stranger", &"11".repeat(32))), OpenFailure::Auth(AuthFailure::UnknownDevice));
+        // a device may not seal requests claiming to come from another
+        assert_eq!(failure(sealed_frame("other", "device", &"11".repeat(32))), OpenFailure::BadContents);
+    }
+}
@@ -0,0 +1,265 @@
+//! Forwarding of frames to an upstream `vmec-server`, e.g. a regional or cloud instance, for when
+//! this one is overloaded or lacks a model.
+//!
+//! Each forwarded frame gets its own thread and REQ socket, like a client would, so that a slow
+//! upstream server never holds up the main loop. Threads report back on an inproc socket, which the
+//! main loop polls next to the client socket.
+//!
+//! Frames that arrived sealed are sealed again with the device's key before they go upstream, and
+//! the upstream server's sealed reply is opened here, so the upstream server needs the keys of the
+//! devices whose frames it is sent. Frames that arrived in plaintext go upstream in plaintext.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Instant;
+
+use log::warn;
+
+use cornflakes::VmecLocation;
+
+use crate::config::UpstreamConfig;
+use crate::transport::Seal;
+
+const RESULTS_ADDRESS: &str = "inproc://upstream-results";
+
+/// A frame waiting on the upstream server, with what is needed to answer its client
+pub struct Forwarded {
+    pub identity: Vec<u8>,
+    pub device_hash: String,
+    pub seal: Option<Seal>,
+    pub request_hash: String,
+    pub location: Option<VmecLocation>,
+    pub received: Instant,
+}
+
+pub struct Upstream {
+    context: zmq::Context,
+    address: String,
+    timeout_ms: i32,
+    max_in_flight: usize,
+    results: zmq::Socket,
+    next_token: u64,
+    in_flight: HashMap<u64, Forwarded>,
+}
+
+impl Upstream {
+    pub fn new(context: &zmq::Context, config: &UpstreamConfig) -> Self {
+        let results = context.socket(zmq::PULL).unwrap();
+        results.bind(RESULTS_ADDRESS).unwrap();
+        Upstream {
+            context: context.clone(),
+            address: config.address.clone(),
+            timeout_ms: config.timeout_ms,
+            max_in_flight: config.max_in_flight,
+            results,
+            next_token: 0,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Readable when a forwarded frame has been answered or has timed out
+    pub fn results_socket(&self) -> &zmq::Socket {
+        &self.results
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.in_flight.len() < self.max_in_flight
+    }
+
+    /// Sends an encoded, unsealed frame request upstream, sealed again if the device sealed it
+    pub fn forward(&mut self, request: Vec<u8>, forwarded: Forwarded) {
+        let request = match &forwarded.seal {
+            Some(seal) => seal.seal_request(&request),
+            None => request,
+        };
+        let token = self.next_token;
+        self.next_token += 1;
+        self.in_flight.insert(token, forwarded);
+
+        let context = self.context.clone();
+        let address = self.address.clone();
+        let timeout_ms = self.timeout_ms;
+        thread::spawn(move || {
+            let reply = request_server(&context, &address, timeout_ms, request);
+            let results = context.socket(zmq::PUSH).unwrap();
+            results.connect(RESULTS_ADDRESS).unwrap();
+            // an empty reply stands for none
+            results.send_multipart([&token.to_le_bytes()[..], &reply.unwrap_or_default()[..]], 0).unwrap();
+        });
+    }
+
+    /// Sends the relayed reply for a forwarded frame to `identity` instead, for when a client resends
+    /// a frame it gave up waiting on. Returns whether a frame with this hash was waiting on the
+    /// upstream server for the device; a frame forwarded for another device is left alone.
+    pub fn redirect(&mut self, device_hash: &str, request_hash: &str, identity: &[u8]) -> bool {
+        if request_hash.is_empty() {
+            return false;
+        }
+        let device_hash = device_hash.trim();
+        match self.in_flight.values_mut().find(|forwarded| forwarded.request_hash == request_hash && forwarded.device_hash.trim() == device_hash) {
+            Some(forwarded) => {
+                forwarded.identity = Vec::from(identity);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Frames the upstream server has answered since the last call, with the reply, or None if it
+    /// did not answer in time or its reply to a sealed frame could not be opened
+    pub fn receive(&mut self) -> Vec<(Forwarded, Option<Vec<u8>>)> {
+        let mut done = Vec::new();
+        while let Ok(parts) = self.results.recv_multipart(zmq::DONTWAIT) {
+            let [token, reply] = &parts[..] else {
+                continue;
+            };
+            let Ok(token) = token[..].try_into().map(u64::from_le_bytes) else {
+                continue;
+            };
+            if let Some(forwarded) = self.in_flight.remove(&token) {
+                let reply = (!reply.is_empty()).then(|| reply.clone());
+                let reply = match &forwarded.seal {
+                    Some(seal) => reply.and_then(|reply| {
+                        let opened = seal.open_reply(&reply);
+                        if opened.is_none() {
+                            warn!("Could not open the reply from {} to a sealed frame", self.address);
+                        }
+                        opened
+                    }),
+                    None => reply,
+                };
+                done.push((forwarded, reply));
+            }
+        }
+        done
+    }
+}
+
+/// One request to another `vmec-server`, on a socket of its own; None if it did not answer in time
+pub fn request_server(context: &zmq::Context, address: &str, timeout_ms: i32, request: Vec<u8>) -> Option<Vec<u8>> {
+    let socket = context.socket(zmq::REQ).ok()?;
+    socket.set_rcvtimeo(timeout_ms).ok()?;
+    socket.set_linger(0).ok()?;
+    socket.connect(address).ok()?;
+    socket.send(request, 0).ok()?;
+    socket.recv_bytes(0).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cornflakes::{envelope, vmec_request_transport, VmecRequestFields, VmecRequestKind};
+
+    use crate::auth::Authenticator;
+    use crate::config::{AuthConfig, DeviceCredential};
+
+    fn forwarded(request_hash: &str) -> Forwarded {
+        Forwarded {
+            identity: Vec::new(),
+            device_hash: String::from("device"),
+            seal: None,
+            request_hash: String::from(request_hash),
+            location: None,
+            received: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn relays_replies_and_timeouts() {
+        let context = zmq::Context::new();
+        let server = context.socket(zmq::REP).unwrap();
+        server.bind("inproc://upstream-test").unwrap();
+
+        let mut upstream = Upstream::new(&context, &UpstreamConfig {
+            address: String::from("inproc://upstream-test"),
+            timeout_ms: 200,
+            when_busy: false,
+            max_in_flight: 2,
+        });
+        upstream.forward(b"request".to_vec(), forwarded("answered"));
+        assert_eq!(server.recv_bytes(0).unwrap(), b"request");
+        server.send(&b"reply"[..], 0).unwrap();
+
+        upstream.forward(b"request".to_vec(), forwarded("ignored"));
+        assert!(!upstream.has_capacity());
+
+        let mut results = Vec::new();
+        while results.len() < 2 {
+            upstream.results_socket().poll(zmq::POLLIN, 1000).unwrap();
+            results.extend(upstream.receive());
+        }
+        results.sort_by(|a, b| a.0.request_hash.cmp(&b.0.request_hash));
+        assert_eq!(results[0].0.request_hash, "answered");
+        assert_eq!(results[0].1.as_deref(), Some(&b"reply"[..]));
+        assert_eq!(results[1].0.request_hash, "ignored");
+        assert_eq!(results[1].1, None);
+        assert!(upstream.has_capacity());
+    }
+
+    #[test]
+    fn resent_frames_wait_on_the_first_forward() {
+        let context = zmq::Context::new();
+        let server = context.socket(zmq::REP).unwrap();
+        server.bind("inproc://upstream-resend-test").unwrap();
+        let mut upstream = Upstream::new(&context, &UpstreamConfig {
+            address: String::from("inproc://upstream-resend-test"),
+            timeout_ms: 1000,
+            when_busy: false,
+            max_in_flight: 2,
+        });
+        upstream.forward(b"request".to_vec(), Forwarded { identity: b"first".to_vec(), ..forwarded("1") });
+        assert_eq!(server.recv_bytes(0).unwrap(), b"request");
+
+        // the client gives up and sends the frame again while the upstream server is still on it
+        assert!(!upstream.redirect("intruder", "1", b"intruder"));
+        assert!(!upstream.redirect("device", "2", b"retry"));
+        assert!(upstream.redirect("device\n", "1", b"retry"));
+        server.send(&b"reply"[..], 0).unwrap();
+
+        upstream.results_socket().poll(zmq::POLLIN, 1000).unwrap();
+        let results = upstream.receive();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.identity, b"retry");
+        assert_eq!(results[0].1.as_deref(), Some(&b"reply"[..]));
+        // the frame went upstream once
+        assert_eq!(server.poll(zmq::POLLIN, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn keeps_sealed_frames_sealed() {
+        let authenticator = Authenticator::new(&AuthConfig {
+            devices: vec![DeviceCredential { device_hash: String::from("device"), key: "11".repeat(32) }],
+            ..Default::default()
+        }).unwrap();
+        let key = envelope::TransportKey::derive(authenticator.device_key("device").unwrap());
+        let frame = vmec_request_transport::encode_request(VmecRequestFields { device_hash: String::from("device"), ..Default::default() }).unwrap();
+        let VmecRequestKind::Sealed(sealed) = vmec_request_transport::decode_request_kind(&envelope::seal_request(&frame, "device", &key)).unwrap() else {
+            panic!("not sealed");
+        };
+        let opened = crate::transport::open(&sealed, &authenticator).ok().unwrap();
+
+        let context = zmq::Context::new();
+        let server = context.socket(zmq::REP).unwrap();
+        server.bind("inproc://upstream-sealed-test").unwrap();
+        let mut upstream = Upstream::new(&context, &UpstreamConfig {
+            address: String::from("inproc://upstream-sealed-test"),
+            timeout_ms: 1000,
+            when_busy: false,
+            max_in_flight: 2,
+        });
+        upstream.forward(opened.encoded.clone(), Forwarded { seal: Some(opened.seal.clone()), ..forwarded("sealed") });
+        let VmecRequestKind::Sealed(resealed) = vmec_request_transport::decode_request_kind(&server.recv_bytes(0).unwrap()).unwrap() else {
+            panic!("forwarded in plaintext");
+        };
+        assert_eq!(crate::transport::open(&resealed, &authenticator).ok().unwrap().encoded, frame);
+        server.send(opened.seal.seal(b"reply"), 0).unwrap();
+
+        upstream.results_socket().poll(zmq::POLLIN, 1000).unwrap();
+        let results = upstream.receive();
+        assert_eq!(results[0].1.as_deref(), Some(&b"reply"[..]));
+    }
+}